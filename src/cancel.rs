@@ -0,0 +1,67 @@
+//
+// Cooperative cancellation, for embedders such as editors which preprocess
+// in the background and want to give up on a run when the source changes
+// under it.
+//
+use std::sync::{Arc, OnceLock};
+
+/// A flag shared between the preprocessor and whoever may cancel it. Clones
+/// share the same flag, so one can be kept and another handed to the
+/// preprocessor, possibly on another thread.
+///
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    /// Why the run was cancelled, once it has been.
+    reason: Arc<OnceLock<String>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Ask the preprocessor to stop. It checks between files, before each
+    /// directive and before expanding each macro, so it may return a few
+    /// more tokens first.
+    ///
+    pub fn cancel(&self) {
+        self.cancel_because("preprocessing cancelled");
+    }
+
+    /// Ask the preprocessor to stop, giving the reason it reports. Only the
+    /// first reason given is kept.
+    ///
+    pub fn cancel_because(&self, reason: &str) {
+        let _ = self.reason.set(reason.to_string());
+    }
+
+    /// Has `cancel` been called on this token or any clone of it?
+    ///
+    pub fn is_cancelled(&self) -> bool {
+        self.reason.get().is_some()
+    }
+
+    /// Why the run was cancelled, or None if it hasn't been.
+    ///
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.get().map(|reason| reason.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancels_from_another_thread() {
+        let token = CancelToken::new();
+        assert!(!token.is_cancelled());
+
+        let clone = token.clone();
+        std::thread::spawn(move || clone.cancel_because("too slow")).join().unwrap();
+
+        token.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(token.reason(), Some("too slow"));
+    }
+}
@@ -6,9 +6,14 @@ use crate::source::Point;
 /// Any preprocessor error.
 /// 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CcError {
     pub what: String,
     pub loc: Option<Point>,
+
+    /// The error isn't in the source; the run was cancelled. See
+    /// `cancel::CancelToken`.
+    pub cancelled: bool,
 }
 
 impl CcError {
@@ -18,15 +23,17 @@ impl CcError {
         CcError {
             what,
             loc: None,
+            cancelled: false,
         }
     }
 
     /// Construct from a literal.
     ///
-    pub fn from_str(what: &'static str) -> Self {
+    pub fn from_static(what: &'static str) -> Self {
         CcError {
             what: what.to_owned(),
             loc: None,
+            cancelled: false,
         }
     }
 
@@ -36,6 +43,17 @@ impl CcError {
         CcError {
             what,
             loc: Some(loc),
+            cancelled: false,
+        }
+    }
+
+    /// The error returned once a run has been cancelled, saying why and
+    /// where it stopped.
+    /// 
+    pub fn cancelled(what: String, loc: Point) -> Self {
+        CcError {
+            cancelled: true,
+            ..CcError::err_with_loc(what, loc)
         }
     }
 }
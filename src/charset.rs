@@ -0,0 +1,165 @@
+//
+// Transcoding of source files from their on-disk encoding into the
+// characters the rest of the preprocessor works with.
+//
+use crate::ccerror::CcError;
+
+use std::str::FromStr;
+
+/// The character encoding of an input file.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Charset {
+    /// Detect UTF-16 from a byte order mark, otherwise assume UTF-8.
+    Auto,
+
+    /// UTF-8.
+    Utf8,
+
+    /// ISO-8859-1, where every byte is the code point of the same value.
+    Latin1,
+
+    /// UTF-16, with the byte order taken from a byte order mark. Without
+    /// one, big-endian is assumed.
+    Utf16,
+
+    /// Little-endian UTF-16.
+    Utf16Le,
+
+    /// Big-endian UTF-16.
+    Utf16Be,
+}
+
+impl FromStr for Charset {
+    type Err = CcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Charset::Auto),
+            "utf-8" | "utf8" => Ok(Charset::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" | "iso8859-1" => Ok(Charset::Latin1),
+            "utf-16" | "utf16" => Ok(Charset::Utf16),
+            "utf-16le" | "utf16le" => Ok(Charset::Utf16Le),
+            "utf-16be" | "utf16be" => Ok(Charset::Utf16Be),
+            _ => Err(CcError::new(format!("unknown input charset '{}'", s))),
+        }
+    }
+}
+
+/// Decode the raw contents of a file in the given charset.
+///
+/// A byte order mark, if present, is decoded as the character U+FEFF so that
+/// `SourceFile` can strip and record it the same way for every encoding.
+///
+pub fn decode(bytes: &[u8], charset: Charset) -> Result<Vec<char>, CcError> {
+    match charset {
+        Charset::Auto => {
+            if bytes.starts_with(&[0xff, 0xfe]) {
+                decode_utf16(bytes, false)
+            } else if bytes.starts_with(&[0xfe, 0xff]) {
+                decode_utf16(bytes, true)
+            } else {
+                decode_utf8(bytes)
+            }
+        },
+        Charset::Utf8 => decode_utf8(bytes),
+        Charset::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+        Charset::Utf16 => decode_utf16(bytes, !bytes.starts_with(&[0xff, 0xfe])),
+        Charset::Utf16Le => decode_utf16(bytes, false),
+        Charset::Utf16Be => decode_utf16(bytes, true),
+    }
+}
+
+fn decode_utf8(bytes: &[u8]) -> Result<Vec<char>, CcError> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.chars().collect()),
+        Err(e) => Err(CcError::new(format!("invalid UTF-8 at byte offset {}", e.valid_up_to()))),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> Result<Vec<char>, CcError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(CcError::from_static("UTF-16 input has an odd number of bytes"));
+    }
+
+    let units = bytes.chunks_exact(2).map(|pair| {
+        if big_endian {
+            u16::from_be_bytes([pair[0], pair[1]])
+        } else {
+            u16::from_le_bytes([pair[0], pair[1]])
+        }
+    });
+
+    let mut text = Vec::with_capacity(bytes.len() / 2);
+
+    for ch in char::decode_utf16(units) {
+        match ch {
+            Ok(ch) => text.push(ch),
+            Err(e) => {
+                return Err(
+                    CcError::new(
+                        format!("invalid UTF-16 surrogate 0x{:04x}", e.unpaired_surrogate())
+                    )
+                )
+            },
+        }
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::BOM;
+
+    #[test]
+    fn latin1_maps_bytes_to_code_points() -> Result<(), CcError> {
+        let text = decode(&[b'a', 0xa3, 0xe9], Charset::Latin1)?;
+
+        assert_eq!(text, vec!['a', '£', 'é']);
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_detects_utf16_from_bom() -> Result<(), CcError> {
+        let text = decode(&[0xff, 0xfe, b'a', 0, 0xa3, 0], Charset::Auto)?;
+        assert_eq!(text, vec![BOM, 'a', '£']);
+
+        let text = decode(&[0xfe, 0xff, 0, b'a', 0, 0xa3], Charset::Auto)?;
+        assert_eq!(text, vec![BOM, 'a', '£']);
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_defaults_to_utf8() -> Result<(), CcError> {
+        let text = decode("a£".as_bytes(), Charset::Auto)?;
+        assert_eq!(text, vec!['a', '£']);
+
+        assert!(decode(&[b'a', 0xa3], Charset::Auto).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn forced_utf16_without_bom() -> Result<(), CcError> {
+        let text = decode(&[b'a', 0, b'b', 0], Charset::Utf16Le)?;
+        assert_eq!(text, vec!['a', 'b']);
+
+        let text = decode(&[0, b'a', 0, b'b'], Charset::Utf16)?;
+        assert_eq!(text, vec!['a', 'b']);
+
+        assert!(decode(&[0, b'a', 0], Charset::Utf16Be).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_charset_names() {
+        assert_eq!("UTF-16LE".parse::<Charset>(), Ok(Charset::Utf16Le));
+        assert_eq!("iso-8859-1".parse::<Charset>(), Ok(Charset::Latin1));
+        assert!("ebcdic".parse::<Charset>().is_err());
+    }
+}
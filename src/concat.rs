@@ -0,0 +1,273 @@
+//
+// Translation phase 6: concatenation of adjacent string literals. This
+// isn't part of preprocessing proper, but is offered for consumers which
+// are building a compiler on top of the token stream.
+//
+use crate::ccerror::CcError;
+use crate::lexer::{self, Encoding, MetaToken, PpToken};
+
+/// Merge each run of adjacent string literal tokens into one literal.
+///
+/// An unprefixed literal takes on the encoding prefix of the others in its
+/// run; a run mixing two different prefixes is an error. The merged token
+/// has the location of the first literal in the run. Raw string literals
+/// are first rewritten as ordinary ones, escaping characters as needed.
+///
+pub fn concat_strings(tokens: Vec<MetaToken>) -> Result<Vec<MetaToken>, CcError> {
+    let mut out: Vec<MetaToken> = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        let token = match token.token {
+            PpToken::RawStringLiteral(encoding, text) => match lexer::raw_string_body(&text) {
+                Some(body) => MetaToken {
+                    token: PpToken::StringLiteral(encoding, unraw(body)),
+                    ..token
+                },
+                None => return Err(CcError::err_with_loc("invalid raw string literal".to_string(), token.loc)),
+            },
+            _ => token,
+        };
+
+        let (encoding, text) = match &token.token {
+            PpToken::StringLiteral(encoding, text) => (*encoding, text),
+            _ => {
+                out.push(token);
+                continue;
+            }
+        };
+
+        let prev = match out.last_mut() {
+            Some(MetaToken { token: PpToken::StringLiteral(prev_encoding, prev_text), .. }) => {
+                Some((prev_encoding, prev_text))
+            },
+            _ => None,
+        };
+
+        match prev {
+            Some((prev_encoding, prev_text)) => {
+                *prev_encoding = merge_encoding(*prev_encoding, encoding, &token)?;
+                append_raw(prev_text, text);
+            },
+            None => out.push(token),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Escape the body of a raw string so it means the same in an ordinary
+/// string literal.
+///
+fn unraw(body: &str) -> String {
+    let mut text = String::with_capacity(body.len());
+
+    for ch in body.chars() {
+        match ch {
+            '\\' => text.push_str("\\\\"),
+            '"' => text.push_str("\\\""),
+            '\n' => text.push_str("\\n"),
+            ch => text.push(ch),
+        }
+    }
+
+    text
+}
+
+/// Determine the encoding of two concatenated literals.
+///
+fn merge_encoding(left: Encoding, right: Encoding, token: &MetaToken) -> Result<Encoding, CcError> {
+    match (left, right) {
+        (Encoding::Plain, right) => Ok(right),
+        (left, Encoding::Plain) => Ok(left),
+        (left, right) if left == right => Ok(left),
+        (left, right) => Err(
+            CcError::err_with_loc(
+                format!(
+                    "cannot concatenate string literals with encoding prefixes '{}' and '{}'",
+                    left.prefix(),
+                    right.prefix()
+                ),
+                token.loc
+            )
+        ),
+    }
+}
+
+/// Append the raw text of one string literal to another.
+///
+/// Escape sequences are kept as spelled, so a hex or octal escape at the
+/// end of `left` could swallow digits at the start of `right` ("\x1" "2" is
+/// two characters, "\x12" is one). When that would happen, the first
+/// character of `right` is written as an octal escape instead.
+///
+fn append_raw(left: &mut String, right: &str) {
+    let mut rest = right.chars();
+
+    if let Some(first) = rest.clone().next() {
+        let absorbed = match open_escape(left) {
+            OpenEscape::Hex => first.is_ascii_hexdigit(),
+            OpenEscape::Octal => ('0'..='7').contains(&first),
+            OpenEscape::None => false,
+        };
+
+        if absorbed {
+            rest.next();
+            left.push_str(&format!("\\{:03o}", first as u32));
+        }
+    }
+
+    left.extend(rest);
+}
+
+/// An escape sequence at the end of a literal that would continue if more
+/// digits followed it.
+///
+enum OpenEscape {
+    None,
+    Hex,
+    Octal,
+}
+
+/// Check if the raw text of a literal ends in an escape sequence which
+/// more digits would extend.
+///
+fn open_escape(text: &str) -> OpenEscape {
+    let mut chars = text.chars().peekable();
+    let mut open = OpenEscape::None;
+
+    while let Some(ch) = chars.next() {
+        open = OpenEscape::None;
+
+        if ch != '\\' {
+            continue;
+        }
+
+        match chars.next() {
+            Some('x') => {
+                while chars.next_if(|ch| ch.is_ascii_hexdigit()).is_some() {}
+                open = OpenEscape::Hex;
+            },
+            Some('0'..='7') => {
+                let mut digits = 1;
+                while digits < 3 && chars.next_if(|ch| ('0'..='7').contains(ch)).is_some() {
+                    digits += 1;
+                }
+                if digits < 3 {
+                    open = OpenEscape::Octal;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Point;
+
+    fn string(encoding: Encoding, text: &str, col: u32) -> MetaToken {
+        MetaToken {
+            token: PpToken::StringLiteral(encoding, text.to_string()),
+            loc: Point { file: 0, line: 1, col },
+            starts_line: false,
+            space_before: false,
+        }
+    }
+
+    fn other(token: PpToken, col: u32) -> MetaToken {
+        MetaToken {
+            token,
+            loc: Point { file: 0, line: 1, col },
+            starts_line: false,
+            space_before: false,
+        }
+    }
+
+    #[test]
+    fn merges_adjacent_literals() -> Result<(), CcError> {
+        let tokens = vec![
+            string(Encoding::Plain, "ab", 1),
+            string(Encoding::Plain, "cd", 6),
+            other(PpToken::Comma, 10),
+            string(Encoding::Plain, "ef", 12),
+        ];
+
+        let tokens = concat_strings(tokens)?;
+
+        assert_eq!(tokens, vec![
+            string(Encoding::Plain, "abcd", 1),
+            other(PpToken::Comma, 10),
+            string(Encoding::Plain, "ef", 12),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_raw_strings() -> Result<(), CcError> {
+        let raw = MetaToken {
+            token: PpToken::RawStringLiteral(Encoding::Plain, "x(a\\\"\n)x".to_string()),
+            ..string(Encoding::Plain, "", 6)
+        };
+
+        let tokens = concat_strings(vec![string(Encoding::Plain, "ab", 1), raw])?;
+
+        assert_eq!(tokens, vec![string(Encoding::Plain, "aba\\\\\\\"\\n", 1)]);
+
+        let bad = MetaToken {
+            token: PpToken::RawStringLiteral(Encoding::Plain, "x(a)".to_string()),
+            ..string(Encoding::Plain, "", 6)
+        };
+        assert_eq!(concat_strings(vec![bad]).unwrap_err().what, "invalid raw string literal");
+
+        Ok(())
+    }
+
+    #[test]
+    fn unprefixed_takes_prefix() -> Result<(), CcError> {
+        let tokens = vec![
+            string(Encoding::Plain, "ab", 1),
+            string(Encoding::Wide, "cd", 6),
+            string(Encoding::Plain, "ef", 11),
+        ];
+
+        let tokens = concat_strings(tokens)?;
+
+        assert_eq!(tokens, vec![string(Encoding::Wide, "abcdef", 1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_prefixes_are_an_error() {
+        let tokens = vec![
+            string(Encoding::Utf8, "ab", 1),
+            string(Encoding::Wide, "cd", 8),
+        ];
+
+        let err = concat_strings(tokens).unwrap_err();
+
+        assert_eq!(err.loc, Some(Point { file: 0, line: 1, col: 8 }));
+    }
+
+    #[test]
+    fn escapes_do_not_run_together() -> Result<(), CcError> {
+        let tokens = vec![
+            string(Encoding::Plain, "\\x1", 1),
+            string(Encoding::Plain, "2", 7),
+            string(Encoding::Plain, "\\01", 11),
+            string(Encoding::Plain, "7x", 17),
+            string(Encoding::Plain, "\\012", 21),
+            string(Encoding::Plain, "3", 27),
+        ];
+
+        let tokens = concat_strings(tokens)?;
+
+        assert_eq!(tokens, vec![string(Encoding::Plain, "\\x1\\062\\01\\067x\\0123", 1)]);
+
+        Ok(())
+    }
+}
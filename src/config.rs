@@ -0,0 +1,271 @@
+//
+// The project configuration file, cpp.toml, which holds the options a
+// project always runs the preprocessor with so they can be checked in
+// alongside the source. Options given on the command line take precedence.
+//
+use crate::ccerror::CcError;
+use crate::charset::Charset;
+use crate::lexer::{Language, NonAsciiPolicy};
+use crate::standard::Dialect;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::{Table, Value};
+
+/// The name of the configuration file looked for in the current directory.
+///
+pub const CONFIG_FILE: &str = "cpp.toml";
+
+/// The contents of a configuration file. Every setting is optional.
+///
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// Directories searched for angled and quoted includes, as `-I`.
+    pub include: Vec<PathBuf>,
+
+    /// Directories searched for quoted includes only, as `-iquote`.
+    pub iquote: Vec<PathBuf>,
+
+    /// System include directories, as `-isystem`.
+    pub isystem: Vec<PathBuf>,
+
+    /// Framework directories, as `-F`.
+    pub framework: Vec<PathBuf>,
+
+    /// Macro definitions, as `-D`.
+    pub define: Vec<String>,
+
+    /// The source language, as `-x`.
+    pub language: Option<Language>,
+
+    /// The C or C++ standard, as `--std`.
+    pub std: Option<Dialect>,
+
+    /// The encoding of source files, as `--input-charset`.
+    pub input_charset: Option<Charset>,
+
+    pub warnings: Warnings,
+    pub output: Output,
+}
+
+/// The `[warnings]` table.
+///
+#[derive(Debug, Default, PartialEq)]
+pub struct Warnings {
+    /// What to do about stray non-ASCII characters, as `--non-ascii`.
+    pub non_ascii: Option<NonAsciiPolicy>,
+
+    /// Make warnings errors, as `-Werror`.
+    pub error: bool,
+
+    /// Exit with success even if there are warnings, as
+    /// `--exit-zero-on-warnings`.
+    pub exit_zero: bool,
+}
+
+/// The `[output]` table.
+///
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// What to write, as `--emit`. This is left as a string since the
+    /// choices belong to the driver.
+    pub emit: Option<String>,
+
+    /// Print statistics at the end of the run, as `--stats`.
+    pub stats: bool,
+}
+
+impl Config {
+    /// Read and parse a configuration file. Relative directories in the
+    /// file are taken to be relative to the directory the file is in.
+    ///
+    pub fn load(path: &Path) -> Result<Config, CcError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| CcError::new(format!("{}: {}", path.to_string_lossy(), e)))?;
+
+        let mut config = Config::parse(&text)
+            .map_err(|e| CcError::new(format!("{}: {}", path.to_string_lossy(), e.what)))?;
+
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+
+        Ok(config)
+    }
+
+    /// Make the relative directories in the configuration relative to `dir`
+    /// instead.
+    ///
+    fn resolve_paths(&mut self, dir: &Path) {
+        for paths in [&mut self.include, &mut self.iquote, &mut self.isystem, &mut self.framework] {
+            for path in paths.iter_mut() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Parse the text of a configuration file. Settings which aren't known
+    /// are an error, so a misspelt one isn't silently ignored.
+    ///
+    pub fn parse(text: &str) -> Result<Config, CcError> {
+        let table: Table = text.parse()
+            .map_err(|e: toml::de::Error| CcError::new(e.message().to_string()))?;
+
+        let mut config = Config::default();
+
+        for (key, value) in &table {
+            match key.as_str() {
+                "include" => config.include = paths(key, value)?,
+                "iquote" => config.iquote = paths(key, value)?,
+                "isystem" => config.isystem = paths(key, value)?,
+                "framework" => config.framework = paths(key, value)?,
+                "define" => config.define = strings(key, value)?,
+                "language" => config.language = Some(parse(key, value)?),
+                "std" => config.std = Some(parse(key, value)?),
+                "input-charset" => config.input_charset = Some(parse(key, value)?),
+                "warnings" => {
+                    for (key, value) in table_of(key, value)? {
+                        match key.as_str() {
+                            "non-ascii" => config.warnings.non_ascii = Some(parse(key, value)?),
+                            "error" => config.warnings.error = boolean(key, value)?,
+                            "exit-zero" => config.warnings.exit_zero = boolean(key, value)?,
+                            _ => return Err(unknown_setting(key)),
+                        }
+                    }
+                },
+                "output" => {
+                    for (key, value) in table_of(key, value)? {
+                        match key.as_str() {
+                            "emit" => config.output.emit = Some(string(key, value)?.to_string()),
+                            "stats" => config.output.stats = boolean(key, value)?,
+                            _ => return Err(unknown_setting(key)),
+                        }
+                    }
+                },
+                _ => return Err(unknown_setting(key)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The error for a setting which isn't known.
+///
+fn unknown_setting(key: &str) -> CcError {
+    CcError::new(format!("unknown setting '{}'", key))
+}
+
+/// Get a setting which must be a string.
+///
+fn string<'a>(key: &str, value: &'a Value) -> Result<&'a str, CcError> {
+    value.as_str().ok_or_else(|| CcError::new(format!("'{}' must be a string", key)))
+}
+
+/// Get a setting which must be a boolean.
+///
+fn boolean(key: &str, value: &Value) -> Result<bool, CcError> {
+    value.as_bool().ok_or_else(|| CcError::new(format!("'{}' must be true or false", key)))
+}
+
+/// Get a setting which must be a table, such as `[warnings]`.
+///
+fn table_of<'a>(key: &str, value: &'a Value) -> Result<&'a Table, CcError> {
+    value.as_table().ok_or_else(|| CcError::new(format!("'{}' must be a table", key)))
+}
+
+/// Get a setting which must be an array of strings.
+///
+fn strings(key: &str, value: &Value) -> Result<Vec<String>, CcError> {
+    let not_strings = || CcError::new(format!("'{}' must be an array of strings", key));
+
+    value.as_array()
+        .ok_or_else(not_strings)?
+        .iter()
+        .map(|value| value.as_str().map(str::to_string).ok_or_else(not_strings))
+        .collect()
+}
+
+/// Get a setting which must be an array of directories.
+///
+fn paths(key: &str, value: &Value) -> Result<Vec<PathBuf>, CcError> {
+    Ok(strings(key, value)?.into_iter().map(PathBuf::from).collect())
+}
+
+/// Get a setting from a string, using the same parser as the command line
+/// option.
+///
+fn parse<T: FromStr<Err = CcError>>(key: &str, value: &Value) -> Result<T, CcError> {
+    string(key, value)?.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::standard::Standard;
+
+    #[test]
+    fn parses_config() -> Result<(), CcError> {
+        let config = Config::parse(r#"
+            include = ["inc", "/opt/inc"]
+            define = ["DEBUG", "LEVEL=2"]
+            language = "c++"
+            std = "gnu2x"
+            input-charset = "latin1"
+
+            [warnings]
+            non-ascii = "error"
+            error = true
+
+            [output]
+            emit = "tokens-json"
+        "#)?;
+
+        assert_eq!(config.include, vec![PathBuf::from("inc"), PathBuf::from("/opt/inc")]);
+        assert_eq!(config.define, vec!["DEBUG".to_string(), "LEVEL=2".to_string()]);
+        assert_eq!(config.language, Some(Language::Cxx));
+        assert_eq!(config.std, Some(Dialect { standard: Standard::C23, gnu: true }));
+        assert_eq!(config.input_charset, Some(Charset::Latin1));
+        assert_eq!(config.warnings.non_ascii, Some(NonAsciiPolicy::Error));
+        assert!(config.warnings.error);
+        assert!(!config.warnings.exit_zero);
+        assert_eq!(config.output.emit.as_deref(), Some("tokens-json"));
+        assert!(config.iquote.is_empty());
+
+        assert_eq!(Config::parse("")?, Config::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_paths_from_config_dir() -> Result<(), CcError> {
+        let mut config = Config::parse(r#"
+            include = ["inc", "/opt/inc"]
+            iquote = ["q"]
+            isystem = ["../sys"]
+            framework = ["fw"]
+        "#)?;
+
+        config.resolve_paths(Path::new("sub"));
+
+        assert_eq!(config.include, vec![PathBuf::from("sub/inc"), PathBuf::from("/opt/inc")]);
+        assert_eq!(config.iquote, vec![PathBuf::from("sub/q")]);
+        assert_eq!(config.isystem, vec![PathBuf::from("sub/../sys")]);
+        assert_eq!(config.framework, vec![PathBuf::from("sub/fw")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let e = Config::parse("includes = [\"inc\"]").unwrap_err();
+        assert!(e.what.contains("includes"), "{}", e.what);
+
+        let e = Config::parse("language = \"fortran\"").unwrap_err();
+        assert!(e.what.contains("unknown language 'fortran'"), "{}", e.what);
+
+        let e = Config::parse("[warnings]\nerror = \"yes\"").unwrap_err();
+        assert!(e.what.contains("'error' must be true or false"), "{}", e.what);
+    }
+}
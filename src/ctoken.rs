@@ -0,0 +1,319 @@
+//
+// Translation phase 7: conversion of preprocessing tokens into C tokens,
+// for consumers building a compiler on top of the preprocessor.
+//
+use crate::ccerror::CcError;
+use crate::escape;
+use crate::lexer::{self, Encoding, MetaToken, PpToken};
+use crate::number::{self, FloatConstant, IntConstant, NumericConstant};
+use crate::source::Point;
+use crate::standard::Standard;
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+
+/// A C keyword.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Keyword {
+    Auto,
+    Break,
+    Case,
+    Char,
+    Const,
+    Continue,
+    Default,
+    Do,
+    Double,
+    Else,
+    Enum,
+    Extern,
+    Float,
+    For,
+    Goto,
+    If,
+    Int,
+    Long,
+    Register,
+    Return,
+    Short,
+    Signed,
+    Sizeof,
+    Static,
+    Struct,
+    Switch,
+    Typedef,
+    Union,
+    Unsigned,
+    Void,
+    Volatile,
+    While,
+
+    // C99
+    Inline,
+    Restrict,
+    Bool,
+    Complex,
+    Imaginary,
+
+    // C11
+    Alignas,
+    Alignof,
+    Atomic,
+    Generic,
+    Noreturn,
+    StaticAssert,
+    ThreadLocal,
+
+    // C23
+    Constexpr,
+    False,
+    Nullptr,
+    True,
+    Typeof,
+    TypeofUnqual,
+    BitInt,
+    Decimal32,
+    Decimal64,
+    Decimal128,
+}
+
+lazy_static! {
+    /// Every keyword spelling, with the standard that introduced it.
+    ///
+    /// C23 added lower case spellings of several C11 keywords (`alignas` for
+    /// `_Alignas`, etc.), which map to the same keyword.
+    ///
+    static ref KEYWORDS: HashMap<&'static str, (Keyword, Standard)> = vec![
+        ("auto", Keyword::Auto, Standard::C89),
+        ("break", Keyword::Break, Standard::C89),
+        ("case", Keyword::Case, Standard::C89),
+        ("char", Keyword::Char, Standard::C89),
+        ("const", Keyword::Const, Standard::C89),
+        ("continue", Keyword::Continue, Standard::C89),
+        ("default", Keyword::Default, Standard::C89),
+        ("do", Keyword::Do, Standard::C89),
+        ("double", Keyword::Double, Standard::C89),
+        ("else", Keyword::Else, Standard::C89),
+        ("enum", Keyword::Enum, Standard::C89),
+        ("extern", Keyword::Extern, Standard::C89),
+        ("float", Keyword::Float, Standard::C89),
+        ("for", Keyword::For, Standard::C89),
+        ("goto", Keyword::Goto, Standard::C89),
+        ("if", Keyword::If, Standard::C89),
+        ("int", Keyword::Int, Standard::C89),
+        ("long", Keyword::Long, Standard::C89),
+        ("register", Keyword::Register, Standard::C89),
+        ("return", Keyword::Return, Standard::C89),
+        ("short", Keyword::Short, Standard::C89),
+        ("signed", Keyword::Signed, Standard::C89),
+        ("sizeof", Keyword::Sizeof, Standard::C89),
+        ("static", Keyword::Static, Standard::C89),
+        ("struct", Keyword::Struct, Standard::C89),
+        ("switch", Keyword::Switch, Standard::C89),
+        ("typedef", Keyword::Typedef, Standard::C89),
+        ("union", Keyword::Union, Standard::C89),
+        ("unsigned", Keyword::Unsigned, Standard::C89),
+        ("void", Keyword::Void, Standard::C89),
+        ("volatile", Keyword::Volatile, Standard::C89),
+        ("while", Keyword::While, Standard::C89),
+        ("inline", Keyword::Inline, Standard::C99),
+        ("restrict", Keyword::Restrict, Standard::C99),
+        ("_Bool", Keyword::Bool, Standard::C99),
+        ("_Complex", Keyword::Complex, Standard::C99),
+        ("_Imaginary", Keyword::Imaginary, Standard::C99),
+        ("_Alignas", Keyword::Alignas, Standard::C11),
+        ("_Alignof", Keyword::Alignof, Standard::C11),
+        ("_Atomic", Keyword::Atomic, Standard::C11),
+        ("_Generic", Keyword::Generic, Standard::C11),
+        ("_Noreturn", Keyword::Noreturn, Standard::C11),
+        ("_Static_assert", Keyword::StaticAssert, Standard::C11),
+        ("_Thread_local", Keyword::ThreadLocal, Standard::C11),
+        ("alignas", Keyword::Alignas, Standard::C23),
+        ("alignof", Keyword::Alignof, Standard::C23),
+        ("bool", Keyword::Bool, Standard::C23),
+        ("static_assert", Keyword::StaticAssert, Standard::C23),
+        ("thread_local", Keyword::ThreadLocal, Standard::C23),
+        ("constexpr", Keyword::Constexpr, Standard::C23),
+        ("false", Keyword::False, Standard::C23),
+        ("nullptr", Keyword::Nullptr, Standard::C23),
+        ("true", Keyword::True, Standard::C23),
+        ("typeof", Keyword::Typeof, Standard::C23),
+        ("typeof_unqual", Keyword::TypeofUnqual, Standard::C23),
+        ("_BitInt", Keyword::BitInt, Standard::C23),
+        ("_Decimal32", Keyword::Decimal32, Standard::C23),
+        ("_Decimal64", Keyword::Decimal64, Standard::C23),
+        ("_Decimal128", Keyword::Decimal128, Standard::C23),
+    ].into_iter().map(|(spelling, keyword, standard)| (spelling, (keyword, standard))).collect();
+}
+
+impl Keyword {
+    /// Look up an identifier, returning the keyword it spells in the given
+    /// standard, if any.
+    ///
+    pub fn lookup(id: &str, standard: Standard) -> Option<Keyword> {
+        match KEYWORDS.get(id) {
+            Some((keyword, introduced)) if *introduced <= standard => Some(*keyword),
+            _ => None,
+        }
+    }
+}
+
+/// A C token.
+///
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CToken {
+    Keyword(Keyword),
+    Identifier(String),
+    IntConstant(IntConstant),
+    FloatConstant(FloatConstant),
+
+    /// A character constant, with its value.
+    CharConstant(Encoding, i64),
+
+    /// A string literal, as code unit values without a terminating zero.
+    StringLiteral(Encoding, Vec<u32>),
+
+    /// An operator or punctuator, as the preprocessing token it came from.
+    Punctuator(PpToken),
+
+    Eof,
+}
+
+/// A C token with its source location.
+///
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CMetaToken {
+    pub token: CToken,
+    pub loc: Point,
+}
+
+/// Convert preprocessing tokens into C tokens for the given standard.
+///
+/// Adjacent string literals should already have been merged with
+/// `concat::concat_strings`. Warnings, such as for multi-character
+/// constants, are appended to `warnings`.
+///
+pub fn convert(tokens: Vec<MetaToken>, standard: Standard, warnings: &mut Vec<CcError>) -> Result<Vec<CMetaToken>, CcError> {
+    let mut out = Vec::with_capacity(tokens.len());
+
+    for MetaToken { token, loc, .. } in tokens {
+        let token = match token {
+            PpToken::Identifier(id) => match Keyword::lookup(&id, standard) {
+                Some(keyword) => CToken::Keyword(keyword),
+                None => CToken::Identifier(id),
+            },
+            PpToken::Number(spelling) => match number::convert(&spelling, loc)? {
+                NumericConstant::Int(value) => CToken::IntConstant(value),
+                NumericConstant::Float(value) => CToken::FloatConstant(value),
+            },
+            PpToken::CharLiteral(encoding, text) => {
+                CToken::CharConstant(encoding, escape::char_constant(&text, encoding, loc, warnings)?)
+            },
+            PpToken::StringLiteral(encoding, text) => {
+                CToken::StringLiteral(encoding, escape::evaluate(&text, encoding, loc)?)
+            },
+            PpToken::RawStringLiteral(encoding, text) => match lexer::raw_string_body(&text) {
+                Some(body) => CToken::StringLiteral(encoding, escape::evaluate_raw(body, encoding)),
+                None => return Err(CcError::err_with_loc("invalid raw string literal".to_string(), loc)),
+            },
+            hash @ (PpToken::Hash | PpToken::HashHash) => {
+                return Err(CcError::err_with_loc(format!("stray '{}' in program", hash), loc))
+            },
+            PpToken::Other(ch) => {
+                return Err(CcError::err_with_loc(format!("stray '{}' in program", ch), loc))
+            },
+            PpToken::Eof => CToken::Eof,
+            alternative @ PpToken::Alternative(_) => CToken::Punctuator(alternative.kind().clone()),
+            op => CToken::Punctuator(op),
+        };
+
+        out.push(CMetaToken { token, loc });
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::number::IntLength;
+
+    fn pp(token: PpToken) -> MetaToken {
+        MetaToken {
+            token,
+            loc: Point { file: 0, line: 1, col: 1 },
+            starts_line: false,
+            space_before: false,
+        }
+    }
+
+    fn tokens(tokens: Vec<CMetaToken>) -> Vec<CToken> {
+        tokens.into_iter().map(|token| token.token).collect()
+    }
+
+    #[test]
+    fn keywords_depend_on_standard() -> Result<(), CcError> {
+        let input = vec![
+            pp(PpToken::Identifier("int".to_string())),
+            pp(PpToken::Identifier("inline".to_string())),
+            pp(PpToken::Identifier("bool".to_string())),
+            pp(PpToken::Identifier("_Bool".to_string())),
+        ];
+
+        let mut warnings = Vec::new();
+
+        assert_eq!(tokens(convert(input.clone(), Standard::C89, &mut warnings)?), vec![
+            CToken::Keyword(Keyword::Int),
+            CToken::Identifier("inline".to_string()),
+            CToken::Identifier("bool".to_string()),
+            CToken::Identifier("_Bool".to_string()),
+        ]);
+
+        assert_eq!(tokens(convert(input, Standard::C23, &mut warnings)?), vec![
+            CToken::Keyword(Keyword::Int),
+            CToken::Keyword(Keyword::Inline),
+            CToken::Keyword(Keyword::Bool),
+            CToken::Keyword(Keyword::Bool),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn converts_literals() -> Result<(), CcError> {
+        let input = vec![
+            pp(PpToken::Number("42u".to_string())),
+            pp(PpToken::CharLiteral(Encoding::Plain, "ab".to_string())),
+            pp(PpToken::StringLiteral(Encoding::Wide, "a\\n".to_string())),
+            pp(PpToken::Semicolon),
+        ];
+
+        let mut warnings = Vec::new();
+        let output = tokens(convert(input, Standard::C17, &mut warnings)?);
+
+        assert_eq!(output, vec![
+            CToken::IntConstant(IntConstant { value: 42, radix: 10, unsigned: true, length: IntLength::Int }),
+            CToken::CharConstant(Encoding::Plain, 0x6162),
+            CToken::StringLiteral(Encoding::Wide, vec![0x61, 0x0a]),
+            CToken::Punctuator(PpToken::Semicolon),
+        ]);
+        assert_eq!(warnings.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stray_characters_are_errors() {
+        let mut warnings = Vec::new();
+
+        assert!(convert(vec![pp(PpToken::Hash)], Standard::C17, &mut warnings).is_err());
+        assert!(convert(vec![pp(PpToken::Other('$'))], Standard::C17, &mut warnings).is_err());
+        assert!(convert(vec![pp(PpToken::Number("0x1g8".to_string()))], Standard::C17, &mut warnings).is_err());
+        assert!(convert(vec![pp(PpToken::RawStringLiteral(Encoding::Plain, String::new()))], Standard::C17, &mut warnings).is_err());
+    }
+}
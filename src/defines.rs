@@ -0,0 +1,155 @@
+//
+// Defines files, which hold many macro definitions and undefinitions so
+// that a build system doesn't need a command line thousands of arguments
+// long. The entries are applied in order, as if each were a -D or -U option.
+//
+// A file whose name ends in .json is an array of objects, each with either
+// a "define" member, `NAME` or `NAME=VALUE` as for -D, or an "undef" member
+// naming a macro, and optionally a "comment":
+//
+//      [
+//          { "define": "LEVEL=2", "comment": "set by the build" },
+//          { "undef": "NDEBUG" }
+//      ]
+//
+// Any other file is text, with one entry on each line: `-DNAME`,
+// `-DNAME=VALUE` or `-UNAME`, optionally with a space after the -D or -U.
+// Blank lines are ignored, and so are comment lines, which start with `#`.
+//
+use crate::ccerror::CcError;
+use crate::json::{self, Value};
+use crate::macros::MacroOption;
+
+use std::fs;
+use std::path::Path;
+
+/// Read a defines file, as JSON if its name ends in `.json` and as text
+/// otherwise.
+///
+pub fn load(path: &Path) -> Result<Vec<MacroOption>, CcError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| CcError::new(format!("{}: {}", path.to_string_lossy(), e)))?;
+
+    let options = match path.extension() {
+        Some(extension) if extension == "json" => parse_json(&text),
+        _ => parse_text(&text),
+    };
+
+    options.map_err(|e| CcError::new(format!("{}: {}", path.to_string_lossy(), e.what)))
+}
+
+/// Parse the text of a text defines file.
+///
+pub fn parse_text(text: &str) -> Result<Vec<MacroOption>, CcError> {
+    let mut options = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(definition) = line.strip_prefix("-D") {
+            options.push(MacroOption::Define(definition.trim_start().to_string()));
+        } else if let Some(name) = line.strip_prefix("-U") {
+            options.push(MacroOption::Undef(name.trim_start().to_string()));
+        } else {
+            return Err(CcError::new(format!("line {}: expected -D or -U", i + 1)));
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parse the text of a JSON defines file.
+///
+pub fn parse_json(text: &str) -> Result<Vec<MacroOption>, CcError> {
+    let Value::Array(entries) = json::parse(text)? else {
+        return Err(CcError::from_static("expected an array of entries"));
+    };
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| json_entry(entry).map_err(|e| CcError::new(format!("entry {}: {}", i + 1, e.what))))
+        .collect()
+}
+
+/// Parse one entry of a JSON defines file. Members which aren't known are
+/// an error, so a misspelt one isn't silently ignored.
+///
+fn json_entry(entry: &Value) -> Result<MacroOption, CcError> {
+    let Value::Object(members) = entry else {
+        return Err(CcError::from_static("expected an object"));
+    };
+
+    let mut option = None;
+
+    for (name, value) in members {
+        let string = || match value {
+            Value::String(value) => Ok(value.clone()),
+            _ => Err(CcError::new(format!("'{}' must be a string", name))),
+        };
+
+        let entry = match name.as_str() {
+            "define" => MacroOption::Define(string()?),
+            "undef" => MacroOption::Undef(string()?),
+            "comment" => {
+                string()?;
+                continue;
+            },
+            _ => return Err(CcError::new(format!("unknown member '{}'", name))),
+        };
+
+        if option.replace(entry).is_some() {
+            return Err(CcError::from_static("only one of 'define' and 'undef' may be given"));
+        }
+    }
+
+    option.ok_or_else(|| CcError::from_static("expected 'define' or 'undef'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn define(s: &str) -> MacroOption {
+        MacroOption::Define(s.to_string())
+    }
+
+    fn undef(s: &str) -> MacroOption {
+        MacroOption::Undef(s.to_string())
+    }
+
+    #[test]
+    fn parses_text() -> Result<(), CcError> {
+        let text = "# from the build\n-DDEBUG\n\n  -D LEVEL=2 + x\r\n-UNDEBUG\n";
+
+        assert_eq!(parse_text(text)?, vec![define("DEBUG"), define("LEVEL=2 + x"), undef("NDEBUG")]);
+        assert_eq!(parse_text("-DA\nB=1\n").unwrap_err().what, "line 2: expected -D or -U");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_json() -> Result<(), CcError> {
+        let text = r#"[
+            { "define": "LEVEL=2", "comment": "set by the build" },
+            { "comment": "not wanted", "undef": "NDEBUG" }
+        ]"#;
+
+        assert_eq!(parse_json(text)?, vec![define("LEVEL=2"), undef("NDEBUG")]);
+        assert_eq!(parse_json("[]")?, Vec::new());
+
+        assert_eq!(parse_json("{}").unwrap_err().what, "expected an array of entries");
+        assert_eq!(parse_json(r#"[{"define": "A"}, {"comment": "x"}]"#).unwrap_err().what, "entry 2: expected 'define' or 'undef'");
+        assert_eq!(parse_json(r#"[{"define": "A", "undef": "A"}]"#).unwrap_err().what, "entry 1: only one of 'define' and 'undef' may be given");
+        assert_eq!(parse_json(r#"[{"defines": 1}]"#).unwrap_err().what, "entry 1: unknown member 'defines'");
+        assert_eq!(parse_json(r#"[{"undef": "A", "comment": null}]"#).unwrap_err().what, "entry 1: 'comment' must be a string");
+        assert_eq!(parse_json(r#"[{"define": 1}]"#).unwrap_err().what, "entry 1: 'define' must be a string");
+        assert_eq!(parse_json(r#"["A"]"#).unwrap_err().what, "entry 1: expected an object");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//
+// Collection of diagnostics from several translation units, which may be
+// preprocessed on different threads, into one deterministic report.
+//
+use crate::ccerror::CcError;
+use crate::source::Source;
+
+use std::fmt::Display;
+use std::sync::Mutex;
+
+/// How serious a diagnostic is.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// An error or warning, with the name of the file it occurred in, so it
+/// still makes sense away from the Source which produced it.
+///
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+
+    /// The name of the file, or empty if the error has no location.
+    pub filename: String,
+
+    pub error: CcError,
+}
+
+impl Diagnostic {
+    /// Construct from an error reported while reading `source`.
+    ///
+    pub fn new(severity: Severity, source: &Source, error: CcError) -> Self {
+        let filename = error.loc
+            .and_then(|pt| source.get_filename(pt.file))
+            .unwrap_or_default();
+
+        Diagnostic { severity, filename, error }
+    }
+
+    /// The key diagnostics are ordered by: file name, position, severity,
+    /// then message. Diagnostics without a location come first.
+    ///
+    fn key(&self) -> (&str, u32, u32, Severity, &str) {
+        let (line, col) = self.error.loc.map_or((0, 0), |pt| (pt.line, pt.col));
+        (&self.filename, line, col, self.severity, &self.error.what)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(pt) = self.error.loc {
+            write!(f, "{}:{}:{}: ", self.filename, pt.line, pt.col)?;
+        }
+
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.error.what),
+            Severity::Error => write!(f, "{}", self.error.what),
+        }
+    }
+}
+
+/// Somewhere to send diagnostics. Reporting only needs a shared reference,
+/// so one sink can be used from many threads at once.
+///
+pub trait DiagnosticSink {
+    fn report(&self, diagnostic: Diagnostic);
+}
+
+/// A sink which can be shared between threads, and which gives back the
+/// diagnostics in an order which doesn't depend on how the threads ran.
+///
+#[derive(Debug, Default)]
+pub struct SharedSink {
+    diagnostics: Mutex<Vec<Diagnostic>>,
+}
+
+impl SharedSink {
+    pub fn new() -> Self {
+        SharedSink::default()
+    }
+
+    /// The diagnostics reported, sorted by file name, position, severity
+    /// and message, so the order doesn't depend on which thread reported
+    /// first.
+    ///
+    pub fn into_sorted(self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.diagnostics
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+
+        diagnostics.sort_by(|a, b| a.key().cmp(&b.key()));
+        diagnostics
+    }
+}
+
+impl DiagnosticSink for SharedSink {
+    fn report(&self, diagnostic: Diagnostic) {
+        //
+        // A thread which panicked while holding the lock can't have left
+        // the vector half-updated, so carry on with it.
+        //
+        self.diagnostics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(diagnostic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Point;
+
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn diagnostic(severity: Severity, filename: &str, line: u32, what: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            filename: filename.to_string(),
+            error: CcError::err_with_loc(what.to_string(), Point { file: 0, line, col: 1 }),
+        }
+    }
+
+    #[test]
+    fn formats_diagnostics() {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("a.c"), vec!['a']);
+
+        let error = CcError::err_with_loc("bad".to_string(), Point { file: 0, line: 2, col: 3 });
+        assert_eq!(Diagnostic::new(Severity::Error, &source, error).to_string(), "a.c:2:3: bad");
+
+        let warning = CcError::err_with_loc("odd".to_string(), Point { file: 0, line: 2, col: 3 });
+        assert_eq!(Diagnostic::new(Severity::Warning, &source, warning).to_string(), "a.c:2:3: warning: odd");
+
+        let error = CcError::from_static("no loc");
+        assert_eq!(Diagnostic::new(Severity::Error, &source, error).to_string(), "no loc");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_through_serde() -> Result<(), CcError> {
+        let diagnostic = diagnostic(Severity::Warning, "a.c", 2, "odd");
+        let text = toml::to_string(&diagnostic).map_err(|e| CcError::new(e.to_string()))?;
+
+        assert_eq!(toml::from_str::<Diagnostic>(&text).map_err(|e| CcError::new(e.to_string()))?, diagnostic);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sorts_diagnostics_at_the_same_place() {
+        let sink = SharedSink::new();
+
+        sink.report(diagnostic(Severity::Error, "a.c", 1, "z"));
+        sink.report(diagnostic(Severity::Error, "a.c", 1, "y"));
+        sink.report(diagnostic(Severity::Warning, "a.c", 1, "x"));
+
+        let sorted: Vec<String> = sink.into_sorted().iter().map(|d| d.to_string()).collect();
+        assert_eq!(sorted, vec!["a.c:1:1: warning: x", "a.c:1:1: y", "a.c:1:1: z"]);
+    }
+
+    #[test]
+    fn sorts_diagnostics_from_threads() {
+        let sink = Arc::new(SharedSink::new());
+
+        let threads: Vec<_> = ["b.c", "a.c"].iter()
+            .map(|filename| {
+                let sink = Arc::clone(&sink);
+                std::thread::spawn(move || {
+                    for line in [3, 1, 2] {
+                        sink.report(diagnostic(Severity::Warning, filename, line, "w"));
+                    }
+                    sink.report(diagnostic(Severity::Error, filename, 1, "e"));
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let sorted: Vec<String> = Arc::into_inner(sink).unwrap()
+            .into_sorted()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+
+        assert_eq!(sorted, vec![
+            "a.c:1:1: warning: w",
+            "a.c:1:1: e",
+            "a.c:2:1: warning: w",
+            "a.c:3:1: warning: w",
+            "b.c:1:1: warning: w",
+            "b.c:1:1: e",
+            "b.c:2:1: warning: w",
+            "b.c:3:1: warning: w",
+        ]);
+    }
+}
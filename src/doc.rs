@@ -0,0 +1,159 @@
+//
+// Extraction of documentation comments, with what they document, for
+// documentation generators which want the positions the preprocessor sees.
+//
+use crate::ccerror::CcError;
+use crate::lexer::{self, LexOptions, MetaToken, PpToken};
+use crate::source::{Point, Source};
+use crate::strip::{self, Comment, CommentKind};
+
+/// What a documentation comment is attached to: the token after it.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub enum DocTarget {
+    /// An ordinary token, usually an identifier.
+    Token(MetaToken),
+
+    /// A directive, such as `#define`, by name.
+    Directive { name: String, loc: Point },
+}
+
+/// A documentation comment.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub struct DocComment {
+    /// The text of the comment, including the comment markers. Consecutive
+    /// `///` lines are one comment.
+    pub text: String,
+
+    /// The location of the start of the comment.
+    pub loc: Point,
+
+    /// The token following the comment, if there is one.
+    pub target: Option<DocTarget>,
+}
+
+/// Collect the `/** ... */` and `///` comments in the file at the top of
+/// `source`, lexing the file to find the token each one documents.
+///
+pub fn doc_comments(source: &mut Source, options: &LexOptions) -> Result<Vec<DocComment>, CcError> {
+    let file = source.files.len() as u32 - 1;
+    let text = source.files[file as usize].text.clone();
+
+    let comments = merge_line_comments(
+        &text,
+        strip::find_comments(&text, file, options)?
+            .into_iter()
+            .filter(|comment| is_doc_comment(&text, comment))
+            .collect()
+    );
+
+    let mut tokens = Vec::new();
+    let mut emit = Vec::new();
+
+    loop {
+        match lexer::next_token_with(source, &mut emit, options)? {
+            MetaToken { token: PpToken::Eof, .. } => break,
+            token => tokens.push(token),
+        }
+    }
+
+    let mut docs = Vec::new();
+    let mut next = 0;
+
+    for comment in comments {
+        let end = strip::point_at(&text, file, comment.end);
+
+        while next < tokens.len() && (tokens[next].loc.line, tokens[next].loc.col) < (end.line, end.col) {
+            next += 1;
+        }
+
+        docs.push(DocComment {
+            text: text[comment.start..comment.end].iter().collect(),
+            loc: strip::point_at(&text, file, comment.start),
+            target: target(&tokens[next..]),
+        });
+    }
+
+    Ok(docs)
+}
+
+/// Whether a comment is a documentation comment: `/**` but not `/**/`, or
+/// `///` but not `////`.
+///
+fn is_doc_comment(text: &[char], comment: &Comment) -> bool {
+    let spelling = &text[comment.start..comment.end];
+
+    match comment.kind {
+        CommentKind::Block => spelling.starts_with(&['/', '*', '*']) && spelling.len() > 4,
+        CommentKind::Line => spelling.starts_with(&['/', '/', '/']) && !spelling.starts_with(&['/', '/', '/', '/']),
+    }
+}
+
+/// Merge runs of line comments on consecutive lines into one comment.
+///
+fn merge_line_comments(text: &[char], comments: Vec<Comment>) -> Vec<Comment> {
+    let mut merged: Vec<Comment> = Vec::new();
+
+    for comment in comments {
+        if let Some(prev) = merged.last_mut() {
+            let between = &text[prev.end..comment.start];
+            let adjacent = between.iter().filter(|ch| **ch == '\n').count() == 1
+                && between.iter().all(|ch| ch.is_ascii_whitespace());
+
+            if prev.kind == CommentKind::Line && comment.kind == CommentKind::Line && adjacent {
+                prev.end = comment.end;
+                continue;
+            }
+        }
+
+        merged.push(comment);
+    }
+
+    merged
+}
+
+/// The target of a comment, given the tokens following it.
+///
+fn target(tokens: &[MetaToken]) -> Option<DocTarget> {
+    match tokens {
+        [hash, MetaToken { token: PpToken::Identifier(name), .. }, ..] if hash.token == PpToken::Hash && hash.starts_line => {
+            Some(DocTarget::Directive { name: name.clone(), loc: hash.loc })
+        },
+        [token, ..] => Some(DocTarget::Token(token.clone())),
+        [] => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn extracts_doc_comments() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = "/** A. */\nint a;\n/* no */ /**/ //// no\n/// B\n/// C\n#define B 1\n/** end */";
+
+        source.push_data(&PathBuf::from("abc"), text.chars().collect());
+
+        let docs = doc_comments(&mut source, &LexOptions::default())?;
+
+        assert_eq!(docs.len(), 3);
+
+        assert_eq!(docs[0].text, "/** A. */");
+        assert_eq!(docs[0].loc, Point { file: 0, line: 1, col: 1 });
+        assert!(matches!(&docs[0].target, Some(DocTarget::Token(MetaToken { token: PpToken::Identifier(id), .. })) if id == "int"));
+
+        assert_eq!(docs[1].text, "/// B\n/// C");
+        assert_eq!(docs[1].target, Some(DocTarget::Directive {
+            name: "define".to_string(),
+            loc: Point { file: 0, line: 6, col: 1 },
+        }));
+
+        assert_eq!(docs[2].target, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//
+// Evaluation of escape sequences in character and string literals. The
+// lexer keeps literals exactly as spelled; this turns that spelling into
+// the values of the code units the literal denotes.
+//
+use crate::ccerror::CcError;
+use crate::lexer::Encoding;
+use crate::source::Point;
+
+/// Evaluate the raw text of a character or string literal (without its
+/// quotes or prefix) into code unit values.
+///
+/// Characters written directly in the source, and universal character names,
+/// are encoded in the literal's encoding: UTF-8 for plain and `u8` literals,
+/// UTF-16 for `u` literals, and UTF-32 for `L` and `U` literals. Numeric
+/// escapes give a single code unit, which must fit in the encoding's unit.
+///
+/// `loc` is the location of the literal, used for diagnostics. An escape the
+/// standard doesn't define, such as `\q`, stands for the character after the
+/// backslash, as in GCC. It is warned about where the literal is lexed; see
+/// `lexer::unknown_escapes`.
+///
+pub fn evaluate(text: &str, encoding: Encoding, loc: Point) -> Result<Vec<u32>, CcError> {
+    let mut units = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            encode(ch, encoding, &mut units);
+            continue;
+        }
+
+        let esc = match chars.next() {
+            Some(esc) => esc,
+            None => return Err(err("unterminated escape sequence", loc)),
+        };
+
+        match esc {
+            'x' => {
+                let mut value: u64 = 0;
+                let mut digits = 0;
+
+                while let Some(digit) = chars.next_if(|ch| ch.is_ascii_hexdigit()) {
+                    value = (value << 4) | digit.to_digit(16).unwrap() as u64;
+                    value = value.min(u64::from(u32::MAX) + 1);
+                    digits += 1;
+                }
+
+                if digits == 0 {
+                    return Err(err("\\x used with no following hex digits", loc));
+                }
+
+                if value > encoding.max_unit() as u64 {
+                    return Err(err("hex escape sequence out of range", loc));
+                }
+
+                units.push(value as u32);
+            },
+            '0'..='7' => {
+                let mut value = esc.to_digit(8).unwrap();
+                let mut digits = 1;
+
+                while digits < 3 {
+                    match chars.next_if(|ch| ('0'..='7').contains(ch)) {
+                        Some(digit) => value = (value << 3) | digit.to_digit(8).unwrap(),
+                        None => break,
+                    }
+                    digits += 1;
+                }
+
+                if value > encoding.max_unit() {
+                    return Err(err("octal escape sequence out of range", loc));
+                }
+
+                units.push(value);
+            },
+            'u' | 'U' => {
+                let len = if esc == 'u' { 4 } else { 8 };
+                let mut value = 0;
+
+                for _ in 0..len {
+                    match chars.next_if(|ch| ch.is_ascii_hexdigit()) {
+                        Some(digit) => value = (value << 4) | digit.to_digit(16).unwrap(),
+                        None => {
+                            return Err(
+                                CcError::err_with_loc(
+                                    format!("incomplete universal character name \\{}", esc),
+                                    loc
+                                )
+                            )
+                        }
+                    }
+                }
+
+                encode(ucn(value, loc)?, encoding, &mut units);
+            },
+            'a' => units.push(0x07),
+            'b' => units.push(0x08),
+            'f' => units.push(0x0c),
+            'n' => units.push(0x0a),
+            'r' => units.push(0x0d),
+            't' => units.push(0x09),
+            'v' => units.push(0x0b),
+
+            //
+            // GNU extension for ESC.
+            //
+            'e' | 'E' => units.push(0x1b),
+
+            //
+            // \\, \', \" and \? stand for themselves, as (like GCC) does
+            // any escape the standard doesn't define.
+            //
+            esc => encode(esc, encoding, &mut units),
+        }
+    }
+
+    Ok(units)
+}
+
+/// Whether `esc` can follow a backslash in a character or string literal:
+/// one of the standard escapes, or GCC's `\e`.
+///
+pub fn is_known_escape(esc: char) -> bool {
+    matches!(esc, 'x' | '0'..='7' | 'u' | 'U' | 'a' | 'b' | 'f' | 'n' | 'r' | 't' | 'v' | 'e' | 'E' | '\\' | '\'' | '"' | '?')
+}
+
+/// The warning for an escape sequence which isn't known, at `loc`.
+///
+pub fn unknown_escape(esc: char, loc: Point) -> CcError {
+    CcError::err_with_loc(format!("unknown escape sequence: '\\{}'", esc.escape_default()), loc)
+}
+
+/// Encode the body of a raw string literal into code unit values. There are
+/// no escapes in a raw string, so every character stands for itself.
+///
+pub fn evaluate_raw(text: &str, encoding: Encoding) -> Vec<u32> {
+    let mut units = Vec::new();
+
+    for ch in text.chars() {
+        encode(ch, encoding, &mut units);
+    }
+
+    units
+}
+
+/// Evaluate a character constant to its value, as in an `#if` expression.
+///
+/// The values are those GCC gives on common targets, where `char` is a signed
+/// 8-bit type and `int` and `wchar_t` are signed 32-bit types:
+///
+/// - A plain constant with one character has the value of that `char`, so
+///   `'\xff'` is -1.
+/// - A plain constant with several characters (a multi-character constant) is
+///   an `int` whose bytes are the characters, first character most significant:
+///   `'ab'` is `('a' << 8) | 'b'`. Only the last four characters fit; a longer
+///   constant keeps those and is warned about.
+/// - `L`, `u` and `U` constants take the value of their last code unit as a 
+///   `wchar_t`, `char16_t` or `char32_t` respectively, with a warning if there
+///   was more than one.
+/// - A `u8` constant must be a single code unit.
+///
+/// Multi-character constants are implementation-defined, so each one adds
+/// a portability warning to `warnings`.
+///
+pub fn char_constant(text: &str, encoding: Encoding, loc: Point, warnings: &mut Vec<CcError>) -> Result<i64, CcError> {
+    let units = evaluate(text, encoding, loc)?;
+
+    let last = match units.last() {
+        Some(last) => *last,
+        None => return Err(err("empty character constant", loc)),
+    };
+
+    if units.len() > 1 {
+        match encoding {
+            Encoding::Plain => {
+                warnings.push(err("multi-character character constant", loc));
+                if units.len() > 4 {
+                    warnings.push(err("character constant too long for its type", loc));
+                }
+            },
+            Encoding::Utf8 => {
+                return Err(err("character too large for a u8 character constant", loc))
+            },
+            _ => warnings.push(err("character constant too long for its type", loc)),
+        }
+    }
+
+    let value = match encoding {
+        Encoding::Plain if units.len() == 1 => last as u8 as i8 as i64,
+        Encoding::Plain => units.iter().fold(0u32, |acc, unit| (acc << 8) | (unit & 0xff)) as i32 as i64,
+        Encoding::Wide => last as i32 as i64,
+        Encoding::Utf8 | Encoding::Utf16 | Encoding::Utf32 => last as i64,
+    };
+
+    Ok(value)
+}
+
+/// Validate the value of a universal character name.
+///
+fn ucn(value: u32, loc: Point) -> Result<char, CcError> {
+    //
+    // Below 0xa0, only $, @ and ` may be named (C11 6.4.3p2).
+    //
+    if value < 0xa0 && value != 0x24 && value != 0x40 && value != 0x60 {
+        return Err(
+            CcError::err_with_loc(
+                format!("universal character \\U{:08x} is not valid in a literal", value),
+                loc
+            )
+        );
+    }
+
+    match char::from_u32(value) {
+        Some(ch) => Ok(ch),
+        None => Err(
+            CcError::err_with_loc(
+                format!("\\U{:08x} is not a valid universal character", value),
+                loc
+            )
+        ),
+    }
+}
+
+/// Append the code units for `ch` in the given encoding.
+///
+fn encode(ch: char, encoding: Encoding, units: &mut Vec<u32>) {
+    match encoding {
+        Encoding::Plain | Encoding::Utf8 => {
+            let mut buf = [0; 4];
+            units.extend(ch.encode_utf8(&mut buf).bytes().map(u32::from));
+        },
+        Encoding::Utf16 => {
+            let mut buf = [0; 2];
+            units.extend(ch.encode_utf16(&mut buf).iter().map(|unit| u32::from(*unit)));
+        },
+        Encoding::Wide | Encoding::Utf32 => units.push(ch as u32),
+    }
+}
+
+fn err(what: &'static str, loc: Point) -> CcError {
+    CcError::err_with_loc(what.to_string(), loc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOC: Point = Point { file: 0, line: 1, col: 1 };
+
+    #[test]
+    fn simple_escapes() -> Result<(), CcError> {
+        let units = evaluate("a\\n\\t\\\\\\'\\\"\\?", Encoding::Plain, LOC)?;
+
+        assert_eq!(units, vec![0x61, 0x0a, 0x09, 0x5c, 0x27, 0x22, 0x3f]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_escapes_stand_for_themselves() -> Result<(), CcError> {
+        assert_eq!(evaluate("\\q\\e", Encoding::Plain, LOC)?, vec![0x71, 0x1b]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_escapes() -> Result<(), CcError> {
+        assert_eq!(evaluate("\\x41\\101\\0\\1234", Encoding::Plain, LOC)?, vec![0x41, 0x41, 0, 0o123, 0x34]);
+        assert_eq!(evaluate("\\x1234", Encoding::Utf16, LOC)?, vec![0x1234]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_escapes_out_of_range() {
+        assert!(evaluate("\\x100", Encoding::Plain, LOC).is_err());
+        assert!(evaluate("\\400", Encoding::Plain, LOC).is_err());
+        assert!(evaluate("\\x10000", Encoding::Utf16, LOC).is_err());
+        assert!(evaluate("\\x100000000", Encoding::Utf32, LOC).is_err());
+        assert!(evaluate("\\x", Encoding::Plain, LOC).is_err());
+    }
+
+    #[test]
+    fn characters_are_encoded() -> Result<(), CcError> {
+        assert_eq!(evaluate("£", Encoding::Plain, LOC)?, vec![0xc2, 0xa3]);
+        assert_eq!(evaluate("\\u00a3", Encoding::Utf8, LOC)?, vec![0xc2, 0xa3]);
+        assert_eq!(evaluate("\\U0001F600", Encoding::Utf16, LOC)?, vec![0xd83d, 0xde00]);
+        assert_eq!(evaluate("\\U0001F600", Encoding::Wide, LOC)?, vec![0x1f600]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_character_constants() -> Result<(), CcError> {
+        let mut warnings = Vec::new();
+
+        assert_eq!(char_constant("a", Encoding::Plain, LOC, &mut warnings)?, 97);
+        assert_eq!(char_constant("\\xff", Encoding::Plain, LOC, &mut warnings)?, -1);
+        assert_eq!(char_constant("\\xff", Encoding::Utf8, LOC, &mut warnings)?, 255);
+        assert_eq!(char_constant("\\xffffffff", Encoding::Wide, LOC, &mut warnings)?, -1);
+        assert_eq!(char_constant("\\xffffffff", Encoding::Utf32, LOC, &mut warnings)?, 0xffffffff);
+        assert_eq!(char_constant("\\U0001F600", Encoding::Utf32, LOC, &mut warnings)?, 0x1f600);
+        assert!(warnings.is_empty());
+
+        assert!(char_constant("", Encoding::Plain, LOC, &mut warnings).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_character_constants() -> Result<(), CcError> {
+        let mut warnings = Vec::new();
+
+        assert_eq!(char_constant("ab", Encoding::Plain, LOC, &mut warnings)?, 0x6162);
+        assert_eq!(warnings.len(), 1);
+
+        assert_eq!(char_constant("\\377bcd", Encoding::Plain, LOC, &mut warnings)?, -0x9d9c9c);
+        assert_eq!(char_constant("abcde", Encoding::Plain, LOC, &mut warnings)?, 0x62636465);
+        assert_eq!(warnings.len(), 4);
+
+        let mut warnings = Vec::new();
+
+        assert_eq!(char_constant("ab", Encoding::Wide, LOC, &mut warnings)?, 98);
+        assert_eq!(warnings.len(), 1);
+
+        assert!(char_constant("ab", Encoding::Utf8, LOC, &mut warnings).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_ucns() {
+        assert!(evaluate("\\u0041", Encoding::Plain, LOC).is_err());
+        assert!(evaluate("\\ud800", Encoding::Utf32, LOC).is_err());
+        assert!(evaluate("\\U00110000", Encoding::Utf32, LOC).is_err());
+        assert!(evaluate("\\u12", Encoding::Utf32, LOC).is_err());
+    }
+}
@@ -0,0 +1,588 @@
+//
+// Evaluation of the controlling expressions of #if and #elif. The
+// preprocessor replaces `defined` and expands macros first, so what is
+// left is numbers, character constants, operators, and identifiers which
+// aren't macros.
+//
+use crate::ccerror::CcError;
+use crate::escape;
+use crate::lexer::{Encoding, MetaToken, PpToken};
+use crate::macros::MacroTable;
+use crate::number::{self, NumericConstant};
+use crate::source::Point;
+
+use std::fmt::Display;
+
+/// A value in a #if expression. Every integer has the type intmax_t or
+/// uintmax_t.
+///
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    /// Is the value non-zero?
+    ///
+    pub fn is_true(self) -> bool {
+        self.bits() != 0
+    }
+
+    fn bits(self) -> u64 {
+        match self {
+            Value::Signed(value) => value as u64,
+            Value::Unsigned(value) => value,
+        }
+    }
+
+    fn is_unsigned(self) -> bool {
+        matches!(self, Value::Unsigned(_))
+    }
+
+    /// The value converted to unsigned if `unsigned` is set, as for the
+    /// usual arithmetic conversions.
+    ///
+    fn convert(self, unsigned: bool) -> Value {
+        if unsigned {
+            Value::Unsigned(self.bits())
+        } else {
+            self
+        }
+    }
+
+    fn from_bool(value: bool) -> Value {
+        Value::Signed(value as i64)
+    }
+
+    /// The C name of the value's type.
+    ///
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Value::Signed(_) => "intmax_t",
+            Value::Unsigned(_) => "uintmax_t",
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Signed(value) => write!(f, "{}", value),
+            Value::Unsigned(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Replace each `defined X` and `defined ( X )` in the tokens of a #if line
+/// with 1 if X is a macro and 0 if it isn't. This has to be done before the
+/// line is macro expanded, so that X isn't expanded itself.
+///
+pub fn replace_defined(tokens: &[MetaToken], macros: &MacroTable) -> Result<Vec<MetaToken>, CcError> {
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut rest = tokens;
+
+    while let Some((token, after)) = rest.split_first() {
+        rest = after;
+
+        if token.token != PpToken::Identifier("defined".to_string()) {
+            out.push(token.clone());
+            continue;
+        }
+
+        let parenthesized = rest.first().is_some_and(|token| token.token == PpToken::LeftParen);
+        if parenthesized {
+            rest = &rest[1..];
+        }
+
+        let name = match rest.split_first() {
+            Some((MetaToken { token: PpToken::Identifier(name), .. }, after)) => {
+                rest = after;
+                name
+            },
+            _ => {
+                return Err(CcError::err_with_loc("operator \"defined\" requires an identifier".to_string(), token.loc));
+            },
+        };
+
+        if parenthesized {
+            match rest.split_first() {
+                Some((MetaToken { token: PpToken::RightParen, .. }, after)) => rest = after,
+                _ => return Err(CcError::err_with_loc("missing ')' after \"defined\"".to_string(), token.loc)),
+            }
+        }
+
+        let value = if macros.is_defined(name) { "1" } else { "0" };
+        out.push(MetaToken { token: PpToken::Number(value.to_string()), ..token.clone() });
+    }
+
+    Ok(out)
+}
+
+/// Evaluate a controlling expression. If `booleans` is set, as in C++ and
+/// C23, `true` is 1; otherwise it's an identifier like any other, which is
+/// 0. `loc` is the location of the directive, for errors at the end of the
+/// expression.
+///
+pub fn evaluate(tokens: &[MetaToken], booleans: bool, loc: Point, warnings: &mut Vec<CcError>) -> Result<Value, CcError> {
+    if tokens.is_empty() {
+        return Err(CcError::err_with_loc("#if with no expression".to_string(), loc));
+    }
+
+    let mut parser = Parser { tokens, pos: 0, booleans, loc, evaluating: true, warnings };
+    let value = parser.expression()?;
+
+    match parser.tokens.get(parser.pos) {
+        Some(token) => Err(CcError::err_with_loc(
+            format!("missing binary operator before token \"{}\"", token.token),
+            token.loc
+        )),
+        None => Ok(value),
+    }
+}
+
+/// The precedence of a binary operator, higher binding tighter, or None if
+/// the token isn't one.
+///
+fn precedence(token: &PpToken) -> Option<u32> {
+    let precedence = match token {
+        PpToken::LogicalOr => 1,
+        PpToken::LogicalAnd => 2,
+        PpToken::BitOr => 3,
+        PpToken::BitXor => 4,
+        PpToken::Ampersand => 5,
+        PpToken::Equal | PpToken::NotEqual => 6,
+        PpToken::Less | PpToken::LessEqual | PpToken::Greater | PpToken::GreaterEqual => 7,
+        PpToken::ShiftLeft | PpToken::ShiftRight => 8,
+        PpToken::Add | PpToken::Subtract => 9,
+        PpToken::Star | PpToken::Divide | PpToken::Mod => 10,
+        _ => return None,
+    };
+
+    Some(precedence)
+}
+
+/// A recursive descent parser which evaluates as it goes.
+///
+struct Parser<'a> {
+    tokens: &'a [MetaToken],
+
+    /// The index of the next token.
+    pos: usize,
+
+    booleans: bool,
+
+    /// The location of the directive.
+    loc: Point,
+
+    /// False in an operand which isn't evaluated, such as the right hand
+    /// side of `0 && x`. Division by zero and overflow there aren't
+    /// diagnosed.
+    evaluating: bool,
+
+    warnings: &'a mut Vec<CcError>,
+}
+
+impl Parser<'_> {
+    /// The kind of the next token, so that an alternative token such as
+    /// `and` is taken as the operator it stands for.
+    ///
+    fn peek(&self) -> Option<&PpToken> {
+        self.tokens.get(self.pos).map(|token| token.token.kind())
+    }
+
+    fn next(&mut self) -> Result<&'_ MetaToken, CcError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
+            },
+            None => Err(CcError::err_with_loc("expected value in expression".to_string(), self.loc)),
+        }
+    }
+
+    /// The location of the next token, or of the directive at the end.
+    ///
+    fn next_loc(&self) -> Point {
+        self.tokens.get(self.pos).map_or(self.loc, |token| token.loc)
+    }
+
+    /// Parse an operand with evaluation turned on or off.
+    ///
+    fn with_evaluating<F>(&mut self, evaluating: bool, f: F) -> Result<Value, CcError>
+        where F: FnOnce(&mut Self) -> Result<Value, CcError>
+    {
+        let outer = self.evaluating;
+        self.evaluating = outer && evaluating;
+        let value = f(self);
+        self.evaluating = outer;
+        value
+    }
+
+    /// expression: conditional { `,` conditional }
+    ///
+    fn expression(&mut self) -> Result<Value, CcError> {
+        let mut value = self.conditional()?;
+
+        while self.peek() == Some(&PpToken::Comma) {
+            self.pos += 1;
+            value = self.conditional()?;
+        }
+
+        Ok(value)
+    }
+
+    /// conditional: binary [ `?` expression `:` conditional ]
+    ///
+    fn conditional(&mut self) -> Result<Value, CcError> {
+        let condition = self.binary(1)?;
+
+        if self.peek() != Some(&PpToken::Question) {
+            return Ok(condition);
+        }
+
+        let question = self.next()?.loc;
+        let taken = condition.is_true();
+
+        let if_true = self.with_evaluating(taken, |parser| parser.expression())?;
+
+        if self.peek() != Some(&PpToken::Colon) {
+            return Err(CcError::err_with_loc("'?' without following ':'".to_string(), question));
+        }
+        self.pos += 1;
+
+        let if_false = self.with_evaluating(!taken, |parser| parser.conditional())?;
+
+        let unsigned = if_true.is_unsigned() || if_false.is_unsigned();
+        Ok(if taken { if_true } else { if_false }.convert(unsigned))
+    }
+
+    /// Binary operators of at least the given precedence, by precedence
+    /// climbing. All of them are left associative.
+    ///
+    fn binary(&mut self, min_precedence: u32) -> Result<Value, CcError> {
+        let mut lhs = self.unary()?;
+
+        while let Some(precedence) = self.peek().and_then(precedence) {
+            if precedence < min_precedence {
+                break;
+            }
+
+            let op = self.next()?;
+            let (op, loc) = (op.token.kind().clone(), op.loc);
+
+            //
+            // The right hand side of && and || is only evaluated if it can
+            // change the result.
+            //
+            let evaluate_rhs = match op {
+                PpToken::LogicalAnd => lhs.is_true(),
+                PpToken::LogicalOr => !lhs.is_true(),
+                _ => true,
+            };
+
+            let rhs = self.with_evaluating(evaluate_rhs, |parser| parser.binary(precedence + 1))?;
+            lhs = self.apply(&op, lhs, rhs, loc)?;
+        }
+
+        Ok(lhs)
+    }
+
+    /// unary: { `+` | `-` | `~` | `!` } primary
+    ///
+    fn unary(&mut self) -> Result<Value, CcError> {
+        let op = match self.peek() {
+            Some(op @ (PpToken::Add | PpToken::Subtract | PpToken::BitNot | PpToken::LogicalNot)) => op.clone(),
+            _ => return self.primary(),
+        };
+
+        let loc = self.next()?.loc;
+        let value = self.unary()?;
+
+        Ok(match (op, value) {
+            (PpToken::Subtract, Value::Signed(value)) => {
+                if value == i64::MIN {
+                    self.overflow(loc);
+                }
+                Value::Signed(value.wrapping_neg())
+            },
+            (PpToken::Subtract, Value::Unsigned(value)) => Value::Unsigned(value.wrapping_neg()),
+            (PpToken::BitNot, Value::Signed(value)) => Value::Signed(!value),
+            (PpToken::BitNot, Value::Unsigned(value)) => Value::Unsigned(!value),
+            (PpToken::LogicalNot, value) => Value::from_bool(!value.is_true()),
+            (_, value) => value,
+        })
+    }
+
+    /// primary: number | character-constant | identifier | `(` expression `)`
+    ///
+    fn primary(&mut self) -> Result<Value, CcError> {
+        let loc = self.next_loc();
+        let token = self.next()?.token.clone();
+
+        match token {
+            PpToken::Number(spelling) => self.number(&spelling, loc),
+            PpToken::CharLiteral(encoding, text) => {
+                let value = escape::char_constant(&text, encoding, loc, self.warnings)?;
+                Ok(match encoding {
+                    Encoding::Plain | Encoding::Wide => Value::Signed(value),
+                    _ => Value::Unsigned(value as u64),
+                })
+            },
+            PpToken::Identifier(id) => Ok(Value::from_bool(self.booleans && id == "true")),
+            PpToken::LeftParen => {
+                let value = self.expression()?;
+
+                match self.peek() {
+                    Some(PpToken::RightParen) => {
+                        self.pos += 1;
+                        Ok(value)
+                    },
+                    _ => Err(CcError::err_with_loc("missing ')' in expression".to_string(), self.next_loc())),
+                }
+            },
+            token => Err(CcError::err_with_loc(
+                format!("token \"{}\" is not valid in preprocessor expressions", token),
+                loc
+            )),
+        }
+    }
+
+    /// The value of an integer constant. It's signed if it fits, unless it
+    /// has a `u` suffix.
+    ///
+    fn number(&mut self, spelling: &str, loc: Point) -> Result<Value, CcError> {
+        let constant = match number::convert(spelling, loc)? {
+            NumericConstant::Int(constant) => constant,
+            NumericConstant::Float(_) => {
+                return Err(CcError::err_with_loc("floating constant in preprocessor expression".to_string(), loc));
+            },
+        };
+
+        if constant.unsigned {
+            return Ok(Value::Unsigned(constant.value));
+        }
+
+        match i64::try_from(constant.value) {
+            Ok(value) => Ok(Value::Signed(value)),
+            Err(_) => {
+                if constant.radix == 10 {
+                    self.warnings.push(CcError::err_with_loc(
+                        "integer constant is so large that it is unsigned".to_string(),
+                        loc
+                    ));
+                }
+                Ok(Value::Unsigned(constant.value))
+            },
+        }
+    }
+
+    /// Apply a binary operator.
+    ///
+    fn apply(&mut self, op: &PpToken, lhs: Value, rhs: Value, loc: Point) -> Result<Value, CcError> {
+        match op {
+            PpToken::LogicalAnd => return Ok(Value::from_bool(lhs.is_true() && rhs.is_true())),
+            PpToken::LogicalOr => return Ok(Value::from_bool(lhs.is_true() || rhs.is_true())),
+            PpToken::ShiftLeft => return Ok(self.shift(lhs, rhs, true)),
+            PpToken::ShiftRight => return Ok(self.shift(lhs, rhs, false)),
+            _ => {},
+        }
+
+        let unsigned = lhs.is_unsigned() || rhs.is_unsigned();
+
+        if matches!(op, PpToken::Divide | PpToken::Mod) && rhs.bits() == 0 {
+            if self.evaluating {
+                return Err(CcError::err_with_loc("division by zero in #if".to_string(), loc));
+            }
+            return Ok(Value::Signed(0).convert(unsigned));
+        }
+
+        let value = match (lhs.convert(unsigned), rhs.convert(unsigned)) {
+            (Value::Unsigned(a), Value::Unsigned(b)) => match op {
+                PpToken::Add => Value::Unsigned(a.wrapping_add(b)),
+                PpToken::Subtract => Value::Unsigned(a.wrapping_sub(b)),
+                PpToken::Star => Value::Unsigned(a.wrapping_mul(b)),
+                PpToken::Divide => Value::Unsigned(a / b),
+                PpToken::Mod => Value::Unsigned(a % b),
+                PpToken::Ampersand => Value::Unsigned(a & b),
+                PpToken::BitOr => Value::Unsigned(a | b),
+                PpToken::BitXor => Value::Unsigned(a ^ b),
+                op => Value::from_bool(compare(op, a, b)),
+            },
+            (a, b) => {
+                let (a, b) = (a.bits() as i64, b.bits() as i64);
+
+                let (value, overflow) = match op {
+                    PpToken::Add => a.overflowing_add(b),
+                    PpToken::Subtract => a.overflowing_sub(b),
+                    PpToken::Star => a.overflowing_mul(b),
+                    PpToken::Divide => a.overflowing_div(b),
+                    PpToken::Mod => a.overflowing_rem(b),
+                    PpToken::Ampersand => (a & b, false),
+                    PpToken::BitOr => (a | b, false),
+                    PpToken::BitXor => (a ^ b, false),
+                    op => (compare(op, a, b) as i64, false),
+                };
+
+                if overflow {
+                    self.overflow(loc);
+                }
+                Value::Signed(value)
+            },
+        };
+
+        Ok(value)
+    }
+
+    /// Shift `lhs` by `rhs` bits. The result has the type of `lhs`. A
+    /// negative shift shifts the other way, and shifting by the width or
+    /// more shifts every bit out, leaving the sign for a signed right shift.
+    ///
+    fn shift(&mut self, lhs: Value, rhs: Value, left: bool) -> Value {
+        let (left, amount) = match rhs {
+            Value::Signed(amount) if amount < 0 => (!left, amount.unsigned_abs()),
+            amount => (left, amount.bits()),
+        };
+
+        let amount = amount.min(64) as u32;
+
+        match lhs {
+            Value::Unsigned(value) if left => Value::Unsigned(value.checked_shl(amount).unwrap_or(0)),
+            Value::Unsigned(value) => Value::Unsigned(value.checked_shr(amount).unwrap_or(0)),
+            Value::Signed(value) if left => Value::Signed(value.checked_shl(amount).unwrap_or(0)),
+            Value::Signed(value) => Value::Signed(value >> amount.min(63)),
+        }
+    }
+
+    /// Warn about signed overflow, if the operand is evaluated.
+    ///
+    fn overflow(&mut self, loc: Point) {
+        if self.evaluating {
+            self.warnings.push(CcError::err_with_loc("integer overflow in preprocessor expression".to_string(), loc));
+        }
+    }
+}
+
+/// Apply a comparison operator.
+///
+fn compare<T: Ord>(op: &PpToken, a: T, b: T) -> bool {
+    match op {
+        PpToken::Equal => a == b,
+        PpToken::NotEqual => a != b,
+        PpToken::Less => a < b,
+        PpToken::LessEqual => a <= b,
+        PpToken::Greater => a > b,
+        PpToken::GreaterEqual => a >= b,
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    const LOC: Point = Point { file: 0, line: 1, col: 1 };
+
+    fn eval(text: &str) -> Result<Value, CcError> {
+        evaluate(&lex(text)?, false, LOC, &mut Vec::new())
+    }
+
+    #[test]
+    fn evaluates_arithmetic() -> Result<(), CcError> {
+        assert_eq!(eval("1 + 2 * 3")?, Value::Signed(7));
+        assert_eq!(eval("(1 + 2) * 3")?, Value::Signed(9));
+        assert_eq!(eval("10 - 2 - 3")?, Value::Signed(5));
+        assert_eq!(eval("-7 / 2")?, Value::Signed(-3));
+        assert_eq!(eval("-7 % 2")?, Value::Signed(-1));
+        assert_eq!(eval("~0 == -1")?, Value::Signed(1));
+        assert_eq!(eval("!5 + !0")?, Value::Signed(1));
+        assert_eq!(eval("0x10 | 1 ^ 3 & 2")?, Value::Signed(0x13));
+        assert_eq!(eval("'A' + 1")?, Value::Signed(66));
+        assert_eq!(eval("1, 2")?, Value::Signed(2));
+
+        let value = eval("-1 + 0u")?;
+        assert_eq!((value.to_string(), value.type_name()), ("18446744073709551615".to_string(), "uintmax_t"));
+        let value = eval("-1")?;
+        assert_eq!((value.to_string(), value.type_name()), ("-1".to_string(), "intmax_t"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn uses_unsigned_arithmetic() -> Result<(), CcError> {
+        assert_eq!(eval("-1 < 0")?, Value::Signed(1));
+        assert_eq!(eval("-1 < 0u")?, Value::Signed(0));
+        assert_eq!(eval("0u - 1")?, Value::Unsigned(u64::MAX));
+        assert_eq!(eval("0xffffffffffffffff")?, Value::Unsigned(u64::MAX));
+        assert_eq!(eval("1 ? -1 : 0u")?, Value::Unsigned(u64::MAX));
+        assert_eq!(eval("-1 >> 70")?, Value::Signed(-1));
+        assert_eq!(eval("1 << -1")?, Value::Signed(0));
+        assert_eq!(eval("1u << 63 >> 63")?, Value::Unsigned(1));
+
+        let mut warnings = Vec::new();
+        evaluate(&lex("18446744073709551615 == 0 || 0x7fffffffffffffff + 1")?, false, LOC, &mut warnings)?;
+        let warnings: Vec<String> = warnings.into_iter().map(|w| w.what).collect();
+        assert_eq!(warnings, vec![
+            "integer constant is so large that it is unsigned",
+            "integer overflow in preprocessor expression",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_circuits() -> Result<(), CcError> {
+        assert_eq!(eval("0 && 1 / 0")?, Value::Signed(0));
+        assert_eq!(eval("1 || 1 / 0")?, Value::Signed(1));
+        assert_eq!(eval("1 ? 2 : 1 / 0")?, Value::Signed(2));
+        assert_eq!(eval("2 || 0")?, Value::Signed(1));
+        assert_eq!(eval("1 && 0 || 3 && 4")?, Value::Signed(1));
+        assert_eq!(eval("0 ? 1 : 0 ? 2 : 3")?, Value::Signed(3));
+        assert_eq!(eval("1 / 0").unwrap_err().what, "division by zero in #if");
+
+        let mut warnings = Vec::new();
+        evaluate(&lex("0 && -0x7fffffffffffffff - 2")?, false, LOC, &mut warnings)?;
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn treats_identifiers_as_zero() -> Result<(), CcError> {
+        assert_eq!(eval("FOO + 1")?, Value::Signed(1));
+        assert_eq!(eval("true")?, Value::Signed(0));
+        assert_eq!(evaluate(&lex("true && !false")?, true, LOC, &mut Vec::new())?, Value::Signed(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_defined() -> Result<(), CcError> {
+        let mut macros = MacroTable::new();
+        macros.define_option("A", &mut Vec::new())?;
+
+        let spell = |tokens: Vec<MetaToken>| tokens.iter().map(|token| token.token.spelling()).collect::<Vec<_>>().join(" ");
+
+        assert_eq!(spell(replace_defined(&lex("defined A && defined(B) || defined ( A )")?, &macros)?), "1 && 0 || 1");
+        assert_eq!(replace_defined(&lex("defined")?, &macros).unwrap_err().what, "operator \"defined\" requires an identifier");
+        assert_eq!(replace_defined(&lex("defined(1)")?, &macros).unwrap_err().what, "operator \"defined\" requires an identifier");
+        assert_eq!(replace_defined(&lex("defined(A")?, &macros).unwrap_err().what, "missing ')' after \"defined\"");
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagnoses_bad_expressions() -> Result<(), CcError> {
+        assert_eq!(eval("").unwrap_err().what, "#if with no expression");
+        assert_eq!(eval("1 +").unwrap_err().what, "expected value in expression");
+        assert_eq!(eval("(1").unwrap_err().what, "missing ')' in expression");
+        assert_eq!(eval("1 2").unwrap_err().what, "missing binary operator before token \"2\"");
+        assert_eq!(eval("1 ? 2").unwrap_err().what, "'?' without following ':'");
+        assert_eq!(eval("1.0").unwrap_err().what, "floating constant in preprocessor expression");
+        assert_eq!(eval("x = 1").unwrap_err().what, "missing binary operator before token \"=\"");
+        assert_eq!(eval("\"s\"").unwrap_err().what, "token \"\"s\"\" is not valid in preprocessor expressions");
+
+        Ok(())
+    }
+}
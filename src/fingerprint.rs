@@ -0,0 +1,142 @@
+//
+// Stable hashes of source text and token streams, for build tools and
+// caches which compare them between runs. The standard library's hasher
+// may change between releases, so FNV-1a is used instead.
+//
+use crate::json;
+use crate::lexer::PpToken;
+use crate::source::Source;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// A 64-bit FNV-1a hash, built up incrementally.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Fnv64 {
+    /// Add bytes to the hash.
+    ///
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Add text to the hash, as UTF-8.
+    ///
+    pub fn write_text(&mut self, text: &[char]) {
+        let mut buf = [0; 4];
+        for ch in text {
+            self.write(ch.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+
+    /// Add a token to the hash. Only the kind and spelling of the token
+    /// count, not where it is or the whitespace around it.
+    ///
+    pub fn write_token(&mut self, token: &PpToken) {
+        let (kind, spelling) = json::kind_and_spelling(token);
+
+        self.write(kind.as_bytes());
+        self.write(&[0]);
+        self.write(spelling.as_bytes());
+        self.write(&[0]);
+    }
+
+    /// The hash of everything written so far.
+    ///
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The hash of some text.
+///
+pub fn hash_text(text: &[char]) -> u64 {
+    let mut hasher = Fnv64::default();
+    hasher.write_text(text);
+    hasher.finish()
+}
+
+/// Format a hash as 16 hex digits.
+///
+pub fn hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Format a manifest of a run as a single-line JSON object: the files read,
+/// in the order they were read, with the hash of each, and the hash of the
+/// token stream. `tokens` is None, and left out, if the run didn't produce a
+/// token stream.
+///
+pub fn manifest_json(source: &Source, tokens: Option<u64>) -> String {
+    let files: Vec<String> = source.files.iter()
+        .map(|file| format!(
+            "{{\"path\":{},\"hash\":\"{}\"}}",
+            json::string(&file.strname),
+            hex(hash_text(&file.text))
+        ))
+        .collect();
+
+    match tokens {
+        Some(tokens) => format!("{{\"files\":[{}],\"tokens\":\"{}\"}}", files.join(","), hex(tokens)),
+        None => format!("{{\"files\":[{}]}}", files.join(",")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccerror::CcError;
+    use crate::lexer::lex;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn hashes_text() {
+        assert_eq!(hash_text(&[]), 0xcbf29ce484222325);
+        assert_eq!(hash_text(&['a']), 0xaf63dc4c8601ec8c);
+        assert_eq!(hex(hash_text(&"foobar".chars().collect::<Vec<_>>())), "85944171f73967e8");
+    }
+
+    #[test]
+    fn ignores_token_layout() -> Result<(), CcError> {
+        let hash = |text: &str| -> Result<u64, CcError> {
+            let mut hasher = Fnv64::default();
+            for token in lex(text)? {
+                hasher.write_token(&token.token);
+            }
+            Ok(hasher.finish())
+        };
+
+        assert_eq!(hash("a+b;")?, hash("a /* x */ +\n  b ;")?);
+        assert_ne!(hash("a+b;")?, hash("a+c;")?);
+        assert_ne!(hash("ab")?, hash("a b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn formats_manifest() {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("a\"b"), "foobar".chars().collect());
+
+        assert_eq!(
+            manifest_json(&source, Some(1)),
+            "{\"files\":[{\"path\":\"a\\\"b\",\"hash\":\"85944171f73967e8\"}],\"tokens\":\"0000000000000001\"}"
+        );
+        assert_eq!(
+            manifest_json(&source, None),
+            "{\"files\":[{\"path\":\"a\\\"b\",\"hash\":\"85944171f73967e8\"}]}"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//
+// Apple header maps (.hmap files), which Xcode generates to map header names
+// to the files that implement them, and passes to the compiler with -I as if
+// they were directories.
+//
+// The layout, in the byte order of the machine that wrote it, is:
+//
+//      magic           u32, "hmap"
+//      version         u16, 1
+//      reserved        u16, 0
+//      strings         u32, offset of the string table
+//      nentries        u32, count of used buckets
+//      nbuckets        u32, count of buckets, a power of two
+//      maxvalue        u32, length of the longest value
+//      buckets         each: key, prefix, suffix, as u32 string offsets
+//
+// A string offset is relative to the start of the string table, and names a
+// zero terminated string; a key of zero marks an empty bucket. The value for
+// a key is its prefix followed by its suffix. Keys are compared ignoring
+// ASCII case.
+//
+use crate::ccerror::CcError;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The magic number at the start of a header map, as a native u32.
+const MAGIC: u32 = u32::from_be_bytes(*b"hmap");
+
+/// The only version of the format.
+const VERSION: u16 = 1;
+
+/// The size of the header, before the buckets.
+const HEADER_SIZE: usize = 24;
+
+/// The size of each bucket.
+const BUCKET_SIZE: usize = 12;
+
+/// A parsed header map.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderMap {
+    /// The mapped file for each header name, keyed in lower case.
+    entries: HashMap<String, String>,
+}
+
+impl HeaderMap {
+    /// Read a header map from a file.
+    ///
+    pub fn open(path: &Path) -> Result<Self, CcError> {
+        let bytes = std::fs::read(path)?;
+        HeaderMap::parse(&bytes)
+            .map_err(|e| CcError::new(format!("{}: {}", path.to_string_lossy(), e.what)))
+    }
+
+    /// Parse a header map from its bytes.
+    ///
+    pub fn parse(bytes: &[u8]) -> Result<Self, CcError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(CcError::from_static("truncated header map"));
+        }
+
+        let swapped = match u32::from_le_bytes(bytes[0..4].try_into().unwrap()) {
+            MAGIC => false,
+            magic if magic.swap_bytes() == MAGIC => true,
+            _ => return Err(CcError::from_static("not a header map")),
+        };
+
+        let u16_at = |offset: usize| {
+            let value = u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+            if swapped { value.swap_bytes() } else { value }
+        };
+
+        let u32_at = |offset: usize| {
+            let value = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            if swapped { value.swap_bytes() } else { value }
+        };
+
+        if u16_at(4) != VERSION {
+            return Err(CcError::new(format!("unsupported header map version {}", u16_at(4))));
+        }
+
+        let strings = u32_at(8) as usize;
+        let nbuckets = u32_at(16) as usize;
+
+        if !nbuckets.is_power_of_two() || HEADER_SIZE + nbuckets * BUCKET_SIZE > bytes.len() {
+            return Err(CcError::from_static("bad bucket count in header map"));
+        }
+
+        let string_at = |offset: u32| -> Result<String, CcError> {
+            let start = strings + offset as usize;
+            let text = bytes.get(start..).unwrap_or_default();
+
+            match text.iter().position(|byte| *byte == 0) {
+                Some(len) => Ok(String::from_utf8_lossy(&text[..len]).to_string()),
+                None => Err(CcError::from_static("bad string offset in header map")),
+            }
+        };
+
+        let mut entries = HashMap::new();
+
+        for bucket in 0..nbuckets {
+            let offset = HEADER_SIZE + bucket * BUCKET_SIZE;
+            let key = u32_at(offset);
+
+            if key == 0 {
+                continue;
+            }
+
+            let value = string_at(u32_at(offset + 4))? + &string_at(u32_at(offset + 8))?;
+            entries.insert(string_at(key)?.to_ascii_lowercase(), value);
+        }
+
+        Ok(HeaderMap { entries })
+    }
+
+    /// Look up a header name, returning the file it maps to.
+    ///
+    pub fn lookup(&self, name: &str) -> Option<PathBuf> {
+        self.entries.get(&name.to_ascii_lowercase()).map(PathBuf::from)
+    }
+}
+
+/// Whether an include path names a header map rather than a directory.
+///
+pub fn is_header_map(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "hmap") && path.is_file()
+}
+
+/// Build a little endian header map from (key, prefix, suffix) entries,
+/// for tests.
+///
+#[cfg(test)]
+pub(crate) fn build_hmap(entries: &[(&str, &str, &str)]) -> Vec<u8> {
+    let nbuckets = (entries.len() * 2).next_power_of_two() as u32;
+
+    //
+    // Offset zero is reserved to mark an empty bucket.
+    //
+    let mut strings = vec![0u8];
+    let mut add = |s: &str| {
+        let offset = strings.len() as u32;
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+        offset
+    };
+
+    let mut buckets = vec![[0u32; 3]; nbuckets as usize];
+    for (i, (key, prefix, suffix)) in entries.iter().enumerate() {
+        buckets[i] = [add(key), add(prefix), add(suffix)];
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&MAGIC.to_le_bytes());
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&(HEADER_SIZE as u32 + nbuckets * BUCKET_SIZE as u32).to_le_bytes());
+    bytes.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&nbuckets.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+
+    for bucket in buckets {
+        for offset in bucket {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+
+    bytes.extend_from_slice(&strings);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header_maps() -> Result<(), CcError> {
+        let map = HeaderMap::parse(&build_hmap(&[
+            ("Foo/Bar.h", "/src/foo/", "Bar.h"),
+            ("baz.h", "/src/", "baz/baz.h"),
+        ]))?;
+
+        assert_eq!(map.lookup("Foo/Bar.h"), Some(PathBuf::from("/src/foo/Bar.h")));
+        assert_eq!(map.lookup("foo/bar.h"), Some(PathBuf::from("/src/foo/Bar.h")));
+        assert_eq!(map.lookup("baz.h"), Some(PathBuf::from("/src/baz/baz.h")));
+        assert_eq!(map.lookup("quux.h"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_header_maps() {
+        let mut bytes = build_hmap(&[("a.h", "/", "a.h")]);
+
+        assert!(HeaderMap::parse(&bytes[..10]).is_err());
+
+        bytes[0] = b'x';
+        assert!(HeaderMap::parse(&bytes).is_err());
+    }
+}
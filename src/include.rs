@@ -0,0 +1,383 @@
+//
+// Resolution of header names in #include directives to files, following
+// the usual compiler search rules.
+//
+use crate::ccerror::CcError;
+use crate::hmap::{self, HeaderMap};
+use crate::source::Point;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// How a header was named in an #include.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+pub enum HeaderKind {
+    /// `#include "name"`
+    Quoted,
+
+    /// `#include <name>`
+    Angled,
+}
+
+/// The directories to search for included files.
+///
+/// A quoted header is searched for first in the directory of the file that
+/// includes it, then in the quote directories, then as an angled header.
+/// An angled header is searched for in the user directories, the system
+/// directories, then the framework directories.
+///
+#[derive(Debug, Default, Clone)]
+pub struct IncludePaths {
+    /// Directories searched only for quoted headers.
+    pub quote: Vec<PathBuf>,
+
+    /// User directories, from -I.
+    pub user: Vec<PathBuf>,
+
+    /// System directories.
+    pub system: Vec<PathBuf>,
+
+    /// Framework directories, from -F, searched after all the others for
+    /// headers named `Framework/Header.h`.
+    pub frameworks: Vec<PathBuf>,
+
+    /// If a header isn't found, look for it again ignoring case, as code
+    /// written on Windows often gets the case of header names wrong.
+    pub ignore_case: bool,
+
+    /// Header maps given in place of directories, by path; see
+    /// `load_header_maps`.
+    pub header_maps: HashMap<PathBuf, HeaderMap>,
+}
+
+impl IncludePaths {
+    /// The directories searched for a header of the given kind, in order,
+    /// not counting the directory of the including file.
+    ///
+    pub fn search_order(&self, kind: HeaderKind) -> Vec<&Path> {
+        let quote = match kind {
+            HeaderKind::Quoted => self.quote.as_slice(),
+            HeaderKind::Angled => &[],
+        };
+
+        quote.iter()
+            .chain(self.user.iter())
+            .chain(self.system.iter())
+            .map(|dir| dir.as_path())
+            .collect()
+    }
+
+    /// Read any header maps among the search directories. Until this is
+    /// called, they are treated as directories, and so never match.
+    ///
+    pub fn load_header_maps(&mut self) -> Result<(), CcError> {
+        for path in self.quote.iter().chain(self.user.iter()).chain(self.system.iter()) {
+            if hmap::is_header_map(path) && !self.header_maps.contains_key(path) {
+                self.header_maps.insert(path.clone(), HeaderMap::open(path)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the file for a header name. `includer` is the file containing
+    /// the #include, if any, whose directory is searched first for quoted
+    /// headers, and `loc` is where the #include is.
+    ///
+    /// Every path tried, including the one that was found, is appended to
+    /// `probed`, so that the search can be shown to the user. If the header
+    /// was only found by ignoring case, a warning at `loc` naming the file
+    /// on disk is appended to `warnings`.
+    ///
+    pub fn resolve(&self, name: &str, kind: HeaderKind, includer: Option<&Path>, loc: Option<Point>, probed: &mut Vec<PathBuf>, warnings: &mut Vec<CcError>) -> Option<PathBuf> {
+        let name = normalize_header_name(name);
+        let name = Path::new(&name);
+
+        if name.is_absolute() {
+            probed.push(name.to_path_buf());
+            return if name.is_file() { Some(name.to_path_buf()) } else { None };
+        }
+
+        let includer_dir = match kind {
+            HeaderKind::Quoted => includer.map(|file| file.parent().unwrap_or(Path::new(""))),
+            HeaderKind::Angled => None,
+        };
+
+        let dirs: Vec<&Path> = includer_dir.into_iter().chain(self.search_order(kind)).collect();
+
+        for dir in &dirs {
+            let path = match self.header_maps.get(*dir) {
+                Some(map) => match map.lookup(&name.to_string_lossy()) {
+                    Some(path) => path,
+                    None => continue,
+                },
+                None => dir.join(name),
+            };
+
+            probed.push(path.clone());
+
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+
+        if let Some(path) = self.resolve_framework(name, probed) {
+            return Some(path);
+        }
+
+        if self.ignore_case {
+            for dir in &dirs {
+                if let Some(path) = find_ignoring_case(dir, name) {
+                    let on_disk = path.strip_prefix(dir).unwrap_or(&path);
+                    warnings.push(CcError {
+                        loc,
+                        ..CcError::new(format!(
+                            "non-portable include of '{}': the file on disk is '{}'",
+                            name.to_string_lossy(),
+                            on_disk.to_string_lossy()))
+                    });
+                    return Some(path);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find a header named `Framework/Header.h` in the framework
+    /// directories, as `Framework.framework/Headers/Header.h`, or failing
+    /// that in the framework's `PrivateHeaders`.
+    ///
+    fn resolve_framework(&self, name: &Path, probed: &mut Vec<PathBuf>) -> Option<PathBuf> {
+        let mut components = name.components();
+        let framework = match components.next() {
+            Some(Component::Normal(framework)) => framework,
+            _ => return None,
+        };
+
+        let header = components.as_path();
+        if header.as_os_str().is_empty() {
+            return None;
+        }
+
+        let mut bundle = framework.to_os_string();
+        bundle.push(".framework");
+
+        for dir in &self.frameworks {
+            for headers in ["Headers", "PrivateHeaders"] {
+                let path = dir.join(&bundle).join(headers).join(header);
+                probed.push(path.clone());
+
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Convert the separators in a header name to the native ones, so that
+/// `#include "sys\types.h"` from Windows code works elsewhere, and forward
+/// slashes work under `\\?\` long path directories on Windows, which don't
+/// accept them. A UNC or long path header name is already absolute, and is
+/// searched for as is.
+///
+pub fn normalize_header_name(name: &str) -> String {
+    if cfg!(windows) {
+        //
+        // A long path is taken literally, so must be left alone.
+        //
+        if name.starts_with("\\\\?\\") {
+            name.to_string()
+        } else {
+            name.replace('/', "\\")
+        }
+    } else {
+        name.replace('\\', "/")
+    }
+}
+
+/// Find a file under `dir` whose path matches `name` ignoring case, one
+/// component at a time.
+///
+fn find_ignoring_case(dir: &Path, name: &Path) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+
+    for component in name.components() {
+        let component = match component {
+            Component::Normal(component) => component,
+            other => {
+                path.push(other);
+                continue;
+            },
+        };
+
+        let exact = path.join(component);
+        if exact.exists() {
+            path = exact;
+            continue;
+        }
+
+        let wanted = component.to_string_lossy().to_lowercase();
+        let entry = fs::read_dir(&path).ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == wanted)?;
+
+        path.push(entry.file_name());
+    }
+
+    if path.is_file() { Some(path) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Make a scratch directory tree for a test, with the given files.
+    ///
+    fn scratch(test: &str, files: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cpp-include-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn quoted_searches_includer_first() {
+        let root = scratch("quoted", &["src/a.h", "inc/a.h", "inc/b.h"]);
+
+        let paths = IncludePaths {
+            user: vec![root.join("inc")],
+            ..Default::default()
+        };
+
+        let includer = root.join("src/main.c");
+        let mut probed = Vec::new();
+        let mut warnings = Vec::new();
+
+        assert_eq!(paths.resolve("a.h", HeaderKind::Quoted, Some(&includer), None, &mut probed, &mut warnings), Some(root.join("src/a.h")));
+        assert_eq!(paths.resolve("a.h", HeaderKind::Angled, Some(&includer), None, &mut probed, &mut warnings), Some(root.join("inc/a.h")));
+
+        probed.clear();
+        assert_eq!(paths.resolve("b.h", HeaderKind::Quoted, Some(&includer), None, &mut probed, &mut warnings), Some(root.join("inc/b.h")));
+        assert_eq!(probed, vec![root.join("src/b.h"), root.join("inc/b.h")]);
+
+        probed.clear();
+        assert_eq!(paths.resolve("c.h", HeaderKind::Quoted, Some(&includer), None, &mut probed, &mut warnings), None);
+        assert_eq!(probed.len(), 2);
+        assert!(warnings.is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_ignoring_case() {
+        let root = scratch("case", &["inc/Sys/WinDefs.h"]);
+
+        let mut paths = IncludePaths {
+            user: vec![root.join("inc")],
+            ..Default::default()
+        };
+
+        let mut probed = Vec::new();
+        let mut warnings = Vec::new();
+
+        assert_eq!(paths.resolve("sys/windefs.h", HeaderKind::Angled, None, None, &mut probed, &mut warnings), None);
+        assert!(warnings.is_empty());
+
+        paths.ignore_case = true;
+        let loc = Point { file: 0, line: 3, col: 1 };
+        assert_eq!(paths.resolve("sys/windefs.h", HeaderKind::Angled, None, Some(loc), &mut probed, &mut warnings), Some(root.join("inc/Sys/WinDefs.h")));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].what.contains("Sys/WinDefs.h"));
+        assert_eq!(warnings[0].loc, Some(loc));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn searches_header_maps() -> Result<(), CcError> {
+        let root = scratch("hmap", &["src/foo/Bar.h", "inc/Bar.h"]);
+        let map = root.join("Project.hmap");
+        let target = root.join("src/foo/").to_string_lossy().to_string();
+        fs::write(&map, hmap::build_hmap(&[("Foo/Bar.h", &target, "Bar.h")]))?;
+
+        let mut paths = IncludePaths {
+            user: vec![map, root.join("inc")],
+            ..Default::default()
+        };
+        paths.load_header_maps()?;
+
+        let mut probed = Vec::new();
+        let mut warnings = Vec::new();
+
+        assert_eq!(paths.resolve("Foo/Bar.h", HeaderKind::Angled, None, None, &mut probed, &mut warnings), Some(root.join("src/foo/Bar.h")));
+        assert_eq!(paths.resolve("Bar.h", HeaderKind::Angled, None, None, &mut probed, &mut warnings), Some(root.join("inc/Bar.h")));
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn searches_frameworks() {
+        let root = scratch("framework", &[
+            "fw/Foo.framework/Headers/Foo.h",
+            "fw/Foo.framework/Headers/Sub/Bar.h",
+            "fw/Foo.framework/PrivateHeaders/Secret.h",
+        ]);
+
+        let paths = IncludePaths {
+            frameworks: vec![root.join("fw")],
+            ..Default::default()
+        };
+
+        let mut probed = Vec::new();
+        let mut warnings = Vec::new();
+
+        let mut resolve = |name| paths.resolve(name, HeaderKind::Angled, None, None, &mut probed, &mut warnings);
+
+        assert_eq!(resolve("Foo/Foo.h"), Some(root.join("fw/Foo.framework/Headers/Foo.h")));
+        assert_eq!(resolve("Foo/Sub/Bar.h"), Some(root.join("fw/Foo.framework/Headers/Sub/Bar.h")));
+        assert_eq!(resolve("Foo/Secret.h"), Some(root.join("fw/Foo.framework/PrivateHeaders/Secret.h")));
+        assert_eq!(resolve("Foo.h"), None);
+        assert_eq!(resolve("Bar/Foo.h"), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn normalizes_separators() {
+        if cfg!(windows) {
+            assert_eq!(normalize_header_name("sys/types.h"), "sys\\types.h");
+            assert_eq!(normalize_header_name("\\\\?\\C:\\a/b.h"), "\\\\?\\C:\\a/b.h");
+        } else {
+            assert_eq!(normalize_header_name("sys\\types.h"), "sys/types.h");
+            assert_eq!(normalize_header_name("\\\\server\\share\\a.h"), "//server/share/a.h");
+        }
+    }
+
+    #[test]
+    fn search_order() {
+        let paths = IncludePaths {
+            quote: vec![PathBuf::from("q")],
+            user: vec![PathBuf::from("u")],
+            system: vec![PathBuf::from("s")],
+            ..Default::default()
+        };
+
+        assert_eq!(paths.search_order(HeaderKind::Quoted), vec![Path::new("q"), Path::new("u"), Path::new("s")]);
+        assert_eq!(paths.search_order(HeaderKind::Angled), vec![Path::new("u"), Path::new("s")]);
+    }
+}
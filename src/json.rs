@@ -0,0 +1,456 @@
+//
+// JSON output of token streams, for debugging and for tools such as syntax
+// highlighters. Each token is written as one JSON object on its own line.
+//
+// There is also a small reader, for the few inputs which may be JSON, such
+// as defines files.
+//
+use crate::ccerror::CcError;
+use crate::doc::{DocComment, DocTarget};
+use crate::lexer::{MetaToken, PpToken};
+use crate::preprocess::{self, Condition};
+use crate::source::Source;
+
+/// The kind of a token as named in JSON output, and its spelling.
+///
+pub(crate) fn kind_and_spelling(token: &PpToken) -> (&'static str, String) {
+    let kind = match token {
+        PpToken::Identifier(_) => "identifier",
+        PpToken::Number(_) => "number",
+        PpToken::CharLiteral(..) => "char",
+        PpToken::StringLiteral(..) | PpToken::RawStringLiteral(..) => "string",
+        PpToken::Other(_) => "other",
+        PpToken::Eof => "eof",
+        _ => "punctuator",
+    };
+
+    (kind, token.spelling())
+}
+
+/// Format a token as a single-line JSON object with its kind, spelling,
+/// file name, line, column and flags.
+///
+pub fn token_json(token: &MetaToken, source: &Source) -> String {
+    let (kind, spelling) = kind_and_spelling(&token.token);
+
+    let file = source.get_filename(token.loc.file).unwrap_or_default();
+
+    format!(
+        "{{\"kind\":\"{}\",\"spelling\":{},\"file\":{},\"line\":{},\"col\":{},\"flags\":{{\"starts_line\":{}}}}}",
+        kind,
+        string(&spelling),
+        string(&file),
+        token.loc.line,
+        token.loc.col,
+        token.starts_line
+    )
+}
+
+/// Format a documentation comment as a single-line JSON object with its
+/// text, file name, line and column, and the kind, spelling, line and column
+/// of the token it documents, or null. A directive is given the kind
+/// "directive" and spelled with its `#`.
+///
+pub fn doc_comment_json(doc: &DocComment, source: &Source) -> String {
+    let target = match &doc.target {
+        Some(DocTarget::Token(token)) => {
+            let (kind, spelling) = kind_and_spelling(&token.token);
+            Some((kind, spelling, token.loc))
+        },
+        Some(DocTarget::Directive { name, loc }) => Some(("directive", format!("#{}", name), *loc)),
+        None => None,
+    };
+
+    let target = match target {
+        Some((kind, spelling, loc)) => format!(
+            "{{\"kind\":\"{}\",\"spelling\":{},\"line\":{},\"col\":{}}}",
+            kind,
+            string(&spelling),
+            loc.line,
+            loc.col
+        ),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"text\":{},\"file\":{},\"line\":{},\"col\":{},\"target\":{}}}",
+        string(&doc.text),
+        string(&source.get_filename(doc.loc.file).unwrap_or_default()),
+        doc.loc.line,
+        doc.loc.col,
+        target
+    )
+}
+
+/// Format an evaluated #if or #elif condition as a single-line JSON object
+/// with its directive, file name, line and column, the expression after
+/// macro expansion, and its value and type.
+///
+pub fn condition_json(condition: &Condition, source: &Source) -> String {
+    format!(
+        "{{\"directive\":\"{}\",\"file\":{},\"line\":{},\"col\":{},\"expanded\":{},\"value\":{},\"type\":\"{}\"}}",
+        condition.directive,
+        string(&source.get_filename(condition.loc.file).unwrap_or_default()),
+        condition.loc.line,
+        condition.loc.col,
+        string(&preprocess::spell(&condition.expanded)),
+        condition.value,
+        condition.value.type_name()
+    )
+}
+
+/// Format a string as a quoted JSON string.
+///
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// A JSON value as read by `parse`. Numbers are kept as they were spelled,
+/// and the members of an object in the order they were written.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+/// Parse a JSON document.
+///
+pub fn parse(text: &str) -> Result<Value, CcError> {
+    let mut reader = Reader { text: text.chars().collect(), pos: 0 };
+
+    let value = reader.value()?;
+    reader.skip_space();
+
+    match reader.peek() {
+        None => Ok(value),
+        Some(_) => Err(reader.error("unexpected text after JSON value")),
+    }
+}
+
+/// The state of `parse`: the text, and the index of the next character.
+///
+struct Reader {
+    text: Vec<char>,
+    pos: usize,
+}
+
+impl Reader {
+    fn peek(&self) -> Option<char> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().is_some_and(|ch| matches!(ch, ' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// An error at the current position, with the line it's on.
+    ///
+    fn error(&self, what: &str) -> CcError {
+        let line = self.text[..self.pos.min(self.text.len())].iter().filter(|&&ch| ch == '\n').count() + 1;
+        CcError::new(format!("line {}: {}", line, what))
+    }
+
+    fn expect(&mut self, ch: char) -> Result<(), CcError> {
+        self.skip_space();
+
+        if self.peek() != Some(ch) {
+            return Err(self.error(&format!("expected '{}'", ch)));
+        }
+
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, CcError> {
+        self.skip_space();
+
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(ch) if ch == '-' || ch.is_ascii_digit() => Ok(self.number()),
+            Some(ch) if ch.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|ch| ch.is_ascii_alphabetic()) {
+                    self.pos += 1;
+                }
+
+                match self.text[start..self.pos].iter().collect::<String>().as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => {
+                        self.pos = start;
+                        Err(self.error("expected a value"))
+                    },
+                }
+            },
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, CcError> {
+        let mut members = Vec::new();
+
+        self.expect('{')?;
+        self.skip_space();
+
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            self.skip_space();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a member name"));
+            }
+
+            let name = self.string()?;
+            self.expect(':')?;
+            members.push((name, self.value()?));
+
+            self.skip_space();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                },
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, CcError> {
+        let mut elements = Vec::new();
+
+        self.expect('[')?;
+        self.skip_space();
+
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(elements));
+        }
+
+        loop {
+            elements.push(self.value()?);
+
+            self.skip_space();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(elements));
+                },
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Value {
+        let start = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit() || matches!(ch, '-' | '+' | '.' | 'e' | 'E')) {
+            self.pos += 1;
+        }
+
+        Value::Number(self.text[start..self.pos].iter().collect())
+    }
+
+    /// A quoted string, with its escapes replaced. A surrogate pair written
+    /// as two `\u` escapes is one character.
+    ///
+    fn string(&mut self) -> Result<String, CcError> {
+        let mut out = String::new();
+
+        self.expect('"')?;
+
+        loop {
+            let Some(ch) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+
+            match ch {
+                '"' => return Ok(out),
+                '\\' => {
+                    let Some(escape) = self.peek() else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+
+                    out.push(match escape {
+                        '"' | '\\' | '/' => escape,
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err(self.error(&format!("invalid escape '\\{}'", escape))),
+                    });
+                },
+                ch if (ch as u32) < 0x20 => return Err(self.error("control character in string")),
+                ch => out.push(ch),
+            }
+        }
+    }
+
+    /// The character written by a `\u` escape, whose `\u` has been read.
+    ///
+    fn unicode_escape(&mut self) -> Result<char, CcError> {
+        let high = self.hex4()?;
+
+        let code = if (0xd800..0xdc00).contains(&high) && self.text.get(self.pos..self.pos + 2) == Some(&['\\', 'u']) {
+            self.pos += 2;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+
+        char::from_u32(code).ok_or_else(|| self.error("invalid character in \\u escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, CcError> {
+        let digits: String = self.text.get(self.pos..self.pos + 4).unwrap_or_default().iter().collect();
+
+        match u32::from_str_radix(&digits, 16) {
+            Ok(value) if digits.len() == 4 => {
+                self.pos += 4;
+                Ok(value)
+            },
+            _ => Err(self.error("expected four hex digits in \\u escape")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::next_token;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn parses_json() -> Result<(), CcError> {
+        assert_eq!(parse(r#" [1, -2.5e3, "a\"\u00e9\ud83d\ude00", true, null, {}] "#)?, Value::Array(vec![
+            Value::Number("1".to_string()),
+            Value::Number("-2.5e3".to_string()),
+            Value::String("a\"\u{e9}\u{1f600}".to_string()),
+            Value::Bool(true),
+            Value::Null,
+            Value::Object(Vec::new()),
+        ]));
+        assert_eq!(parse(r#"{"b": [], "a": false}"#)?, Value::Object(vec![
+            ("b".to_string(), Value::Array(Vec::new())),
+            ("a".to_string(), Value::Bool(false)),
+        ]));
+
+        assert_eq!(parse("[1,\n 2").unwrap_err().what, "line 2: expected ',' or ']'");
+        assert!(parse("[1,]").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("\"\\q\"").is_err());
+        assert!(parse("nil").is_err());
+        assert!(parse("1 2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn formats_tokens() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = "x <<=\n L\"a\\\"\"".chars().collect();
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let mut emit = Vec::new();
+
+        let token = next_token(&mut source, &mut emit)?;
+        assert_eq!(
+            token_json(&token, &source),
+            r#"{"kind":"identifier","spelling":"x","file":"abc","line":1,"col":1,"flags":{"starts_line":true}}"#
+        );
+
+        let token = next_token(&mut source, &mut emit)?;
+        assert_eq!(
+            token_json(&token, &source),
+            r#"{"kind":"punctuator","spelling":"<<=","file":"abc","line":1,"col":3,"flags":{"starts_line":false}}"#
+        );
+
+        let token = next_token(&mut source, &mut emit)?;
+        assert_eq!(
+            token_json(&token, &source),
+            r#"{"kind":"string","spelling":"L\"a\\\"\"","file":"abc","line":2,"col":2,"flags":{"starts_line":true}}"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn formats_doc_comments() {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), Vec::new());
+
+        let loc = crate::source::Point { file: 0, line: 2, col: 1 };
+        let doc = DocComment {
+            text: "/// x".to_string(),
+            loc: crate::source::Point { file: 0, line: 1, col: 3 },
+            target: Some(DocTarget::Directive { name: "define".to_string(), loc }),
+        };
+
+        assert_eq!(
+            doc_comment_json(&doc, &source),
+            r##"{"text":"/// x","file":"abc","line":1,"col":3,"target":{"kind":"directive","spelling":"#define","line":2,"col":1}}"##
+        );
+    }
+
+    #[test]
+    fn formats_conditions() -> Result<(), CcError> {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), Vec::new());
+
+        let condition = Condition {
+            directive: "elif".to_string(),
+            loc: crate::source::Point { file: 0, line: 4, col: 1 },
+            expanded: crate::lexer::lex("1 + 2u * (17)")?,
+            value: crate::expr::Value::Unsigned(35),
+        };
+
+        assert_eq!(
+            condition_json(&condition, &source),
+            r#"{"directive":"elif","file":"abc","line":4,"col":1,"expanded":"1 + 2u * (17)","value":35,"type":"uintmax_t"}"#
+        );
+
+        Ok(())
+    }
+}
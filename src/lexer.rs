@@ -1,20 +1,31 @@
 use crate::ccerror::CcError;
-use crate::source::{Source, SourceChar, Point};
+use crate::escape;
+use crate::source::{Source, SourceChar, Point, BOM};
 
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use lazy_static::lazy_static;
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PpToken {
     Identifier(String),
-    StringLiteral(String),
+    StringLiteral(Encoding, String),
+
+    /// A C++ raw string literal, with everything between the quotes:
+    /// the delimiter, the parenthesized body and the delimiter again.
+    RawStringLiteral(Encoding, String),
+
     Number(String),
-    CharLiteral(String),
+    CharLiteral(Encoding, String),
 
     // operators
 
     Hash,
+    HashHash,
     Add,
     Subtract,
     Star,
@@ -61,6 +72,16 @@ pub enum PpToken {
     Colon,
     Comma,
 
+    // C++ only
+    Scope,
+    ArrowStar,
+    DotStar,
+    Spaceship,
+
+    // A C++ alternative token such as `and`, kept as spelled. See `kind`
+    // for the operator it stands for.
+    Alternative(String),
+
     // Any character that's not part of another token.
     Other(char),
     
@@ -73,11 +94,117 @@ pub enum PpToken {
     Eof
 }
 
+impl PpToken {
+    /// The token as it would be written in source. Joined with whitespace,
+    /// the spellings of a token stream lex back to the same tokens. A
+    /// comment is spelled as the space it stands for, and end of file as
+    /// nothing.
+    /// 
+    pub fn spelling(&self) -> String {
+        match self {
+            PpToken::Identifier(id) | PpToken::Alternative(id) => id.clone(),
+            PpToken::Number(num) => num.clone(),
+            PpToken::CharLiteral(encoding, text) => format!("{}'{}'", encoding.prefix(), text),
+            PpToken::StringLiteral(encoding, text) => format!("{}\"{}\"", encoding.prefix(), text),
+            PpToken::RawStringLiteral(encoding, text) => format!("{}R\"{}\"", encoding.prefix(), text),
+            PpToken::Other(ch) => ch.to_string(),
+            PpToken::BlockComment | PpToken::LineComment => " ".to_string(),
+            PpToken::Eof => String::new(),
+            op => operator_spelling(op).unwrap_or_default(),
+        }
+    }
+
+    /// The token as far as the grammar is concerned: the operator an
+    /// alternative token stands for, such as `&&` for `and`, or the token
+    /// itself.
+    /// 
+    pub fn kind(&self) -> &PpToken {
+        match self {
+            PpToken::Alternative(spelling) => CXX_ALTERNATIVE_TOKENS.get(spelling.as_str()).unwrap_or(self),
+            token => token,
+        }
+    }
+
+    /// The C++ alternative token spelled `spelling`, if there is one.
+    /// 
+    pub fn alternative(spelling: &str) -> Option<PpToken> {
+        CXX_ALTERNATIVE_TOKENS.contains_key(spelling).then(|| PpToken::Alternative(spelling.to_string()))
+    }
+}
+
+impl Display for PpToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spelling())
+    }
+}
+
+/// The encoding prefix of a character or string literal.
+/// 
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Encoding {
+    /// No prefix.
+    Plain,
+
+    /// `u8`
+    Utf8,
+
+    /// `L`
+    Wide,
+
+    /// `u`
+    Utf16,
+
+    /// `U`
+    Utf32,
+}
+
+impl Encoding {
+    /// Map the identifier in front of a quote to an encoding, if it is a
+    /// valid encoding prefix.
+    /// 
+    pub fn from_prefix(prefix: &str) -> Option<Encoding> {
+        match prefix {
+            "u8" => Some(Encoding::Utf8),
+            "L" => Some(Encoding::Wide),
+            "u" => Some(Encoding::Utf16),
+            "U" => Some(Encoding::Utf32),
+            _ => None,
+        }
+    }
+
+    /// The prefix as it is spelled in source.
+    /// 
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Encoding::Plain => "",
+            Encoding::Utf8 => "u8",
+            Encoding::Wide => "L",
+            Encoding::Utf16 => "u",
+            Encoding::Utf32 => "U",
+        }
+    }
+
+    /// The largest value a single code unit of this encoding can hold.
+    /// 
+    pub fn max_unit(&self) -> u32 {
+        match self {
+            Encoding::Plain | Encoding::Utf8 => 0xff,
+            Encoding::Utf16 => 0xffff,
+            Encoding::Wide | Encoding::Utf32 => 0xffff_ffff,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MetaToken {
     pub token: PpToken,
     pub loc: Point,
     pub starts_line: bool,
+
+    /// Was the token preceded by whitespace or a comment?
+    pub space_before: bool,
 }
 
 impl MetaToken {
@@ -85,12 +212,13 @@ impl MetaToken {
         MetaToken {
             token,
             loc,
-            starts_line
+            starts_line,
+            space_before: false,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct OpNode {
     token: PpToken,
     next: Option<HashMap<char, OpNode>>,
@@ -115,7 +243,11 @@ lazy_static! {
         ('[', OpNode::new(PpToken::LeftBracket, None)),
         (']', OpNode::new(PpToken::RightBracket, None)),
         (';', OpNode::new(PpToken::Semicolon, None)),
-        ('#', OpNode::new(PpToken::Hash, None)),
+        ('#', OpNode::new(PpToken::Hash, 
+            Some(vec![
+                ('#', OpNode::new(PpToken::HashHash, None)),
+            ].into_iter().collect())
+        )),
         ('?', OpNode::new(PpToken::Question, None)),
         (':', OpNode::new(PpToken::Colon, None)),
         (',', OpNode::new(PpToken::Comma, None)),
@@ -129,7 +261,7 @@ lazy_static! {
         )),
         ('-', OpNode::new(PpToken::Subtract, 
             Some(vec![
-                ('+', OpNode::new(PpToken::Decrement, None)),
+                ('-', OpNode::new(PpToken::Decrement, None)),
                 ('=', OpNode::new(PpToken::SubtractAssign, None)),
                 ('>', OpNode::new(PpToken::Arrow, None)),
             ].into_iter().collect())   
@@ -200,6 +332,303 @@ lazy_static! {
         )),
 
     ].into_iter().collect();
+
+    /// The operators of C++, which adds a few to those of C.
+    /// 
+    static ref CXX_OPERATORS: HashMap<char, OpNode> = {
+        let mut ops = OPERATORS.clone();
+
+        add_operator(&mut ops, "::", PpToken::Scope);
+        add_operator(&mut ops, "->*", PpToken::ArrowStar);
+        add_operator(&mut ops, ".*", PpToken::DotStar);
+        add_operator(&mut ops, "<=>", PpToken::Spaceship);
+
+        ops
+    };
+
+    /// The alternative spellings of C++ operators, which are operators and
+    /// not identifiers even to the preprocessor.
+    /// 
+    static ref CXX_ALTERNATIVE_TOKENS: HashMap<&'static str, PpToken> = vec![
+        ("and", PpToken::LogicalAnd),
+        ("and_eq", PpToken::AndAssign),
+        ("bitand", PpToken::Ampersand),
+        ("bitor", PpToken::BitOr),
+        ("compl", PpToken::BitNot),
+        ("not", PpToken::LogicalNot),
+        ("not_eq", PpToken::NotEqual),
+        ("or", PpToken::LogicalOr),
+        ("or_eq", PpToken::OrAssign),
+        ("xor", PpToken::BitXor),
+        ("xor_eq", PpToken::XorAssign),
+    ].into_iter().collect();
+}
+
+/// Add an operator to an operator table. Every prefix of the spelling must
+/// already be in the table.
+/// 
+fn add_operator(ops: &mut HashMap<char, OpNode>, spelling: &str, token: PpToken) {
+    let mut chars: Vec<char> = spelling.chars().collect();
+    let last = chars.pop().unwrap();
+
+    let mut map = ops;
+    for ch in chars {
+        map = map.get_mut(&ch).unwrap().next.get_or_insert_with(HashMap::new);
+    }
+
+    map.insert(last, OpNode::new(token, None));
+}
+
+/// The language of the input, which changes a few lexical rules.
+/// 
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Default)]
+pub enum Language {
+    /// C source.
+    #[default]
+    C,
+
+    /// A C header. Lexed exactly like C.
+    CHeader,
+
+    /// Assembly source run through the preprocessor, as for `.S` files. 
+    /// Single quotes do not have to be balanced.
+    AssemblerWithCpp,
+
+    /// C++ source. Adds the `::`, `->*`, `.*` and `<=>` operators, and
+    /// alternative operator spellings such as `and`, and raw string literals.
+    Cxx,
+}
+
+impl Language {
+    /// Pick the language from a file's extension, as a compiler driver would.
+    /// 
+    pub fn from_path(path: &Path) -> Language {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("h") => Language::CHeader,
+            Some("S") | Some("sx") => Language::AssemblerWithCpp,
+            Some("C") | Some("cc") | Some("cp") | Some("cpp") | Some("cxx") | Some("c++") 
+                | Some("hh") | Some("hpp") | Some("hxx") => Language::Cxx,
+            _ => Language::C,
+        }
+    }
+
+    /// The name of the language as given to `-x`.
+    /// 
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::C => "c",
+            Language::CHeader => "c-header",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::Cxx => "c++",
+        }
+    }
+
+    /// The lexical rules this language adds to plain C, for describing the
+    /// configuration to the user.
+    /// 
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Language::C | Language::CHeader => &[],
+            Language::AssemblerWithCpp => &["unbalanced-quotes"],
+            Language::Cxx => &["cxx-operators", "alternative-tokens", "raw-strings"],
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = CcError;
+
+    /// Parse a language name as given to `-x`.
+    /// 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(Language::C),
+            "c-header" => Ok(Language::CHeader),
+            "assembler-with-cpp" => Ok(Language::AssemblerWithCpp),
+            "c++" => Ok(Language::Cxx),
+            _ => Err(CcError::new(format!("unknown language '{}'", s))),
+        }
+    }
+}
+
+/// What to do about a non-ASCII character outside of a literal or comment,
+/// which can't be part of any token.
+/// 
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Default)]
+pub enum NonAsciiPolicy {
+    /// Pass it through silently, as `PpToken::Other`.
+    Allow,
+
+    /// Pass it through, and have the caller warn about it; see
+    /// `stray_non_ascii`.
+    #[default]
+    Warn,
+
+    /// Make it a lexing error.
+    Error,
+}
+
+impl FromStr for NonAsciiPolicy {
+    type Err = CcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(NonAsciiPolicy::Allow),
+            "warn" => Ok(NonAsciiPolicy::Warn),
+            "error" => Ok(NonAsciiPolicy::Error),
+            _ => Err(CcError::new(format!("unknown non-ASCII policy '{}'", s))),
+        }
+    }
+}
+
+/// Options which control lexing.
+/// 
+#[derive(Debug, Default, Clone)]
+pub struct LexOptions {
+    /// The input language.
+    pub language: Language,
+
+    /// What to do about stray non-ASCII characters.
+    pub non_ascii: NonAsciiPolicy,
+
+    /// Whether GNU extensions are on, as with `-std=gnu11`.
+    pub gnu: bool,
+}
+
+impl LexOptions {
+    /// Whether raw strings are lexed: always in C++, and in C as a GNU
+    /// extension.
+    ///
+    pub fn raw_strings(&self) -> bool {
+        self.language == Language::Cxx || self.gnu
+    }
+}
+
+/// If `token` is a stray non-ASCII character, return a diagnostic for it
+/// naming the code point.
+/// 
+pub fn stray_non_ascii(token: &MetaToken) -> Option<CcError> {
+    match token.token {
+        PpToken::Other(ch) if !ch.is_ascii() => Some(
+            CcError::err_with_loc(
+                format!("stray non-ASCII character U+{:04X} '{}' in program", ch as u32, ch),
+                token.loc
+            )
+        ),
+        _ => None,
+    }
+}
+
+/// If `token` is a character constant or string literal, return a warning
+/// for each escape sequence in it which isn't known. The token keeps its
+/// spelling; see `escape::evaluate` for what such an escape means.
+/// 
+pub fn unknown_escapes(token: &MetaToken) -> Vec<CcError> {
+    let text = match &token.token {
+        PpToken::CharLiteral(_, text) | PpToken::StringLiteral(_, text) => text,
+        _ => return Vec::new(),
+    };
+
+    let mut warnings = Vec::new();
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            match chars.next() {
+                Some(esc) if !escape::is_known_escape(esc) => warnings.push(escape::unknown_escape(esc, token.loc)),
+                _ => {},
+            }
+        }
+    }
+
+    warnings
+}
+
+/// Return the source spelling of an operator or punctuator token, found by
+/// searching the operator tables. Returns None for any other kind of token.
+/// 
+pub fn operator_spelling(token: &PpToken) -> Option<String> {
+    fn search(map: &HashMap<char, OpNode>, token: &PpToken, prefix: &mut String) -> bool {
+        for (ch, op) in map {
+            prefix.push(*ch);
+
+            if op.token == *token {
+                return true;
+            }
+
+            if let Some(next) = &op.next {
+                if search(next, token, prefix) {
+                    return true;
+                }
+            }
+
+            prefix.pop();
+        }
+
+        false
+    }
+
+    let mut spelling = String::new();
+
+    if search(&CXX_OPERATORS, token, &mut spelling) {
+        Some(spelling)
+    } else {
+        None
+    }
+}
+
+/// Whether `text` is the spelling of an operator or punctuator, or the start
+/// of a longer one. C++ operators are included.
+/// 
+pub fn starts_operator(text: &str) -> bool {
+    let mut map = Some(&*CXX_OPERATORS);
+
+    for ch in text.chars() {
+        match map.and_then(|map| map.get(&ch)) {
+            Some(op) => map = op.next.as_ref(),
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// Lex a token from its spelling, as for the result of pasting two tokens
+/// together with `##`. Returns None unless the whole spelling is exactly
+/// one token.
+/// 
+pub fn lex_spelling(spelling: &str, options: &LexOptions) -> Option<PpToken> {
+    let mut emit = Vec::new();
+    let mut tokens = lex_str("<spelling>", spelling, options, &mut emit).ok()?;
+
+    match tokens.len() {
+        1 if emit.is_empty() => tokens.pop().map(|token| token.token),
+        _ => None,
+    }
+}
+
+/// Lex all of `text`, which isn't a file but is named `name` in locations,
+/// up to end of file. Any whitespace is appended to `emit`.
+/// 
+pub fn lex_str(name: &str, text: &str, options: &LexOptions, emit: &mut Vec<char>) -> Result<Vec<MetaToken>, CcError> {
+    let mut source = Source::new();
+    source.push_data(&PathBuf::from(name), text.chars().collect());
+
+    let mut tokens = Vec::new();
+
+    loop {
+        match next_token_with(&mut source, emit, options)? {
+            MetaToken { token: PpToken::Eof, .. } => return Ok(tokens),
+            token => tokens.push(token),
+        }
+    }
+}
+
+/// Lex all of `text` with the default options, for tests.
+/// 
+#[cfg(test)]
+pub(crate) fn lex(text: &str) -> Result<Vec<MetaToken>, CcError> {
+    lex_str("abc", text, &LexOptions::default(), &mut Vec::new())
 }
 
 /// Return the next lexical token in the input stream. 
@@ -207,33 +636,91 @@ lazy_static! {
 /// Any whitespace before the token will be appended to the `emit` vector.
 /// 
 pub fn next_token(source: &mut Source, emit: &mut Vec<char>) -> Result<MetaToken, CcError> {
+    next_token_with(source, emit, &LexOptions::default())
+}
+
+/// Return the next lexical token in the input stream, lexing according
+/// to `options`.
+/// 
+/// Any whitespace before the token will be appended to the `emit` vector.
+/// 
+pub fn next_token_with(source: &mut Source, emit: &mut Vec<char>, options: &LexOptions) -> Result<MetaToken, CcError> {
+    let before = emit.len();
+    let token = lex_token(source, emit, options)?;
+
+    Ok(MetaToken { space_before: emit.len() != before, ..token })
+}
+
+/// Lex the next token, for `next_token_with`.
+/// 
+fn lex_token(source: &mut Source, emit: &mut Vec<char>, options: &LexOptions) -> Result<MetaToken, CcError> {
     let mut newline = source.switched;
     
     //
     // Whitespace
     //
     loop {
-        let ch = match peek_spliced(source) {
-            Some(ch) => ch,
-            None => return Ok(MetaToken::new(PpToken::Eof, Point{ file: 0, line: 0, col: 0}, false)),
-        };
-
-        if ch.ch.is_ascii_whitespace() {
+        for ch in source.take_while(|ch| ch.is_ascii_whitespace()) {
             if ch.ch == '\n' {
                 newline = true;
             }
             emit.push(ch.ch);
-            source.next();
-            continue;
         }
 
+        let ch = match peek_spliced(source) {
+            Some(ch) => ch,
+            None => return Ok(MetaToken::new(PpToken::Eof, Point{ file: 0, line: 0, col: 0}, false)),
+        };
+
         let pt = ch.pt;
 
         //
         // Identifier?
         //
         if ch.ch.is_ascii_alphabetic() || ch.ch == '_' {
-            return Ok(MetaToken::new(identifier(source), pt, newline));
+            let token = identifier(source);
+
+            //
+            // An encoding prefix immediately followed by a quote is part
+            // of a character or string literal.
+            //
+            if let PpToken::Identifier(id) = &token {
+                if let Some(encoding) = Encoding::from_prefix(id) {
+                    match peek_spliced(source) {
+                        Some(quote) if quote.ch == '\'' || quote.ch == '"' => {
+                            next_spliced(source);
+                            let token = textlit(source, quote.ch == '\'', encoding, pt)?;
+                            return Ok(MetaToken::new(token, pt, newline));
+                        },
+                        _ => {},
+                    }
+                }
+            }
+
+            if let PpToken::Identifier(id) = &token {
+                if options.raw_strings() {
+                    let raw_encoding = id.strip_suffix('R').and_then(|prefix| match prefix {
+                        "" => Some(Encoding::Plain),
+                        prefix => Encoding::from_prefix(prefix),
+                    });
+
+                    if let Some(encoding) = raw_encoding {
+                        if peek_spliced(source).is_some_and(|quote| quote.ch == '"') {
+                            next_spliced(source);
+                            let token = raw_string(source, encoding, pt)?;
+                            return Ok(MetaToken::new(token, pt, newline));
+                        }
+                    }
+                }
+
+                if options.language == Language::Cxx {
+                    if let Some(token) = PpToken::alternative(id) {
+                        return Ok(MetaToken::new(token, pt, newline));
+                    }
+                }
+            }
+
+            return Ok(MetaToken::new(token, pt, newline));
         }
         
         //
@@ -258,7 +745,16 @@ pub fn next_token(source: &mut Source, emit: &mut Vec<char>) -> Result<MetaToken
         //
         if ch.ch == '\'' {
             next_spliced(source);
-            let token = textlit(source, true, ch.pt)?;
+
+            //
+            // Assemblers use single quotes for other things, so in assembly
+            // an unmatched quote is just a character.
+            //
+            if options.language == Language::AssemblerWithCpp && !quote_is_closed(source) {
+                return Ok(MetaToken::new(PpToken::Other('\''), pt, newline));
+            }
+
+            let token = textlit(source, true, Encoding::Plain, ch.pt)?;
             return Ok(MetaToken::new(token, pt, newline));
         }
 
@@ -267,14 +763,33 @@ pub fn next_token(source: &mut Source, emit: &mut Vec<char>) -> Result<MetaToken
         //
         if ch.ch == '\"' {
             next_spliced(source);
-            let token = textlit(source, false, ch.pt)?;
+            let token = textlit(source, false, Encoding::Plain, ch.pt)?;
             return Ok(MetaToken::new(token, pt, newline));
         }
 
+        //
+        // A byte order mark is only valid at the very start of a file, where
+        // Source has already removed it.
+        //
+        if ch.ch == BOM {
+            next_spliced(source);
+            return Err(
+                CcError::err_with_loc(
+                    "byte order mark in the middle of a file".to_string(),
+                    pt
+                )
+            );
+        }
+
         //
         // Operator?    
         //    
-        match lookup_op(source, &OPERATORS) {
+        let operators: &HashMap<char, OpNode> = match options.language {
+            Language::Cxx => &CXX_OPERATORS,
+            _ => &OPERATORS,
+        };
+
+        match lookup_op(source, operators) {
             Some(PpToken::BlockComment) => {
                 skip_block_comment(source, ch.pt)?;
                 emit.push(' ');
@@ -292,7 +807,15 @@ pub fn next_token(source: &mut Source, emit: &mut Vec<char>) -> Result<MetaToken
         match peek_spliced(source) {
             Some(ch) => {
                 next_spliced(source);
-                return Ok(MetaToken::new(PpToken::Other(ch.ch), pt, newline));
+                let token = MetaToken::new(PpToken::Other(ch.ch), pt, newline);
+
+                if options.non_ascii == NonAsciiPolicy::Error {
+                    if let Some(e) = stray_non_ascii(&token) {
+                        return Err(e);
+                    }
+                }
+
+                return Ok(token);
             },
             _ => break,
         }
@@ -305,24 +828,9 @@ pub fn next_token(source: &mut Source, emit: &mut Vec<char>) -> Result<MetaToken
 /// character in the source is a valid identifier start.
 /// 
 fn identifier(source: &mut Source) -> PpToken {
-    let mut idchars = Vec::new();
-
-    loop {
-        let ch = match peek_spliced(source) {
-            Some(ch) => ch.ch,
-            None => break,
-        };
-
-        if ch.is_ascii_alphanumeric() || ch == '_' {
-            idchars.push(ch);
-        } else {
-            break;
-        }
-
-        next_spliced(source);
-    }
-
-    let id: String = idchars.into_iter().collect();
+    let id = source.take_while(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        .map(|ch| ch.ch)
+        .collect();
 
     PpToken::Identifier(id)
 }
@@ -342,11 +850,8 @@ fn ppnumber(source: &mut Source) -> PpToken {
     //
     let mut numchars = Vec::new();
 
-    loop {
-        let ch = match peek_spliced(source) {
-            Some(ch) => ch.ch,
-            None => break,
-        };
+    while let Some(ch) = peek_spliced(source) {
+        let ch = ch.ch;
 
         //
         // 'e' or 'E' can be followed by a number
@@ -382,7 +887,7 @@ fn ppnumber(source: &mut Source) -> PpToken {
 
 /// Collect a character or a string literal.
 /// 
-fn textlit(source: &mut Source, is_char: bool, pt: Point) -> Result<PpToken, CcError> {
+fn textlit(source: &mut Source, is_char: bool, encoding: Encoding, pt: Point) -> Result<PpToken, CcError> {
     let mut chars = Vec::new();
 
     loop {
@@ -427,9 +932,89 @@ fn textlit(source: &mut Source, is_char: bool, pt: Point) -> Result<PpToken, CcE
     }
     
     if is_char {
-        Ok(PpToken::CharLiteral(chars.into_iter().collect()))
+        Ok(PpToken::CharLiteral(encoding, chars.into_iter().collect()))
     } else {
-        Ok(PpToken::StringLiteral(chars.into_iter().collect()))
+        Ok(PpToken::StringLiteral(encoding, chars.into_iter().collect()))
+    }
+}
+
+/// The longest delimiter a raw string may have.
+const MAX_RAW_DELIMITER: usize = 16;
+
+/// Collect a raw string literal, given that the prefix and opening quote
+/// have been consumed. Line splices are not processed inside a raw string,
+/// so the source is read a character at a time.
+/// 
+fn raw_string(source: &mut Source, encoding: Encoding, pt: Point) -> Result<PpToken, CcError> {
+    let unterminated = || CcError::err_with_loc("unterminated raw string".to_string(), pt);
+    let mut delimiter = String::new();
+
+    loop {
+        let ch = source.next().ok_or_else(unterminated)?.ch;
+
+        match ch {
+            '(' => break,
+            ' ' | ')' | '\\' | '\t' | '\x0b' | '\x0c' | '\n' | '"' => {
+                return Err(
+                    CcError::err_with_loc(
+                        format!("invalid character '{}' in raw string delimiter", ch.escape_default()),
+                        pt
+                    )
+                )
+            },
+            ch => delimiter.push(ch),
+        }
+
+        if delimiter.len() > MAX_RAW_DELIMITER {
+            return Err(
+                CcError::err_with_loc(
+                    format!("raw string delimiter longer than {} characters", MAX_RAW_DELIMITER),
+                    pt
+                )
+            )
+        }
+    }
+
+    let close: Vec<char> = format!("){}\"", delimiter).chars().collect();
+    let mut chars: Vec<char> = delimiter.chars().collect();
+    chars.push('(');
+
+    while !chars.ends_with(&close) {
+        chars.push(source.next().ok_or_else(unterminated)?.ch);
+    }
+
+    chars.pop();
+
+    Ok(PpToken::RawStringLiteral(encoding, chars.into_iter().collect()))
+}
+
+/// The body of a raw string literal, from the text of a RawStringLiteral
+/// token. Returns None if the text isn't a delimiter, a parenthesized body
+/// and the delimiter again, as can happen with tokens read from a file.
+/// 
+pub fn raw_string_body(text: &str) -> Option<&str> {
+    let open = text.find('(')?;
+    let delimiter = &text[..open];
+
+    text[open + 1..].strip_suffix(delimiter)?.strip_suffix(')')
+}
+
+/// Given that an opening single quote has been consumed, check if there 
+/// is a matching close quote before the end of the line.
+/// 
+fn quote_is_closed(source: &Source) -> bool {
+    let mut n = 0;
+
+    loop {
+        match peek_spliced_n(source, n) {
+            Some(ch) => match ch.ch {
+                '\n' => return false,
+                '\'' => return true,
+                '\\' => n += 2,
+                _ => n += 1,
+            },
+            None => return false,
+        }
     }
 }
 
@@ -473,8 +1058,9 @@ fn escape_sequence(source: &mut Source, accum: &mut Vec<char>, pt: Point) -> Res
                 },
                 '0'..='7' => {
                     accum.push(ch.ch);
+                    next_spliced(source);
 
-                    loop {
+                    for _ in 1..3 {
                         let ch = match peek_spliced(source) {
                             Some(ch) => ch.ch,
                             None => {
@@ -487,7 +1073,7 @@ fn escape_sequence(source: &mut Source, accum: &mut Vec<char>, pt: Point) -> Res
                             }
                         };
 
-                        if !ch.is_ascii_digit() && ch != '8' && ch != '9' {
+                        if !('0'..='7').contains(&ch) {
                             break;
                         }
 
@@ -633,8 +1219,8 @@ fn peek_spliced_n(source: &Source, mut n: u32) -> Option<SourceChar> {
                 if n == 0 {
                     break source.peek_n(i);
                 } else {
-                    n = n - 1;
-                    i = i + 1;
+                    n -= 1;
+                    i += 1;
                 }
             }
         }
@@ -688,6 +1274,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parses_decrement() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = vec!['-', '-', '-', '+'];
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let mut emit = Vec::new();
+
+        assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Decrement);
+        assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Subtract);
+        assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Add);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spells_operators() {
+        assert_eq!(operator_spelling(&PpToken::LeftShiftAssign), Some("<<=".to_string()));
+        assert_eq!(operator_spelling(&PpToken::Arrow), Some("->".to_string()));
+        assert_eq!(operator_spelling(&PpToken::Hash), Some("#".to_string()));
+        assert_eq!(operator_spelling(&PpToken::HashHash), Some("##".to_string()));
+        assert_eq!(operator_spelling(&PpToken::Identifier("a".to_string())), None);
+    }
+
     #[test]
     fn parses_spliced() -> Result<(), CcError> {
         let mut source = Source::new();
@@ -890,7 +1501,7 @@ mod tests {
 
         let mut emit = Vec::new();
         
-        let id = PpToken::CharLiteral("a".to_string());
+        let id = PpToken::CharLiteral(Encoding::Plain, "a".to_string());
         assert_eq!(next_token(&mut source, &mut emit)?.token, id);
         assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Comma);
 
@@ -921,7 +1532,7 @@ mod tests {
 
         let mut emit = Vec::new();
         
-        let id = PpToken::CharLiteral("\\'".to_string());
+        let id = PpToken::CharLiteral(Encoding::Plain, "\\'".to_string());
         assert_eq!(next_token(&mut source, &mut emit)?.token, id);
         assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Comma);
 
@@ -937,13 +1548,36 @@ mod tests {
 
         let mut emit = Vec::new();
         
-        let id = PpToken::StringLiteral("abc".to_string());
+        let id = PpToken::StringLiteral(Encoding::Plain, "abc".to_string());
         assert_eq!(next_token(&mut source, &mut emit)?.token, id);
         assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Comma);
 
         Ok(())
     }
 
+    #[test]
+    fn prefixed_literals() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text: Vec<char> = "L'a' u8\"b\" U\"c\" u \"d\"".chars().collect();
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let mut emit = Vec::new();
+        
+        let id = PpToken::CharLiteral(Encoding::Wide, "a".to_string());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, id);
+        let id = PpToken::StringLiteral(Encoding::Utf8, "b".to_string());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, id);
+        let id = PpToken::StringLiteral(Encoding::Utf32, "c".to_string());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, id);
+        let id = PpToken::Identifier("u".to_string());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, id);
+        let id = PpToken::StringLiteral(Encoding::Plain, "d".to_string());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, id);
+
+        Ok(())
+    }
+
     #[test]
     fn unterminated_str_const() -> Result<(), CcError> {
         let mut source = Source::new();
@@ -968,13 +1602,173 @@ mod tests {
 
         let mut emit = Vec::new();
         
-        let id = PpToken::StringLiteral("\\\"".to_string());
+        let id = PpToken::StringLiteral(Encoding::Plain, "\\\"".to_string());
         assert_eq!(next_token(&mut source, &mut emit)?.token, id);
         assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Comma);
 
         Ok(())
     }
 
+    #[test]
+    fn assembler_allows_unbalanced_quote() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = vec!['\'', 'a', '\n', '\'', 'b', '\''];
+
+        source.push_data(&PathBuf::from("abc.S"), text);
+
+        let mut emit = Vec::new();
+        let options = LexOptions { language: Language::AssemblerWithCpp, ..Default::default() };
+        
+        assert_eq!(next_token_with(&mut source, &mut emit, &options)?.token, PpToken::Other('\''));
+        let id = PpToken::Identifier("a".to_string());
+        assert_eq!(next_token_with(&mut source, &mut emit, &options)?.token, id);
+        let id = PpToken::CharLiteral(Encoding::Plain, "b".to_string());
+        assert_eq!(next_token_with(&mut source, &mut emit, &options)?.token, id);
+
+        Ok(())
+    }
+
+    /// Lex all of `text` with the given options.
+    /// 
+    fn lex_with(text: &str, options: &LexOptions) -> Result<Vec<PpToken>, CcError> {
+        let tokens = lex_str("abc", text, options, &mut Vec::new())?;
+        Ok(tokens.into_iter().map(|token| token.token).collect())
+    }
+
+    #[test]
+    fn diagnoses_non_ascii() -> Result<(), CcError> {
+        let text = "a \u{a3} \"\u{a3}\" /* \u{1f600} */";
+        let expected = vec![
+            PpToken::Identifier("a".to_string()),
+            PpToken::Other('\u{a3}'),
+            PpToken::StringLiteral(Encoding::Plain, "\u{a3}".to_string()),
+        ];
+
+        assert_eq!(lex_with(text, &LexOptions::default())?, expected);
+
+        let error = LexOptions { non_ascii: NonAsciiPolicy::Error, ..Default::default() };
+        let e = lex_with(text, &error).unwrap_err();
+        assert_eq!(e.what, "stray non-ASCII character U+00A3 '\u{a3}' in program");
+        assert_eq!(e.loc, Some(Point { file: 0, line: 1, col: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_escape_spelling() -> Result<(), CcError> {
+        assert_eq!(lex_with("\"\\012\\08\\q\"", &LexOptions::default())?, vec![
+            PpToken::StringLiteral(Encoding::Plain, "\\012\\08\\q".to_string()),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn warns_about_unknown_escapes() -> Result<(), CcError> {
+        let cxx = LexOptions { language: Language::Cxx, ..Default::default() };
+
+        let tokens = lex_str("abc", "s = \"\\q\\\\w\\e\"; '\\%' R\"(\\q)\"", &cxx, &mut Vec::new())?;
+
+        let warnings: Vec<_> = tokens.iter().flat_map(unknown_escapes).map(|w| (w.what, w.loc.unwrap().col)).collect();
+        assert_eq!(warnings, vec![
+            ("unknown escape sequence: '\\q'".to_string(), 5),
+            ("unknown escape sequence: '\\%'".to_string(), 16),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lexes_raw_strings() -> Result<(), CcError> {
+        let cxx = LexOptions { language: Language::Cxx, ..Default::default() };
+
+        let text = "R\"x(a\\\n\")\"b)x\" u8R\"(c)\" R";
+        assert_eq!(lex_with(text, &cxx)?, vec![
+            PpToken::RawStringLiteral(Encoding::Plain, "x(a\\\n\")\"b)x".to_string()),
+            PpToken::RawStringLiteral(Encoding::Utf8, "(c)".to_string()),
+            PpToken::Identifier("R".to_string()),
+        ]);
+        assert_eq!(raw_string_body("x(a)\")x"), Some("a)\""));
+        assert_eq!(raw_string_body("()"), Some(""));
+        assert_eq!(raw_string_body(""), None);
+        assert_eq!(raw_string_body("abc"), None);
+        assert_eq!(raw_string_body("x(a)y"), None);
+        assert_eq!(raw_string_body("x("), None);
+
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), "R\"(a\nb)\" z".chars().collect());
+        let mut emit = Vec::new();
+        next_token_with(&mut source, &mut emit, &cxx)?;
+        assert_eq!(next_token_with(&mut source, &mut emit, &cxx)?.loc, Point { file: 0, line: 2, col: 5 });
+
+        assert!(lex_with("R\"abc", &cxx).is_err());
+        assert!(lex_with("R\"a b(x)a b\"", &cxx).is_err());
+        assert!(lex_with("R\"(x)\"", &LexOptions::default())?.len() == 2);
+
+        let gnu = LexOptions { gnu: true, ..Default::default() };
+        assert_eq!(lex_with("R\"(x)\" and", &gnu)?, vec![
+            PpToken::RawStringLiteral(Encoding::Plain, "(x)".to_string()),
+            PpToken::Identifier("and".to_string()),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lexes_cxx_operators() -> Result<(), CcError> {
+        let cxx = LexOptions { language: Language::Cxx, ..Default::default() };
+        let c = LexOptions::default();
+
+        assert_eq!(lex_with("a::b ->* .* <=> x and not_eq", &cxx)?, vec![
+            PpToken::Identifier("a".to_string()),
+            PpToken::Scope,
+            PpToken::Identifier("b".to_string()),
+            PpToken::ArrowStar,
+            PpToken::DotStar,
+            PpToken::Spaceship,
+            PpToken::Identifier("x".to_string()),
+            PpToken::Alternative("and".to_string()),
+            PpToken::Alternative("not_eq".to_string()),
+        ]);
+        assert_eq!(PpToken::Alternative("and".to_string()).kind(), &PpToken::LogicalAnd);
+        assert_eq!(PpToken::Alternative("not_eq".to_string()).spelling(), "not_eq");
+        assert_eq!(PpToken::alternative("bitand"), Some(PpToken::Alternative("bitand".to_string())));
+        assert_eq!(PpToken::alternative("x"), None);
+
+        assert_eq!(lex_with("a::b <=> and", &c)?, vec![
+            PpToken::Identifier("a".to_string()),
+            PpToken::Colon,
+            PpToken::Colon,
+            PpToken::Identifier("b".to_string()),
+            PpToken::LessEqual,
+            PpToken::Greater,
+            PpToken::Identifier("and".to_string()),
+        ]);
+
+        assert_eq!(operator_spelling(&PpToken::ArrowStar), Some("->*".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn language_from_extension() {
+        assert_eq!(Language::from_path(Path::new("start.S")), Language::AssemblerWithCpp);
+        assert_eq!(Language::from_path(Path::new("main.c")), Language::C);
+        assert_eq!(Language::from_path(Path::new("stdio.h")), Language::CHeader);
+        assert_eq!(Language::from_path(Path::new("main.cpp")), Language::Cxx);
+    }
+
+    #[test]
+    fn language_from_name() {
+        assert_eq!("assembler-with-cpp".parse::<Language>(), Ok(Language::AssemblerWithCpp));
+        assert_eq!("c++".parse::<Language>(), Ok(Language::Cxx));
+        assert!("fortran".parse::<Language>().is_err());
+
+        for language in [Language::C, Language::CHeader, Language::AssemblerWithCpp, Language::Cxx] {
+            assert_eq!(language.name().parse::<Language>(), Ok(language));
+        }
+    }
+
     #[test]
     fn random_character_are_other() -> Result<(), CcError> {
         let mut source = Source::new();
@@ -991,6 +1785,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn mid_file_bom_is_an_error() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = vec![BOM, '$', BOM, ','];
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let mut emit = Vec::new();
+        
+        assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Other('$'));
+        assert!(next_token(&mut source, &mut emit).is_err());
+        assert_eq!(next_token(&mut source, &mut emit)?.token, PpToken::Comma);
+
+        Ok(())
+    }
+
     #[test]
     fn token_has_location() -> Result<(), CcError> {        
         let mut source = Source::new();
@@ -1057,4 +1867,70 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn splices_dont_affect_line_start_flag() -> Result<(), CcError> {        
+        let mut source = Source::new();
+        let text = vec!['a', '\\', '\n', ' ', 'b', '\\', '\n', 'c'];
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let mut emit = Vec::new();
+
+        next_token(&mut source, &mut emit)?;
+
+        let token = next_token(&mut source, &mut emit)?;
+        assert_eq!(token.token, PpToken::Identifier("bc".to_string()));
+        assert_eq!(token.loc, Point{ file: 0, line: 2, col: 2});
+        assert!(!token.starts_line);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spells_tokens() -> Result<(), CcError> {
+        let options = LexOptions { language: Language::Cxx, ..Default::default() };
+        let text = "a::b->*c <=> 1.5e+3 u8'x' L\"s\\\"\" uR\"d(\")\")d\" # @ and";
+        let tokens = lex_with(text, &options)?;
+
+        let spelled: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+        assert_eq!(lex_with(&spelled.join(" "), &options)?, tokens);
+
+        assert_eq!(PpToken::Spaceship.spelling(), "<=>");
+        assert_eq!(PpToken::StringLiteral(Encoding::Wide, "s".to_string()).spelling(), "L\"s\"");
+        assert_eq!(PpToken::Eof.spelling(), "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lexes_spellings() {
+        let c = LexOptions::default();
+        let cxx = LexOptions { language: Language::Cxx, ..Default::default() };
+
+        assert_eq!(lex_spelling("ab", &c), Some(PpToken::Identifier("ab".to_string())));
+        assert_eq!(lex_spelling("+=", &c), Some(PpToken::AddAssign));
+        assert_eq!(lex_spelling("##", &c), Some(PpToken::HashHash));
+        assert_eq!(lex_spelling("::", &cxx), Some(PpToken::Scope));
+        assert_eq!(lex_spelling("::", &c), None);
+        assert_eq!(lex_spelling("a+", &c), None);
+        assert_eq!(lex_spelling("//", &c), None);
+        assert_eq!(lex_spelling("\"x", &c), None);
+        assert_eq!(lex_spelling("", &c), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_through_serde() -> Result<(), CcError> {
+        let tokens = lex_with("x L\"a\" += 1", &LexOptions::default())?;
+
+        for token in tokens {
+            let token = MetaToken::new(token, Point { file: 0, line: 1, col: 1 }, true);
+            let text = toml::to_string(&token).map_err(|e| CcError::new(e.to_string()))?;
+
+            assert_eq!(toml::from_str::<MetaToken>(&text).map_err(|e| CcError::new(e.to_string()))?, token);
+        }
+
+        Ok(())
+    }
 }
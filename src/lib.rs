@@ -0,0 +1,23 @@
+pub mod cancel;
+pub mod ccerror;
+pub mod charset;
+pub mod concat;
+pub mod config;
+pub mod ctoken;
+pub mod defines;
+pub mod diagnostic;
+pub mod doc;
+pub mod escape;
+pub mod expr;
+pub mod fingerprint;
+pub mod hmap;
+pub mod include;
+pub mod json;
+pub mod lexer;
+pub mod macros;
+pub mod number;
+pub mod preprocess;
+pub mod source;
+pub mod standard;
+pub mod strip;
+pub mod tokenfile;
@@ -0,0 +1,482 @@
+//
+// The macro table: macros defined with #define or on the command line, and
+// their expansion.
+//
+use crate::cancel::CancelToken;
+use crate::ccerror::CcError;
+use crate::lexer::{self, LexOptions, MetaToken, PpToken};
+use crate::source::Point;
+
+use std::collections::HashMap;
+
+/// A macro definition.
+///
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Macro {
+    pub name: String,
+
+    /// The tokens the macro's name is replaced with.
+    pub replacement: Vec<MetaToken>,
+
+    /// Where the macro was defined, or None if it was defined on the
+    /// command line.
+    pub loc: Option<Point>,
+}
+
+impl Macro {
+    /// Whether two definitions of a macro are the same, and so a
+    /// redefinition is allowed: the replacement lists must have the same
+    /// tokens, with whitespace between the same ones.
+    ///
+    pub fn same_definition(&self, other: &Macro) -> bool {
+        self.replacement.len() == other.replacement.len()
+            && self.replacement.iter().zip(&other.replacement).enumerate().all(|(i, (a, b))| {
+                a.token == b.token && (i == 0 || a.space_before == b.space_before)
+            })
+    }
+}
+
+/// A macro defined or undefined from outside the source: by a -D option,
+/// the configuration file, or a defines file.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub enum MacroOption {
+    /// `NAME` or `NAME=VALUE`, as for -D.
+    Define(String),
+
+    /// A macro name, as for -U.
+    Undef(String),
+}
+
+/// The macros currently defined.
+///
+#[derive(Debug, Default)]
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+
+    /// See `set_cancel`.
+    cancel: Option<CancelToken>,
+}
+
+impl MacroTable {
+    pub fn new() -> Self {
+        MacroTable::default()
+    }
+
+    /// Once `cancel` is cancelled, stop expanding with a cancelled error
+    /// naming the macro being expanded. An expansion can grow exponentially,
+    /// so this is checked before each macro in it.
+    ///
+    pub fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Look up a macro by name.
+    ///
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    /// Is a macro with this name defined?
+    ///
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Define a macro. Redefining a macro differently replaces it, with a
+    /// warning.
+    ///
+    pub fn define(&mut self, definition: Macro, warnings: &mut Vec<CcError>) {
+        if let Some(prev) = self.macros.get(&definition.name) {
+            if !prev.same_definition(&definition) {
+                let what = format!("'{}' redefined", definition.name);
+                warnings.push(match definition.loc {
+                    Some(loc) => CcError::err_with_loc(what, loc),
+                    None => CcError::new(what),
+                });
+            }
+        }
+
+        self.macros.insert(definition.name.clone(), definition);
+    }
+
+    /// Predefine a macro whose replacement is a single number, such as
+    /// `__cplusplus`.
+    ///
+    pub fn predefine(&mut self, name: &str, value: &str) {
+        let number = MetaToken {
+            token: PpToken::Number(value.to_string()),
+            loc: Point { file: 0, line: 0, col: 0 },
+            starts_line: false,
+            space_before: false,
+        };
+
+        self.macros.insert(name.to_string(), Macro { name: name.to_string(), replacement: vec![number], loc: None });
+    }
+
+    /// Remove a macro's definition. It is not an error if it isn't defined.
+    ///
+    pub fn undef(&mut self, name: &str) {
+        self.macros.remove(name);
+    }
+
+    /// Handle a #define directive, given the tokens after `define`. `loc` is
+    /// the location of the directive.
+    ///
+    pub fn define_directive(&mut self, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        let (name, rest) = macro_name(tokens, "define", loc)?;
+
+        if let Some(paren) = rest.first() {
+            if paren.token == PpToken::LeftParen && !paren.space_before {
+                return Err(CcError::err_with_loc("function-like macros are not supported yet".to_string(), paren.loc));
+            }
+
+            if !paren.space_before {
+                warnings.push(CcError::err_with_loc("missing whitespace after the macro name".to_string(), paren.loc));
+            }
+        }
+
+        if let Some(hash_hash) = misplaced_paste(rest) {
+            return Err(CcError::err_with_loc(PASTE_AT_END.to_string(), hash_hash.loc));
+        }
+
+        self.define(Macro { name, replacement: rest.to_vec(), loc: Some(tokens[0].loc) }, warnings);
+        Ok(())
+    }
+
+    /// Handle an #undef directive, given the tokens after `undef`.
+    ///
+    pub fn undef_directive(&mut self, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        let (name, rest) = macro_name(tokens, "undef", loc)?;
+
+        if let Some(extra) = rest.first() {
+            warnings.push(CcError::err_with_loc("extra tokens at end of #undef directive".to_string(), extra.loc));
+        }
+
+        self.undef(&name);
+        Ok(())
+    }
+
+    /// Define a macro from a -D option, `NAME` or `NAME=VALUE`. Without a
+    /// value, the macro is defined as 1.
+    ///
+    pub fn define_option(&mut self, option: &str, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        let (name, value) = option.split_once('=').unwrap_or((option, "1"));
+        let text = format!("{} {}", name, value);
+        let mut tokens = lexer::lex_str("<command line>", &text, &LexOptions::default(), &mut Vec::new())?;
+
+        let err = || CcError::new(format!("invalid macro definition '{}'", option));
+
+        match tokens.first() {
+            Some(MetaToken { token: PpToken::Identifier(id), .. }) if id == name && id != "defined" => {},
+            _ => return Err(err()),
+        }
+
+        let replacement = tokens.split_off(1);
+        if misplaced_paste(&replacement).is_some() {
+            return Err(CcError::from_static(PASTE_AT_END));
+        }
+
+        self.define(Macro { name: name.to_string(), replacement, loc: None }, warnings);
+        Ok(())
+    }
+
+    /// Apply a definition or undefinition from outside the source.
+    ///
+    pub fn apply_option(&mut self, option: &MacroOption, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        match option {
+            MacroOption::Define(definition) => self.define_option(definition, warnings),
+            MacroOption::Undef(name) => {
+                let is_identifier = name.starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_')
+                    && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+
+                if !is_identifier {
+                    return Err(CcError::new(format!("invalid macro name '{}'", name)));
+                }
+
+                self.undef(name);
+                Ok(())
+            },
+        }
+    }
+
+    /// Expand `token`, appending the result to `out`. A token which isn't a
+    /// macro name is appended as it is. The `##` operators in the
+    /// replacement list are applied, then the expansion is rescanned for
+    /// more macro names, except that a macro is never expanded inside its
+    /// own expansion. `options` are used to lex the results of pasting.
+    ///
+    /// The tokens of the expansion take the location of `token`, and the
+    /// first one takes its spacing.
+    ///
+    pub fn expand(&self, token: MetaToken, options: &LexOptions, out: &mut Vec<MetaToken>) -> Result<(), CcError> {
+        self.expand_disabled(token, options, &mut Vec::new(), out)
+    }
+
+    fn expand_disabled(&self, token: MetaToken, options: &LexOptions, disabled: &mut Vec<String>, out: &mut Vec<MetaToken>) -> Result<(), CcError> {
+        let definition = match &token.token {
+            PpToken::Identifier(name) if !disabled.contains(name) => self.macros.get(name),
+            _ => None,
+        };
+
+        let Some(definition) = definition else {
+            out.push(token);
+            return Ok(());
+        };
+
+        if let Some(reason) = self.cancel.as_ref().and_then(|cancel| cancel.reason()) {
+            return Err(CcError::cancelled(format!("{} while expanding macro '{}'", reason, definition.name), token.loc));
+        }
+
+        let replacement = paste(&definition.replacement, token.loc, options)?;
+
+        disabled.push(definition.name.clone());
+
+        for (i, replacement) in replacement.into_iter().enumerate() {
+            let replacement = MetaToken {
+                token: replacement.token,
+                loc: token.loc,
+                starts_line: i == 0 && token.starts_line,
+                space_before: if i == 0 { token.space_before } else { replacement.space_before },
+            };
+
+            self.expand_disabled(replacement, options, disabled, out)?;
+        }
+
+        disabled.pop();
+        Ok(())
+    }
+}
+
+const PASTE_AT_END: &str = "'##' cannot appear at either end of a macro expansion";
+
+/// A `##` at the start or end of a replacement list, which would have
+/// nothing to paste on one side.
+///
+fn misplaced_paste(replacement: &[MetaToken]) -> Option<&MetaToken> {
+    [replacement.first(), replacement.last()].into_iter()
+        .flatten()
+        .find(|token| token.token == PpToken::HashHash)
+}
+
+/// Apply the `##` operators in a replacement list, pasting the tokens on
+/// either side of each into one. The pasted spelling must lex as a single
+/// token; if it doesn't, the error is reported at `loc`, the macro
+/// invocation.
+///
+fn paste(replacement: &[MetaToken], loc: Point, options: &LexOptions) -> Result<Vec<MetaToken>, CcError> {
+    let mut out: Vec<MetaToken> = Vec::with_capacity(replacement.len());
+    let mut tokens = replacement.iter();
+
+    while let Some(token) = tokens.next() {
+        if token.token != PpToken::HashHash {
+            out.push(token.clone());
+            continue;
+        }
+
+        let (Some(lhs), Some(rhs)) = (out.pop(), tokens.next()) else {
+            return Err(CcError::err_with_loc(PASTE_AT_END.to_string(), loc));
+        };
+
+        let pasted = lexer::lex_spelling(&format!("{}{}", lhs.token, rhs.token), options)
+            .ok_or_else(|| CcError::err_with_loc(
+                format!("pasting \"{}\" and \"{}\" does not give a valid preprocessing token", lhs.token, rhs.token),
+                loc
+            ))?;
+
+        out.push(MetaToken { token: pasted, ..lhs });
+    }
+
+    Ok(out)
+}
+
+/// Get the macro name at the start of a #define or #undef directive, and
+/// the tokens after it.
+///
+fn macro_name<'a>(tokens: &'a [MetaToken], directive: &str, loc: Point) -> Result<(String, &'a [MetaToken]), CcError> {
+    match tokens.split_first() {
+        Some((MetaToken { token: PpToken::Identifier(name), loc, .. }, _)) if name == "defined" => {
+            Err(CcError::err_with_loc("\"defined\" cannot be used as a macro name".to_string(), *loc))
+        },
+        Some((MetaToken { token: PpToken::Identifier(name), .. }, rest)) => Ok((name.clone(), rest)),
+        Some((token, _)) => Err(CcError::err_with_loc("macro names must be identifiers".to_string(), token.loc)),
+        None => Err(CcError::err_with_loc(format!("no macro name given in #{} directive", directive), loc)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    fn spell(tokens: &[MetaToken]) -> String {
+        tokens.iter().map(|token| token.token.spelling()).collect::<Vec<_>>().join(" ")
+    }
+
+    fn expand(table: &MacroTable, text: &str) -> Result<String, CcError> {
+        let mut out = Vec::new();
+        for token in lex(text)? {
+            table.expand(token, &LexOptions::default(), &mut out)?;
+        }
+        Ok(spell(&out))
+    }
+
+    const LOC: Point = Point { file: 0, line: 1, col: 1 };
+
+    #[test]
+    fn can_be_shared() {
+        fn assert_sync<T: Send + Sync>() {}
+
+        assert_sync::<MacroTable>();
+    }
+
+    #[test]
+    fn defines_and_undefines() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_directive(&lex("ONE 1")?, LOC, &mut warnings)?;
+        table.define_directive(&lex("EMPTY")?, LOC, &mut warnings)?;
+
+        assert_eq!(spell(&table.get("ONE").unwrap().replacement), "1");
+        assert!(table.get("EMPTY").unwrap().replacement.is_empty());
+
+        table.undef_directive(&lex("ONE")?, LOC, &mut warnings)?;
+        table.undef_directive(&lex("MISSING")?, LOC, &mut warnings)?;
+
+        assert!(!table.is_defined("ONE"));
+        assert!(table.is_defined("EMPTY"));
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagnoses_bad_directives() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        assert_eq!(table.define_directive(&[], LOC, &mut warnings).unwrap_err().what, "no macro name given in #define directive");
+        assert_eq!(table.define_directive(&lex("1 2")?, LOC, &mut warnings).unwrap_err().what, "macro names must be identifiers");
+        assert_eq!(table.define_directive(&lex("defined")?, LOC, &mut warnings).unwrap_err().what, "\"defined\" cannot be used as a macro name");
+        assert_eq!(table.define_directive(&lex("F(x) x")?, LOC, &mut warnings).unwrap_err().what, "function-like macros are not supported yet");
+
+        table.define_directive(&lex("A+")?, LOC, &mut warnings)?;
+        table.undef_directive(&lex("A B")?, LOC, &mut warnings)?;
+
+        let warnings: Vec<String> = warnings.into_iter().map(|w| w.what).collect();
+        assert_eq!(warnings, vec!["missing whitespace after the macro name", "extra tokens at end of #undef directive"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn warns_about_redefinition() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_directive(&lex("A 1 + 2")?, LOC, &mut warnings)?;
+        table.define_directive(&lex("A  1 /**/ +  2")?, LOC, &mut warnings)?;
+        assert!(warnings.is_empty());
+
+        table.define_directive(&lex("A 1+2")?, LOC, &mut warnings)?;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].what, "'A' redefined");
+
+        Ok(())
+    }
+
+    #[test]
+    fn defines_from_options() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_option("DEBUG", &mut warnings)?;
+        table.define_option("LEVEL=2 + x", &mut warnings)?;
+        table.define_option("EMPTY=", &mut warnings)?;
+
+        assert_eq!(spell(&table.get("DEBUG").unwrap().replacement), "1");
+        assert_eq!(spell(&table.get("LEVEL").unwrap().replacement), "2 + x");
+        assert!(table.get("EMPTY").unwrap().replacement.is_empty());
+
+        assert!(table.define_option("1X", &mut warnings).is_err());
+        assert!(table.define_option("A-B", &mut warnings).is_err());
+
+        table.apply_option(&MacroOption::Undef("LEVEL".to_string()), &mut warnings)?;
+        table.apply_option(&MacroOption::Define("X=3".to_string()), &mut warnings)?;
+        assert!(!table.is_defined("LEVEL"));
+        assert!(table.is_defined("X"));
+        assert!(table.apply_option(&MacroOption::Undef("A B".to_string()), &mut warnings).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn expands_object_like_macros() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_option("A=B + 1", &mut warnings)?;
+        table.define_option("B=C", &mut warnings)?;
+        table.define_option("C=A", &mut warnings)?;
+        table.define_option("SELF=SELF", &mut warnings)?;
+        table.define_option("EMPTY=", &mut warnings)?;
+
+        assert_eq!(expand(&table, "x = A;")?, "x = A + 1 ;");
+        assert_eq!(expand(&table, "SELF EMPTY B")?, "SELF B + 1");
+
+        let mut out = Vec::new();
+        table.expand(MetaToken { space_before: true, ..lex("A")?.remove(0) }, &LexOptions::default(), &mut out)?;
+        assert!(out.iter().all(|token| token.loc == LOC));
+        assert!(out[0].space_before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pastes_tokens() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_directive(&lex("CAT x ## y")?, LOC, &mut warnings)?;
+        table.define_directive(&lex("OP + ## = ## ")?, LOC, &mut warnings).unwrap_err();
+        table.define_directive(&lex("SHL < ## <= 1")?, LOC, &mut warnings)?;
+        table.define_directive(&lex("NAME C ## A ## T")?, LOC, &mut warnings)?;
+        table.define_directive(&lex("BAD x ## -")?, LOC, &mut warnings)?;
+        table.define_option("NUM=1 ##", &mut warnings).unwrap_err();
+        table.define_option("NUM=1##e##-##5", &mut warnings)?;
+
+        assert_eq!(expand(&table, "CAT SHL NUM")?, "xy <<= 1 1e-5");
+        assert_eq!(expand(&table, "NAME")?, "xy");
+        assert_eq!(
+            expand(&table, "BAD").unwrap_err().what,
+            "pasting \"x\" and \"-\" does not give a valid preprocessing token"
+        );
+
+        let e = table.define_directive(&lex("P ## x")?, LOC, &mut warnings).unwrap_err();
+        assert_eq!(e.what, "'##' cannot appear at either end of a macro expansion");
+
+        Ok(())
+    }
+
+    #[test]
+    fn stops_expanding_when_cancelled() -> Result<(), CcError> {
+        let mut table = MacroTable::new();
+        let mut warnings = Vec::new();
+
+        table.define_option("A=B B", &mut warnings)?;
+        table.define_option("B=1", &mut warnings)?;
+
+        let cancel = CancelToken::new();
+        table.set_cancel(cancel.clone());
+        assert_eq!(expand(&table, "A")?, "1 1");
+
+        cancel.cancel_because("out of time");
+        let e = expand(&table, "x A").unwrap_err();
+        assert!(e.cancelled);
+        assert_eq!(e.what, "out of time while expanding macro 'A'");
+
+        Ok(())
+    }
+}
@@ -1,66 +1,1174 @@
-mod ccerror;
-mod lexer;
-mod source;
-
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{CommandFactory, Parser, ValueEnum};
+
+use cpp::cancel::CancelToken;
+use cpp::ccerror::CcError;
+use cpp::charset::Charset;
+use cpp::config::{self, Config};
+use cpp::defines;
+use cpp::diagnostic::{Diagnostic, Severity};
+use cpp::doc;
+use cpp::fingerprint::{self, Fnv64};
+use cpp::lexer::{self, Language, LexOptions, MetaToken, NonAsciiPolicy, PpToken};
+use cpp::macros::MacroOption;
+use cpp::include::{HeaderKind, IncludePaths};
+use cpp::json;
+use cpp::number;
+use cpp::preprocess::{Preprocessor, TextWriter};
+use cpp::source::{Point, Source, BOM};
+use cpp::standard::{Dialect, Standard};
+use cpp::strip;
+
+/// What to write as output.
+/// 
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    /// One JSON object per token.
+    TokensJson,
+
+    /// One JSON object per documentation comment.
+    DocComments,
+
+    /// A hash of the token stream, which only changes when the tokens do.
+    Hash,
+}
+
+/// The exit status of the program.
+/// 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    /// Everything was processed without errors.
+    Success = 0,
+
+    /// There were errors in the source.
+    Errors = 1,
 
-use clap::Parser;
+    /// There were warnings, and -Werror made them errors.
+    WarningsAsErrors = 2,
 
-use source::Source;
+    /// A file could not be read.
+    Io = 3,
+
+    /// The command line was not valid.
+    Usage = 4,
+}
 
 #[derive(clap::Parser)]
-struct Args {
+#[command(name = "cpp", version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print the directories searched for headers, as `query search-dirs`
+    /// does, instead of running a command.
+    #[arg(long = "print-search-dirs")]
+    print_search_dirs: bool,
+    #[command(flatten)]
+    config: ConfigOptions,
+    #[command(flatten)]
+    search: SearchOptions,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Preprocess a source file.
+    Preprocess(PreprocessArgs),
+
+    /// Print the tokens of a source file, without processing directives or
+    /// expanding macros.
+    Lex(SourceOptions),
+
+    /// Check that every number and character constant in a source file is
+    /// valid.
+    Lint(SourceOptions),
+
+    /// Answer questions about the configuration, without reading a source
+    /// file.
+    Query(QueryArgs),
+
+    /// Evaluate an expression as #if would, after the -D options, and
+    /// print its value and type.
+    Eval(EvalArgs),
+}
+
+#[derive(clap::Args)]
+struct PreprocessArgs {
+    #[command(flatten)]
+    options: SourceOptions,
+
+    #[arg(long = "emit")]
+    emit: Option<Emit>,
+    #[arg(long = "syntax-only", alias = "fsyntax-only")]
+    syntax_only: bool,
+    #[arg(long = "strip-comments")]
+    strip_comments: bool,
+    #[arg(long = "keep-splices")]
+    keep_splices: bool,
+    #[arg(long = "dump-conditions", alias = "fdump-conditions")]
+    dump_conditions: bool,
+    #[arg(long = "timeout", value_name = "SECS")]
+    timeout: Option<u64>,
+}
+
+#[derive(clap::Args)]
+struct QueryArgs {
+    #[command(flatten)]
+    config: ConfigOptions,
+    #[command(flatten)]
+    search: SearchOptions,
+
+    #[command(subcommand)]
+    query: Query,
+}
+
+#[derive(clap::Args)]
+struct EvalArgs {
+    #[command(flatten)]
+    config: ConfigOptions,
+
+    #[arg(short = 'D')]
+    defines: Vec<String>,
+    #[arg(short = 'x')]
+    language: Option<Language>,
+    #[arg(long = "std", value_name = "STANDARD")]
+    standard: Option<Dialect>,
+
+    #[arg(allow_hyphen_values = true)]
+    expression: String,
+}
+
+#[derive(clap::Subcommand)]
+enum Query {
+    /// Print the file a header name resolves to. The name may be written
+    /// `<name>` or `"name"`; a bare name is taken as quoted.
+    Include {
+        header: String,
+    },
+
+    /// Print the directories searched for headers, in order, after the
+    /// configuration file has been merged in.
+    #[command(alias = "print-search-dirs")]
+    SearchDirs,
+
+    /// Print the version, the language and the lexical extensions it
+    /// enables, and the standard.
+    Version {
+        #[arg(short = 'x', value_name = "LANGUAGE")]
+        language: Option<Language>,
+        #[arg(long = "std", value_name = "STANDARD")]
+        standard: Option<Dialect>,
+    },
+}
+
+/// Options for finding the configuration file.
+/// 
+#[derive(clap::Args)]
+struct ConfigOptions {
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[arg(long = "no-config", conflicts_with = "config")]
+    no_config: bool,
+}
+
+/// Options for searching for included files.
+/// 
+#[derive(clap::Args)]
+struct SearchOptions {
     #[arg(short = 'I')]
     includes: Vec<PathBuf>,
+    #[arg(short = 'F')]
+    frameworks: Vec<PathBuf>,
+    #[arg(long = "iquote")]
+    quote_includes: Vec<PathBuf>,
+    #[arg(long = "isystem")]
+    system_includes: Vec<PathBuf>,
+    #[arg(long = "include-ignore-case")]
+    include_ignore_case: bool,
+    #[arg(short = 'v')]
+    verbose: bool,
+}
+
+/// Options shared by every command which reads a source file.
+/// 
+#[derive(clap::Args)]
+struct SourceOptions {
+    #[command(flatten)]
+    config: ConfigOptions,
+    #[command(flatten)]
+    search: SearchOptions,
+
     #[arg(short = 'D')]
     defines: Vec<String>,
+    #[arg(long = "defines-file", value_name = "FILE")]
+    defines_files: Vec<PathBuf>,
+    /// The configuration file's defines, which come before the others.
+    #[arg(skip)]
+    config_defines: Vec<String>,
+    #[arg(long = "input-charset")]
+    input_charset: Option<Charset>,
+    #[arg(short = 'x')]
+    language: Option<Language>,
+    #[arg(long = "std", value_name = "STANDARD")]
+    standard: Option<Dialect>,
+    #[arg(long = "non-ascii")]
+    non_ascii: Option<NonAsciiPolicy>,
+    #[arg(long = "Werror", overrides_with = "no_warnings_are_errors")]
+    warnings_are_errors: bool,
+    #[arg(long = "no-Werror", alias = "Wno-error", overrides_with = "warnings_are_errors")]
+    no_warnings_are_errors: bool,
+    #[arg(long = "exit-zero-on-warnings", overrides_with = "no_exit_zero_on_warnings")]
+    exit_zero_on_warnings: bool,
+    #[arg(long = "no-exit-zero-on-warnings", overrides_with = "exit_zero_on_warnings")]
+    no_exit_zero_on_warnings: bool,
+    #[arg(long = "manifest", value_name = "FILE")]
+    manifest: Option<PathBuf>,
+    #[arg(long = "stats", overrides_with = "no_stats")]
+    stats: bool,
+    #[arg(long = "no-stats", overrides_with = "stats")]
+    no_stats: bool,
 
     source_file: PathBuf,
 }
 
-struct SourceFile {
-    name: PathBuf,
-    source: Source,
+/// Options which gcc spells with a single dash, which clap can't parse.
+/// 
+const SINGLE_DASH_OPTIONS: &[&str] = &["-iquote", "-isystem", "-std="];
+
+/// Flags which gcc spells with a single dash. Unlike options these must
+/// match exactly, so a longer argument which starts with a flag's name is
+/// passed through unchanged.
+/// 
+const SINGLE_DASH_FLAGS: &[&str] = &["-Werror", "-Wno-error", "-fsyntax-only", "-fdump-conditions", "-print-search-dirs"];
+
+/// Rewrite a command line's gcc style single dash options such as 
+/// `-isystem DIR` or `-isystemDIR` to `--isystem DIR`, and `-std=c23` to
+/// `--std=c23`. Anything else is passed through unchanged.
+/// 
+fn gcc_args<I>(command_line: I) -> Vec<OsString> 
+    where I: IntoIterator<Item = OsString>
+{
+    let mut args = Vec::new();
+
+    for arg in command_line {
+        let option = arg.to_str().and_then(|arg| {
+            SINGLE_DASH_OPTIONS.iter()
+                .find(|option| arg.starts_with(*option))
+                .map(|option| (*option, &arg[option.len()..]))
+        });
+
+        match option {
+            _ if SINGLE_DASH_FLAGS.iter().any(|flag| arg == *flag) => {
+                args.push(OsString::from(format!("-{}", arg.to_string_lossy())));
+            },
+            Some((option, value)) if option.ends_with('=') => {
+                args.push(OsString::from(format!("-{}{}", option, value)));
+            },
+            Some((option, value)) => {
+                args.push(OsString::from(format!("-{}", option)));
+                if !value.is_empty() {
+                    args.push(OsString::from(value));
+                }
+            },
+            None => args.push(arg),
+        }
+    }
+
+    args
+}
+
+/// What the preprocessor starts out with, apart from how to lex.
+/// 
+struct PreprocessSetup {
+    /// The configuration file's defines, then those of any defines files,
+    /// then the -D options, in the order they are applied.
+    defines: Vec<MacroOption>,
+
+    /// The standard from --std, or None for the language's default.
+    standard: Option<Standard>,
+
+    /// Give up after this many seconds, from --timeout.
+    timeout: Option<u64>,
+}
+
+/// End of run statistics, for --stats.
+/// 
+#[derive(Default)]
+struct Stats {
+    /// Tokens returned by the lexer or preprocessor, not counting end of
+    /// file.
+    tokens: usize,
+
+    /// A hash of the tokens counted, for --manifest, or None if the run
+    /// didn't produce a token stream.
+    token_hash: Option<Fnv64>,
+}
+
+impl Stats {
+    /// Print the statistics to stderr.
+    /// 
+    fn print(&self, source: &Source) {
+        let lines: usize = source.files.iter().map(|file| file.line_count()).sum();
+
+        eprintln!("files read:    {}", source.files.len());
+        eprintln!("bytes read:    {}", source.bytes_read);
+        eprintln!("lines:         {}", lines);
+        eprintln!("tokens:        {}", self.tokens);
+    }
+}
+
+/// Counts of the diagnostics reported while processing the source.
+/// 
+#[derive(Default)]
+struct Diagnostics {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Diagnostics {
+    /// The exit status for the diagnostics reported. Warnings only affect
+    /// the status with -Werror, and never with --exit-zero-on-warnings.
+    /// 
+    fn status(&self, options: &SourceOptions) -> Status {
+        if self.errors != 0 {
+            Status::Errors
+        } else if self.warnings != 0 && options.warnings_are_errors && !options.exit_zero_on_warnings {
+            Status::WarningsAsErrors
+        } else {
+            Status::Success
+        }
+    }
+}
+
+/// Exit with the given status.
+/// 
+fn exit_with(status: Status) -> ! {
+    exit(status as i32)
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = match Cli::try_parse_from(gcc_args(std::env::args_os())) {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            exit_with(if e.use_stderr() { Status::Usage } else { Status::Success });
+        }
+    };
+
+    let status = match cli.command {
+        Some(Command::Preprocess(args)) => preprocess(args),
+        Some(Command::Lex(options)) => run_configured(options, lex_only),
+        Some(Command::Lint(options)) => run_configured(options, lint_numbers),
+        Some(Command::Query(args)) => query(args),
+        Some(Command::Eval(args)) => eval(args),
+        None if cli.print_search_dirs => query(QueryArgs { config: cli.config, search: cli.search, query: Query::SearchDirs }),
+        None => {
+            let _ = Cli::command().print_help();
+            Status::Usage
+        },
+    };
+
+    exit_with(status);
+}
+
+/// Run the preprocess command.
+/// 
+fn preprocess(mut args: PreprocessArgs) -> Status {
+    match load_config(&args.options.config) {
+        Ok(Some((path, config))) => {
+            if args.emit.is_none() {
+                if let Some(emit) = &config.output.emit {
+                    match Emit::from_str(emit, false) {
+                        Ok(emit) => args.emit = Some(emit),
+                        Err(_) => {
+                            eprintln!("{}: unknown emit kind '{}'", path.to_string_lossy(), emit);
+                            return Status::Usage;
+                        },
+                    }
+                }
+            }
+
+            args.options.merge(config);
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage;
+        },
+    }
+
+    let PreprocessArgs { options, emit, syntax_only, strip_comments: strip, keep_splices, dump_conditions, timeout } = args;
+
+    let defines = match options.macro_options() {
+        Ok(defines) => defines,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage;
+        },
+    };
+
+    let setup = PreprocessSetup {
+        defines,
+        standard: options.standard.map(|dialect| dialect.standard),
+        timeout,
+    };
+
+    match emit {
+        Some(Emit::TokensJson) => run(options, emit_tokens_json),
+        Some(Emit::DocComments) => run(options, |source, options, _| emit_doc_comments(source, options)),
+        Some(Emit::Hash) => run(options, |source, options, stats| emit_hash(source, options, stats, &setup)),
+        None if dump_conditions => run(options, |source, options, stats| emit_conditions(source, options, stats, &setup)),
+        None if syntax_only => run(options, |source, options, stats| syntax_only_mode(source, options, stats, &setup)),
+        None if strip => run(options, |source, options, _| strip_comments(source, options)),
+        None => run(options, |source, options, stats| preprocess_text(source, options, stats, &setup, keep_splices)),
+    }
+}
+
+/// Run the query command.
+/// 
+fn query(mut args: QueryArgs) -> Status {
+    match load_config(&args.config) {
+        Ok(Some((_, config))) => {
+            args.search.merge(&config);
+            if let Query::Version { language, standard } = &mut args.query {
+                *language = language.or(config.language);
+                *standard = standard.or(config.std);
+            }
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage;
+        },
+    }
+
+    let Some(include_paths) = args.search.include_paths() else {
+        return Status::Io;
+    };
+
+    match &args.query {
+        Query::Include { header } => resolve_include(&include_paths, header, args.search.verbose),
+        Query::SearchDirs => {
+            print_search_dirs(&include_paths);
+            Status::Success
+        },
+        Query::Version { language, standard } => {
+            let language = language.unwrap_or_default();
+            let dialect = standard.unwrap_or(Dialect { standard: Standard::default_for(language), gnu: false });
+            print_version(language, dialect);
+            Status::Success
+        },
+    }
+}
+
+/// Run the eval command. The configuration file's defines come before the
+/// -D options, as when preprocessing.
+/// 
+fn eval(mut args: EvalArgs) -> Status {
+    match load_config(&args.config) {
+        Ok(Some((_, config))) => {
+            args.defines.splice(0..0, config.define);
+            args.language = args.language.or(config.language);
+            args.standard = args.standard.or(config.std);
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage;
+        },
+    }
+
+    let language = args.language.unwrap_or_default();
+    if let Err(status) = check_standard(language, args.standard) {
+        return status;
+    }
+
+    let options = LexOptions {
+        language,
+        gnu: args.standard.is_some_and(|dialect| dialect.gnu),
+        ..Default::default()
+    };
+    let standard = args.standard.map_or(Standard::default_for(language), |dialect| dialect.standard);
+
+    let mut pp = Preprocessor::new(options.clone(), standard);
+    let mut warnings = Vec::new();
+
+    let mut source = Source::new();
+    source.push_data(Path::new("<expression>"), args.expression.chars().collect());
+
+    let value = args.defines.iter()
+        .try_for_each(|define| pp.macros.define_option(define, &mut warnings))
+        .and_then(|_| lexer::lex_str("<expression>", &args.expression, &options, &mut Vec::new()))
+        .and_then(|tokens| pp.evaluate(&tokens, Point { file: 0, line: 1, col: 1 }, &mut warnings));
+
+    for warning in warnings {
+        report_warning(&source, warning);
+    }
+
+    match value {
+        Ok((_, value)) => {
+            println!("{} ({})", value, value.type_name());
+            Status::Success
+        },
+        Err(e) => {
+            report(&source, e);
+            Status::Errors
+        },
+    }
+}
+
+/// Check that a --std option, if there is one, names a standard for
+/// `language`.
+/// 
+fn check_standard(language: Language, standard: Option<Dialect>) -> Result<(), Status> {
+    match standard {
+        Some(dialect) if dialect.standard.is_cxx() != (language == Language::Cxx) => {
+            eprintln!("-std={} is not valid for {}", dialect.name(), language.name());
+            Err(Status::Usage)
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Merge in the configuration file, then run a command which has no options
+/// of its own.
+/// 
+fn run_configured<F>(mut options: SourceOptions, f: F) -> Status
+    where F: FnOnce(&mut Source, &LexOptions, &mut Stats) -> Diagnostics
+{
+    match load_config(&options.config) {
+        Ok(Some((_, config))) => options.merge(config),
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            return Status::Usage;
+        },
+    }
+
+    run(options, f)
+}
+
+/// Read the source file and process it with `f`, then print any statistics
+/// and write any manifest asked for. Returns the exit status.
+/// 
+fn run<F>(options: SourceOptions, f: F) -> Status
+    where F: FnOnce(&mut Source, &LexOptions, &mut Stats) -> Diagnostics
+{
+    if options.search.include_paths().is_none() {
+        return Status::Io;
+    }
 
     let mut source = Source::new();
+    source.charset = options.input_charset.unwrap_or(Charset::Auto);
 
-    match source.push_file(&args.source_file) {
-        Ok(()) => {},
+    //
+    // A file which can't be read is an I/O error, but one which can and
+    // isn't valid in its charset is an error in the source.
+    //
+    let bytes = match fs::read(&options.source_file) {
+        Ok(bytes) => bytes,
         Err(e) => {
-            eprintln!("{}: {}", args.source_file.to_string_lossy(), e);
-            exit(1);
+            eprintln!("{}: {}", options.source_file.to_string_lossy(), e);
+            return Status::Io;
+        },
+    };
+
+    if let Err(e) = source.push_bytes(&options.source_file, &bytes) {
+        eprintln!("{}: {}", options.source_file.to_string_lossy(), e);
+        return Status::Errors;
+    }
+
+    let language = options.language.unwrap_or_else(|| Language::from_path(&options.source_file));
+    if let Err(status) = check_standard(language, options.standard) {
+        return status;
+    }
+
+    let lex_options = LexOptions {
+        language,
+        non_ascii: options.non_ascii.unwrap_or_default(),
+        gnu: options.standard.is_some_and(|dialect| dialect.gnu),
+    };
+    let mut stats = Stats::default();
+
+    let diagnostics = f(&mut source, &lex_options, &mut stats);
+
+    if options.stats {
+        stats.print(&source);
+    }
+
+    if let Some(path) = &options.manifest {
+        if let Err(e) = fs::write(path, fingerprint::manifest_json(&source, stats.token_hash.map(|hash| hash.finish())) + "\n") {
+            eprintln!("{}: {}", path.to_string_lossy(), e);
+            return Status::Io;
         }
+    }
+
+    diagnostics.status(&options)
+}
+
+/// Read the configuration file, with the path it was read from. The file is
+/// the one given with --config, or cpp.toml in the current directory if
+/// there is one.
+/// 
+fn load_config(options: &ConfigOptions) -> Result<Option<(PathBuf, Config)>, CcError> {
+    let path = match &options.config {
+        Some(path) => path.clone(),
+        None if options.no_config => return Ok(None),
+        None => {
+            let path = PathBuf::from(config::CONFIG_FILE);
+            if !path.is_file() {
+                return Ok(None);
+            }
+            path
+        },
     };
 
-    loop {
-        if let Some(ch) = source.next() {
-            println!("{}@{}:{}: {} ", 
-                source.get_filename(ch.pt.file).unwrap(), 
-                ch.pt.line, 
-                ch.pt.col, 
-                ch.ch); 
+    let config = Config::load(&path)?;
+    Ok(Some((path, config)))
+}
+
+impl SearchOptions {
+    /// Merge in the search directories from the configuration file. Those
+    /// given on the command line are searched first.
+    /// 
+    fn merge(&mut self, config: &Config) {
+        self.includes.extend(config.include.iter().cloned());
+        self.quote_includes.extend(config.iquote.iter().cloned());
+        self.system_includes.extend(config.isystem.iter().cloned());
+        self.frameworks.extend(config.framework.iter().cloned());
+    }
+
+    /// Build the include search paths, reading any header maps, and print
+    /// them with -v. Errors are reported here.
+    /// 
+    fn include_paths(&self) -> Option<IncludePaths> {
+        let mut include_paths = IncludePaths {
+            quote: self.quote_includes.clone(),
+            user: self.includes.clone(),
+            system: self.system_includes.clone(),
+            frameworks: self.frameworks.clone(),
+            ignore_case: self.include_ignore_case,
+            ..Default::default()
+        };
+
+        if let Err(e) = include_paths.load_header_maps() {
+            eprintln!("{}", e);
+            return None;
+        }
+
+        if self.verbose {
+            print_search_list(&include_paths);
+        }
+
+        Some(include_paths)
+    }
+}
+
+impl SourceOptions {
+    /// Merge in the configuration file. Options on the command line win,
+    /// and -D options and defines files come after the file's defines, so
+    /// they replace them. A setting turned on in the file can be turned off
+    /// with its --no- option.
+    /// 
+    fn merge(&mut self, config: Config) {
+        self.search.merge(&config);
+        self.config_defines = config.define;
+
+        self.language = self.language.or(config.language);
+        self.standard = self.standard.or(config.std);
+        self.input_charset = self.input_charset.or(config.input_charset);
+        self.non_ascii = self.non_ascii.or(config.warnings.non_ascii);
+        self.warnings_are_errors = !self.no_warnings_are_errors && (self.warnings_are_errors || config.warnings.error);
+        self.exit_zero_on_warnings = !self.no_exit_zero_on_warnings && (self.exit_zero_on_warnings || config.warnings.exit_zero);
+        self.stats = !self.no_stats && (self.stats || config.output.stats);
+    }
+
+    /// The macros to define and undefine before preprocessing, in order:
+    /// the configuration file's defines, the entries of each defines file,
+    /// then the -D options.
+    /// 
+    fn macro_options(&self) -> Result<Vec<MacroOption>, CcError> {
+        let mut options: Vec<_> = self.config_defines.iter().cloned().map(MacroOption::Define).collect();
+
+        for path in &self.defines_files {
+            options.extend(defines::load(path)?);
+        }
+
+        options.extend(self.defines.iter().cloned().map(MacroOption::Define));
+        Ok(options)
+    }
+}
+
+/// Print the include search directories, in the same form as gcc -v.
+/// 
+fn print_search_list(paths: &IncludePaths) {
+    eprintln!("#include \"...\" search starts here:");
+    for dir in &paths.quote {
+        eprintln!(" {}", dir.to_string_lossy());
+    }
 
-            if ch.pt.line == 14 && ch.pt.col == 15 {
-                source.push_file(&PathBuf::from_str("../rustcc/testdata/test.c").unwrap()).unwrap();
+    eprintln!("#include <...> search starts here:");
+    for dir in paths.user.iter().chain(paths.system.iter()) {
+        eprintln!(" {}", dir.to_string_lossy());
+    }
+    for dir in &paths.frameworks {
+        eprintln!(" {} (framework directory)", dir.to_string_lossy());
+    }
+
+    eprintln!("End of search list.");
+}
+
+/// Print the include search directories to stdout, one per line, each 
+/// prefixed with the kind of header it is searched for. Header maps given
+/// in place of a directory are marked as such.
+/// 
+fn print_search_dirs(paths: &IncludePaths) {
+    let groups = [
+        ("quote", &paths.quote),
+        ("angled", &paths.user),
+        ("system", &paths.system),
+        ("framework", &paths.frameworks),
+    ];
+
+    for (kind, dirs) in groups {
+        for dir in dirs {
+            if paths.header_maps.contains_key(dir) {
+                println!("{}: {} (header map)", kind, dir.to_string_lossy());
+            } else {
+                println!("{}: {}", kind, dir.to_string_lossy());
             }
+        }
+    }
+}
 
-        } else {
-            break;
+/// Print the version, the language and the extensions to C it enables,
+/// and the standard. A GNU dialect also enables raw strings.
+/// 
+fn print_version(language: Language, dialect: Dialect) {
+    let mut extensions = language.extensions().to_vec();
+    if dialect.gnu && !extensions.contains(&"raw-strings") {
+        extensions.push("raw-strings");
+    }
+
+    println!("cpp {}", env!("CARGO_PKG_VERSION"));
+    println!("language: {}", language.name());
+    println!("extensions: {}", if extensions.is_empty() { "none".to_string() } else { extensions.join(", ") });
+    println!("standard: {}", dialect.name());
+}
+
+/// Print the file a header name would resolve to, without preprocessing
+/// anything. The name may be written `<name>` or `"name"`; a bare name is
+/// taken as quoted. A quoted name is first looked for relative to the 
+/// current directory. Returns the exit status.
+/// 
+fn resolve_include(paths: &IncludePaths, header: &str, verbose: bool) -> Status {
+    let (name, kind) = if let Some(name) = header.strip_prefix('<').and_then(|name| name.strip_suffix('>')) {
+        (name, HeaderKind::Angled)
+    } else if let Some(name) = header.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+        (name, HeaderKind::Quoted)
+    } else {
+        (header, HeaderKind::Quoted)
+    };
+
+    let mut probed = Vec::new();
+    let mut warnings = Vec::new();
+
+    //
+    // The includer stands in for a file in the current directory.
+    //
+    let found = paths.resolve(name, kind, Some(Path::new("-")), None, &mut probed, &mut warnings);
+
+    if verbose {
+        for path in &probed {
+            eprintln!("probing {}", path.to_string_lossy());
         }
     }
 
+    for warning in &warnings {
+        eprintln!("warning: {}", warning.what);
+    }
+
+    match found {
+        Some(path) => {
+            println!("{}", path.to_string_lossy());
+            Status::Success
+        },
+        None => {
+            eprintln!("{}: No such file or directory", name);
+            Status::Errors
+        },
+    }
+}
+
+/// Print an error, prefixed with the name of the file it occurred in.
+/// 
+fn report(source: &Source, e: CcError) {
+    eprintln!("{}", Diagnostic::new(Severity::Error, source, e));
+}
+
+/// Print a warning, prefixed with the name of the file it occurred in.
+/// 
+fn report_warning(source: &Source, e: CcError) {
+    eprintln!("{}", Diagnostic::new(Severity::Warning, source, e));
+}
+
+/// Lex the whole source, passing each token to `f`, up to but not including
+/// the end of file. `f` may append warnings to the vector it is given. 
+/// Errors and warnings are reported as they are found. 
+/// 
+fn lex_all<F>(source: &mut Source, options: &LexOptions, stats: &mut Stats, f: F) -> Diagnostics 
+    where F: FnMut(&Source, MetaToken, &mut Vec<CcError>) -> Result<(), CcError>
+{
     let mut emit = Vec::new();
-    match lexer::next_token(&mut source, &mut emit) {
-        Ok(token) => println!("{:?}", token),
-        Err(e) => println!("err {}", e),
+
+    let next = |source: &mut Source, _: &mut Vec<CcError>| {
+        let token = lexer::next_token_with(source, &mut emit, options);
+        emit.clear();
+        token
+    };
+
+    process_all(source, options, stats, Diagnostics::default(), next, f)
+}
+
+/// Preprocess the whole source to the standard in `setup`, with the macros
+/// given by -D options defined, passing each token of the output to `f` as
+/// `lex_all` does.
+/// 
+fn preprocess_all<F>(source: &mut Source, options: &LexOptions, stats: &mut Stats, setup: &PreprocessSetup, f: F) -> Diagnostics 
+    where F: FnMut(&Source, MetaToken, &mut Vec<CcError>) -> Result<(), CcError>
+{
+    let mut diagnostics = Diagnostics::default();
+    let mut pp = new_preprocessor(source, options, setup, &mut diagnostics);
+
+    let next = |source: &mut Source, warnings: &mut Vec<CcError>| pp.next_token(source, warnings);
+
+    process_all(source, options, stats, diagnostics, next, f)
+}
+
+/// A preprocessor for the standard in `setup`, with the macros given by -D
+/// options defined. Problems with the macros are reported and counted in
+/// `diagnostics`.
+/// 
+fn new_preprocessor(source: &Source, options: &LexOptions, setup: &PreprocessSetup, diagnostics: &mut Diagnostics) -> Preprocessor {
+    let standard = setup.standard.unwrap_or_else(|| Standard::default_for(options.language));
+    let mut pp = Preprocessor::new(options.clone(), standard);
+    let mut warnings = Vec::new();
+
+    if let Some(secs) = setup.timeout {
+        pp.set_cancel(watchdog(secs));
+    }
+
+    for define in &setup.defines {
+        if let Err(e) = pp.macros.apply_option(define, &mut warnings) {
+            report(source, e);
+            diagnostics.errors += 1;
+        }
+    }
+
+    for warning in warnings {
+        report_warning(source, warning);
+        diagnostics.warnings += 1;
+    }
+
+    pp
+}
+
+/// Pass each token from `next` to `f`, up to but not including the end of
+/// file, reporting errors and warnings from both as they are found. The
+/// counts are added to `diagnostics`.
+/// 
+fn process_all<N, F>(source: &mut Source, options: &LexOptions, stats: &mut Stats, mut diagnostics: Diagnostics, mut next: N, mut f: F) -> Diagnostics 
+    where N: FnMut(&mut Source, &mut Vec<CcError>) -> Result<MetaToken, CcError>,
+          F: FnMut(&Source, MetaToken, &mut Vec<CcError>) -> Result<(), CcError>
+{
+    let mut warnings = Vec::new();
+    let token_hash = stats.token_hash.get_or_insert_with(Fnv64::default);
+
+    let mut eof = false;
+
+    while !eof {
+        let result = match next(source, &mut warnings) {
+            Ok(MetaToken { token: PpToken::Eof, .. }) => {
+                eof = true;
+                Ok(())
+            },
+            Ok(token) => {
+                stats.tokens += 1;
+                token_hash.write_token(&token.token);
+
+                if options.non_ascii == NonAsciiPolicy::Warn {
+                    warnings.extend(lexer::stray_non_ascii(&token));
+                }
+                warnings.extend(lexer::unknown_escapes(&token));
+
+                f(source, token, &mut warnings)
+            },
+            Err(e) => Err(e),
+        };
+
+        for warning in warnings.drain(..) {
+            report_warning(source, warning);
+            diagnostics.warnings += 1;
+        }
+
+        if let Err(e) = result {
+            let cancelled = e.cancelled;
+
+            report(source, e);
+            diagnostics.errors += 1;
+
+            if cancelled {
+                break;
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Start a thread which cancels the run after `secs` seconds, for
+/// --timeout. The thread is left to be stopped when the program exits.
+/// 
+fn watchdog(secs: u64) -> CancelToken {
+    let cancel = CancelToken::new();
+    let token = cancel.clone();
+
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(secs));
+        token.cancel_because(&format!("timed out after {} second{}", secs, if secs == 1 { "" } else { "s" }));
+    });
+
+    cancel
+}
+
+/// Lex the whole source, checking that every pp-number is a valid integer
+/// or floating constant.
+/// 
+fn lint_numbers(source: &mut Source, options: &LexOptions, stats: &mut Stats) -> Diagnostics {
+    lex_all(source, options, stats, |_, token, _| {
+        if let PpToken::Number(spelling) = &token.token {
+            number::convert(spelling, token.loc)?;
+        }
+        Ok(())
+    })
+}
+
+/// Lex the whole source, writing each token as a line of JSON, ending with
+/// an eof record so a consumer can tell the stream wasn't cut short.
+/// 
+fn emit_tokens_json(source: &mut Source, options: &LexOptions, stats: &mut Stats) -> Diagnostics {
+    let diagnostics = lex_all(source, options, stats, |source, token, _| {
+        println!("{}", json::token_json(&token, source));
+        Ok(())
+    });
+
+    let eof = MetaToken {
+        token: PpToken::Eof,
+        loc: Point { file: 0, line: 0, col: 0 },
+        starts_line: false,
+        space_before: false,
     };
+    println!("{}", json::token_json(&eof, source));
+
+    diagnostics
+}
+
+/// Write each documentation comment in the source as a line of JSON.
+/// 
+fn emit_doc_comments(source: &mut Source, options: &LexOptions) -> Diagnostics {
+    let mut diagnostics = Diagnostics::default();
+
+    match doc::doc_comments(source, options) {
+        Ok(docs) => {
+            for doc in docs {
+                println!("{}", json::doc_comment_json(&doc, source));
+            }
+        },
+        Err(e) => {
+            report(source, e);
+            diagnostics.errors += 1;
+        },
+    }
+
+    diagnostics
+}
+
+/// Preprocess the whole source, writing the hash of the output tokens.
+/// Whitespace and comments don't affect the hash.
+/// 
+fn emit_hash(source: &mut Source, options: &LexOptions, stats: &mut Stats, setup: &PreprocessSetup) -> Diagnostics {
+    let diagnostics = preprocess_all(source, options, stats, setup, |_, _, _| Ok(()));
+    println!("{}", fingerprint::hex(stats.token_hash.unwrap_or_default().finish()));
+    diagnostics
+}
+
+/// Preprocess the whole source, writing each #if and #elif condition which
+/// was evaluated as a line of JSON instead of the output.
+/// 
+fn emit_conditions(source: &mut Source, options: &LexOptions, stats: &mut Stats, setup: &PreprocessSetup) -> Diagnostics {
+    let mut diagnostics = Diagnostics::default();
+    let mut pp = new_preprocessor(source, options, setup, &mut diagnostics);
+    pp.record_conditions();
+
+    let next = |source: &mut Source, warnings: &mut Vec<CcError>| pp.next_token(source, warnings);
+    let diagnostics = process_all(source, options, stats, diagnostics, next, |_, _, _| Ok(()));
+
+    for condition in pp.take_conditions() {
+        println!("{}", json::condition_json(&condition, source));
+    }
+
+    diagnostics
+}
+
+/// Preprocess the whole source, writing the output as text. With 
+/// `keep_splices`, lines continued with a backslash-newline stay split.
+/// 
+fn preprocess_text(source: &mut Source, options: &LexOptions, stats: &mut Stats, setup: &PreprocessSetup, keep_splices: bool) -> Diagnostics {
+    let mut writer = TextWriter::new();
+    writer.keep_splices = keep_splices;
+    let mut out = String::new();
+
+    let diagnostics = preprocess_all(source, options, stats, setup, |_, token, _| {
+        writer.write(&token, &mut out);
+        Ok(())
+    });
+
+    writer.finish(&mut out);
+    print!("{}", out);
+    diagnostics
+}
+
+/// Lex the whole source without processing directives or expanding macros,
+/// printing each token with its location.
+/// 
+fn lex_only(source: &mut Source, options: &LexOptions, stats: &mut Stats) -> Diagnostics {
+    lex_all(source, options, stats, |source, token, _| {
+        println!("{}:{}:{}: {:?}",
+            source.get_filename(token.loc.file).unwrap_or_default(),
+            token.loc.line,
+            token.loc.col,
+            token.token);
+        Ok(())
+    })
+}
+
+/// Preprocess the whole source, writing nothing but diagnostics.
+/// 
+fn syntax_only_mode(source: &mut Source, options: &LexOptions, stats: &mut Stats, setup: &PreprocessSetup) -> Diagnostics {
+    preprocess_all(source, options, stats, setup, |_, _, _| Ok(()))
+}
+
+/// Write the source with its comments removed, and nothing else changed.
+/// 
+fn strip_comments(source: &Source, options: &LexOptions) -> Diagnostics {
+    let mut diagnostics = Diagnostics::default();
+    let file = &source.files[0];
+
+    match strip::strip_comments(&file.text, 0, options) {
+        Ok(text) => {
+            if file.bom {
+                print!("{}", BOM);
+            }
+            print!("{}", text);
+        },
+        Err(e) => {
+            report(source, e);
+            diagnostics.errors += 1;
+        },
+    }
 
-    
+    diagnostics
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(command_line: &[&str]) -> Vec<String> {
+        gcc_args(command_line.iter().map(OsString::from))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_single_dash_options() {
+        assert_eq!(
+            rewrite(&["cpp", "-isystem", "sys", "-iquotequote", "a.c"]),
+            ["cpp", "--isystem", "sys", "--iquote", "quote", "a.c"]);
+    }
+
+    #[test]
+    fn keeps_joined_std_values() {
+        assert_eq!(
+            rewrite(&["cpp", "-std=gnu11", "a.c"]),
+            ["cpp", "--std=gnu11", "a.c"]);
+    }
+
+    #[test]
+    fn passes_other_arguments_through() {
+        assert_eq!(
+            rewrite(&["cpp", "-v", "--iquote", "quote", "-I", "inc", "a.c"]),
+            ["cpp", "-v", "--iquote", "quote", "-I", "inc", "a.c"]);
+    }
+
+    #[test]
+    fn matches_single_dash_flags_exactly() {
+        assert_eq!(
+            rewrite(&["cpp", "-fsyntax-only", "-fsyntax-only-not", "a.c"]),
+            ["cpp", "--fsyntax-only", "-fsyntax-only-not", "a.c"]);
+    }
+
+    #[test]
+    fn rewrites_dump_conditions() {
+        assert_eq!(
+            rewrite(&["cpp", "preprocess", "-fdump-conditions", "a.c"]),
+            ["cpp", "preprocess", "--fdump-conditions", "a.c"]);
+    }
+
+    #[test]
+    fn prints_search_dirs_without_a_command() {
+        let cli = Cli::try_parse_from(rewrite(&["cpp", "-print-search-dirs", "-I", "inc"])).unwrap();
+        assert!(cli.command.is_none() && cli.print_search_dirs);
+        assert_eq!(cli.search.includes, [PathBuf::from("inc")]);
+
+        assert!(Cli::try_parse_from(["cpp", "--print-search-dirs", "lex", "a.c"]).is_err());
+    }
+
+    #[test]
+    fn leaves_werror_with_a_value_alone() {
+        assert_eq!(
+            rewrite(&["cpp", "-Werror", "-Werror=foo", "a.c"]),
+            ["cpp", "--Werror", "-Werror=foo", "a.c"]);
+    }
+
+    #[test]
+    fn lint_warns_about_an_unknown_escape_once() {
+        let mut source = Source::new();
+        source.push_data(Path::new("a.c"), "char c = '\\q';\n".chars().collect());
+
+        let diagnostics = lint_numbers(&mut source, &LexOptions::default(), &mut Stats::default());
+
+        assert_eq!(diagnostics.errors, 0);
+        assert_eq!(diagnostics.warnings, 1);
+    }
+
+    #[test]
+    fn invalid_text_is_an_error_in_the_source() {
+        let path = std::env::temp_dir().join(format!("cpp-decode-{}.c", std::process::id()));
+        fs::write(&path, b"int a\xff;\n").unwrap();
+
+        let lint = |path: &Path| match Cli::try_parse_from(["cpp".as_ref(), "lint".as_ref(), path.as_os_str()]) {
+            Ok(Cli { command: Some(Command::Lint(options)), .. }) => run(options, lint_numbers),
+            _ => panic!("lint command not parsed"),
+        };
+
+        assert_eq!(lint(&path), Status::Errors);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lint(&path), Status::Io);
+    }
+}
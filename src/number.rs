@@ -0,0 +1,355 @@
+//
+// Conversion of pp-numbers into typed numeric constants. The lexer's
+// pp-number rule deliberately accepts more than the set of valid constants
+// (e.g. `0x1g8` or `1.2.3`), so this is where those are rejected.
+//
+use crate::ccerror::CcError;
+use crate::source::Point;
+
+/// The length suffix of an integer constant.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IntLength {
+    /// No suffix; `int`.
+    Int,
+
+    /// `l` or `L`.
+    Long,
+
+    /// `ll` or `LL`.
+    LongLong,
+}
+
+/// An integer constant.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IntConstant {
+    /// The value of the constant.
+    pub value: u64,
+
+    /// The base the constant was written in: 2, 8, 10 or 16.
+    pub radix: u32,
+
+    /// Did the constant have a `u` or `U` suffix?
+    pub unsigned: bool,
+
+    /// The length suffix.
+    pub length: IntLength,
+}
+
+/// The type suffix of a floating constant.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FloatKind {
+    /// No suffix; `double`.
+    Double,
+
+    /// `f` or `F`.
+    Float,
+
+    /// `l` or `L`.
+    LongDouble,
+}
+
+/// A floating constant.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FloatConstant {
+    /// The value of the constant.
+    pub value: f64,
+
+    /// The type suffix.
+    pub kind: FloatKind,
+}
+
+/// A numeric constant converted from a pp-number.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NumericConstant {
+    Int(IntConstant),
+    Float(FloatConstant),
+}
+
+/// Convert the spelling of a pp-number into a numeric constant, diagnosing
+/// anything which is not a valid integer or floating constant.
+///
+/// `loc` is the location of the pp-number, used for diagnostics.
+///
+pub fn convert(spelling: &str, loc: Point) -> Result<NumericConstant, CcError> {
+    let lower = spelling.to_ascii_lowercase();
+    let hex = lower.starts_with("0x");
+
+    let is_float = if hex {
+        lower.contains('.') || lower.contains('p')
+    } else {
+        lower.contains('.') || lower.contains('e')
+    };
+
+    if is_float {
+        float_constant(spelling, hex, loc).map(NumericConstant::Float)
+    } else {
+        int_constant(spelling, loc).map(NumericConstant::Int)
+    }
+}
+
+/// Convert an integer constant.
+///
+fn int_constant(spelling: &str, loc: Point) -> Result<IntConstant, CcError> {
+    let lower = spelling.to_ascii_lowercase();
+
+    let (radix, body) = if lower.starts_with("0x") {
+        (16, &spelling[2..])
+    } else if lower.starts_with("0b") {
+        (2, &spelling[2..])
+    } else if lower.starts_with('0') {
+        (8, spelling)
+    } else {
+        (10, spelling)
+    };
+
+    let end = body.find(|ch: char| !ch.is_ascii_hexdigit() || (radix != 16 && ch.is_ascii_alphabetic()))
+        .unwrap_or(body.len());
+    let (digits, suffix) = body.split_at(end);
+
+    if digits.is_empty() {
+        return Err(
+            CcError::err_with_loc(
+                format!("no digits in integer constant '{}'", spelling),
+                loc
+            )
+        );
+    }
+
+    let mut value: u64 = 0;
+
+    for ch in digits.chars() {
+        let digit = match ch.to_digit(radix) {
+            Some(digit) => digit,
+            None => {
+                return Err(
+                    CcError::err_with_loc(
+                        format!("invalid digit '{}' in {} constant", ch, radix_name(radix)),
+                        loc
+                    )
+                )
+            },
+        };
+
+        value = match value.checked_mul(radix as u64).and_then(|v| v.checked_add(digit as u64)) {
+            Some(value) => value,
+            None => {
+                return Err(
+                    CcError::err_with_loc(
+                        format!("integer constant '{}' is too large", spelling),
+                        loc
+                    )
+                )
+            },
+        };
+    }
+
+    let (unsigned, length) = match int_suffix(suffix) {
+        Some(suffix) => suffix,
+        None => {
+            return Err(
+                CcError::err_with_loc(
+                    format!("invalid suffix '{}' on integer constant", suffix),
+                    loc
+                )
+            )
+        },
+    };
+
+    Ok(IntConstant { value, radix, unsigned, length })
+}
+
+/// Parse an integer suffix, which is `u` and `l` or `ll` in either order
+/// and either case (but `lL` isn't allowed).
+///
+fn int_suffix(suffix: &str) -> Option<(bool, IntLength)> {
+    let (unsigned, length) = match suffix.find(['u', 'U']) {
+        Some(0) => (true, &suffix[1..]),
+        Some(n) if n == suffix.len() - 1 => (true, &suffix[..n]),
+        Some(_) => return None,
+        None => (false, suffix),
+    };
+
+    let length = match length {
+        "" => IntLength::Int,
+        "l" | "L" => IntLength::Long,
+        "ll" | "LL" => IntLength::LongLong,
+        _ => return None,
+    };
+
+    Some((unsigned, length))
+}
+
+/// Convert a floating constant.
+///
+fn float_constant(spelling: &str, hex: bool, loc: Point) -> Result<FloatConstant, CcError> {
+    let (body, kind) = match spelling.chars().last() {
+        Some('f') | Some('F') if !hex || spelling.to_ascii_lowercase().contains('p') => {
+            (&spelling[..spelling.len() - 1], FloatKind::Float)
+        },
+        Some('l') | Some('L') => (&spelling[..spelling.len() - 1], FloatKind::LongDouble),
+        _ => (spelling, FloatKind::Double),
+    };
+
+    let value = if hex {
+        hex_float(&body[2..])
+    } else {
+        decimal_float(body)
+    };
+
+    let value = match value {
+        Some(value) => value,
+        None => {
+            return Err(
+                CcError::err_with_loc(
+                    format!("invalid floating constant '{}'", spelling),
+                    loc
+                )
+            )
+        },
+    };
+
+    if value.is_infinite() {
+        return Err(
+            CcError::err_with_loc(
+                format!("floating constant '{}' exceeds range of double", spelling),
+                loc
+            )
+        );
+    }
+
+    Ok(FloatConstant { value, kind })
+}
+
+/// Parse the body of a decimal floating constant: digits with an optional
+/// fraction and exponent.
+///
+fn decimal_float(body: &str) -> Option<f64> {
+    let valid = body.chars().enumerate().all(|(i, ch)| {
+        ch.is_ascii_digit() || ch == '.' || ch == 'e' || ch == 'E' ||
+            ((ch == '+' || ch == '-') && i > 0 && matches!(body.as_bytes()[i - 1], b'e' | b'E'))
+    });
+
+    if !valid {
+        return None;
+    }
+
+    body.parse::<f64>().ok()
+}
+
+/// Parse the body of a hex floating constant, after the `0x`. The binary
+/// exponent is required.
+///
+fn hex_float(body: &str) -> Option<f64> {
+    let (mantissa, exponent) = body.split_once(['p', 'P'])?;
+    let exponent: i32 = exponent.parse().ok()?;
+
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+
+    let mut value = 0.0f64;
+
+    for ch in whole.chars() {
+        value = value * 16.0 + ch.to_digit(16)? as f64;
+    }
+
+    let mut scale = 1.0 / 16.0;
+
+    for ch in fraction.chars() {
+        value += ch.to_digit(16)? as f64 * scale;
+        scale /= 16.0;
+    }
+
+    Some(value * 2f64.powi(exponent))
+}
+
+fn radix_name(radix: u32) -> &'static str {
+    match radix {
+        2 => "binary",
+        8 => "octal",
+        16 => "hexadecimal",
+        _ => "decimal",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOC: Point = Point { file: 0, line: 1, col: 1 };
+
+    fn int(value: u64, radix: u32, unsigned: bool, length: IntLength) -> NumericConstant {
+        NumericConstant::Int(IntConstant { value, radix, unsigned, length })
+    }
+
+    fn float(value: f64, kind: FloatKind) -> NumericConstant {
+        NumericConstant::Float(FloatConstant { value, kind })
+    }
+
+    #[test]
+    fn integer_bases() -> Result<(), CcError> {
+        assert_eq!(convert("42", LOC)?, int(42, 10, false, IntLength::Int));
+        assert_eq!(convert("052", LOC)?, int(42, 8, false, IntLength::Int));
+        assert_eq!(convert("0x2A", LOC)?, int(42, 16, false, IntLength::Int));
+        assert_eq!(convert("0b101010", LOC)?, int(42, 2, false, IntLength::Int));
+        assert_eq!(convert("0", LOC)?, int(0, 8, false, IntLength::Int));
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_suffixes() -> Result<(), CcError> {
+        assert_eq!(convert("42u", LOC)?, int(42, 10, true, IntLength::Int));
+        assert_eq!(convert("42UL", LOC)?, int(42, 10, true, IntLength::Long));
+        assert_eq!(convert("42llu", LOC)?, int(42, 10, true, IntLength::LongLong));
+        assert_eq!(convert("0xffLL", LOC)?, int(255, 16, false, IntLength::LongLong));
+
+        assert!(convert("42lL", LOC).is_err());
+        assert!(convert("42uu", LOC).is_err());
+        assert!(convert("42lul", LOC).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_integers() {
+        assert!(convert("0x1g8", LOC).is_err());
+        assert!(convert("09", LOC).is_err());
+        assert!(convert("0b102", LOC).is_err());
+        assert!(convert("0x", LOC).is_err());
+        assert!(convert("18446744073709551616", LOC).is_err());
+    }
+
+    #[test]
+    fn floats() -> Result<(), CcError> {
+        assert_eq!(convert("1.5", LOC)?, float(1.5, FloatKind::Double));
+        assert_eq!(convert(".5f", LOC)?, float(0.5, FloatKind::Float));
+        assert_eq!(convert("1e3L", LOC)?, float(1000.0, FloatKind::LongDouble));
+        assert_eq!(convert("1.e-1", LOC)?, float(0.1, FloatKind::Double));
+        assert_eq!(convert("0x1.8p1", LOC)?, float(3.0, FloatKind::Double));
+        assert_eq!(convert("0x10p-4f", LOC)?, float(1.0, FloatKind::Float));
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_floats() {
+        assert!(convert("1.2.3", LOC).is_err());
+        assert!(convert("1e", LOC).is_err());
+        assert!(convert("0x1.8", LOC).is_err());
+        assert!(convert("1.5q", LOC).is_err());
+        assert!(convert("1e999", LOC).is_err());
+    }
+}
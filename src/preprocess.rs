@@ -0,0 +1,920 @@
+//
+// Directive processing and macro expansion over the token stream from the
+// lexer, and the writing of the result as text.
+//
+use crate::cancel::CancelToken;
+use crate::ccerror::CcError;
+use crate::expr::{self, Value};
+use crate::lexer::{self, Language, LexOptions, MetaToken, PpToken};
+use crate::macros::MacroTable;
+use crate::source::{Point, Source};
+use crate::standard::Standard;
+
+use std::collections::VecDeque;
+
+/// Directives which are recognized, but not processed yet.
+///
+const UNSUPPORTED_DIRECTIVES: &[&str] = &["include", "include_next", "import", "embed", "line"];
+
+/// The directives which start, continue or end an #if group.
+///
+const CONDITIONAL_DIRECTIVES: &[&str] = &[
+    "if", "ifdef", "ifndef", "elif", "elifdef", "elifndef", "else", "endif",
+];
+
+/// Where the preprocessor is in an #if group.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Branch {
+    /// The current branch is being processed.
+    Taken,
+
+    /// No branch has been taken yet, so a later #elif or #else may be.
+    Waiting,
+
+    /// A branch has already been taken, or the whole group is inside one
+    /// which is being skipped, so the rest is skipped.
+    Done,
+}
+
+/// An #if group which hasn't been ended by #endif yet.
+///
+#[derive(Debug)]
+struct Conditional {
+    /// The directive which started the group, and where it was, for
+    /// reporting a group which is never ended.
+    directive: String,
+    loc: Point,
+
+    branch: Branch,
+
+    /// Has the group's #else been seen?
+    seen_else: bool,
+
+    /// Is the whole group inside a group being skipped?
+    outer_skipped: bool,
+}
+
+/// The controlling expression of an #if or #elif which was evaluated.
+///
+#[derive(Debug, Clone)]
+pub struct Condition {
+    /// The directive, "if" or "elif", and where it was.
+    pub directive: String,
+    pub loc: Point,
+
+    /// The expression after `defined` was replaced and macros expanded.
+    pub expanded: Vec<MetaToken>,
+
+    pub value: Value,
+}
+
+/// The state of the preprocessor between tokens.
+///
+pub struct Preprocessor {
+    /// The macros defined so far.
+    pub macros: MacroTable,
+
+    /// See `set_cancel`.
+    cancel: Option<CancelToken>,
+
+    /// How to lex the source.
+    options: LexOptions,
+
+    /// The standard the source is written to.
+    standard: Standard,
+
+    /// The #if groups the current line is in, innermost last.
+    conditionals: Vec<Conditional>,
+
+    /// See `record_conditions`.
+    conditions: Option<Vec<Condition>>,
+
+    /// Whitespace from the lexer, which is only needed for spacing.
+    emit: Vec<char>,
+
+    /// A token the lexer has returned which hasn't been looked at yet.
+    lookahead: Option<MetaToken>,
+
+    /// Tokens ready to be returned, from a macro expansion or a directive
+    /// which is passed through.
+    pending: VecDeque<MetaToken>,
+
+    /// The line start and spacing of a macro which expanded to nothing,
+    /// which pass to the next token.
+    carried: (bool, bool),
+
+    /// The file the last token came from, to notice moving to another.
+    file: Option<u32>,
+}
+
+impl Preprocessor {
+    /// A preprocessor for source in `standard`. A C++ standard predefines
+    /// `__cplusplus`.
+    ///
+    pub fn new(options: LexOptions, standard: Standard) -> Self {
+        let mut macros = MacroTable::new();
+        if let Some(version) = standard.cplusplus() {
+            macros.predefine("__cplusplus", version);
+        }
+
+        Preprocessor {
+            macros,
+            cancel: None,
+            options,
+            standard,
+            conditionals: Vec::new(),
+            conditions: None,
+            emit: Vec::new(),
+            lookahead: None,
+            pending: VecDeque::new(),
+            carried: (false, false),
+            file: None,
+        }
+    }
+
+    /// The standard the source is written to.
+    ///
+    pub fn standard(&self) -> Standard {
+        self.standard
+    }
+
+    /// Return the next token of preprocessed output, processing any
+    /// directives and expanding macros on the way.
+    ///
+    pub fn next_token(&mut self, source: &mut Source, warnings: &mut Vec<CcError>) -> Result<MetaToken, CcError> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Ok(self.carry(token));
+            }
+
+            //
+            // A skipped group need only be made of valid preprocessing
+            // tokens, and even then, errors in it aren't reported.
+            //
+            let token = match self.lex(source) {
+                Err(_) if self.skipping() => continue,
+                token => token?,
+            };
+
+            if token.token == PpToken::Eof {
+                if let Some(conditional) = self.conditionals.pop() {
+                    self.lookahead = Some(token);
+
+                    let directive = if conditional.seen_else { "else" } else { &conditional.directive };
+                    return Err(CcError::err_with_loc(format!("unterminated #{}", directive), conditional.loc));
+                }
+            }
+
+            //
+            // The token is put back when cancelled, so that every call after
+            // stops at the same place.
+            //
+            let is_directive = token.token == PpToken::Hash && token.starts_line;
+            if is_directive || self.file != Some(token.loc.file) {
+                if let Some(reason) = self.cancel.as_ref().and_then(|cancel| cancel.reason()) {
+                    let e = CcError::cancelled(reason.to_string(), token.loc);
+                    self.lookahead = Some(token);
+                    return Err(e);
+                }
+            }
+
+            self.file = Some(token.loc.file);
+
+            if is_directive {
+                self.directive(source, token, warnings)?;
+                continue;
+            }
+
+            if self.skipping() {
+                continue;
+            }
+
+            if let PpToken::Identifier(name) = &token.token {
+                if self.macros.is_defined(name) {
+                    #[cfg(feature = "tracing")]
+                    let _span = tracing::trace_span!(parent: source.span(), "expand", name = name.as_str()).entered();
+
+                    let (starts_line, space_before) = (token.starts_line, token.space_before);
+
+                    //
+                    // As above, the token is put back when cancelled.
+                    //
+                    let mut expansion = Vec::new();
+                    if let Err(e) = self.macros.expand(token.clone(), &self.options, &mut expansion) {
+                        if e.cancelled {
+                            self.lookahead = Some(token);
+                        }
+                        return Err(e);
+                    }
+
+                    if expansion.is_empty() {
+                        self.carried.0 |= starts_line;
+                        self.carried.1 |= space_before;
+                    }
+
+                    self.pending.extend(expansion);
+                    continue;
+                }
+            }
+
+            return Ok(self.carry(token));
+        }
+    }
+
+    /// Is the current line in a group which is being skipped?
+    ///
+    fn skipping(&self) -> bool {
+        self.conditionals.last().is_some_and(|conditional| conditional.branch != Branch::Taken)
+    }
+
+    /// Once `cancel` is cancelled, `next_token` returns a cancelled error
+    /// at the next file or directive boundary or macro expansion, and every
+    /// time after.
+    ///
+    pub fn set_cancel(&mut self, cancel: CancelToken) {
+        self.macros.set_cancel(cancel.clone());
+        self.cancel = Some(cancel);
+    }
+
+    /// Keep every #if and #elif condition which is evaluated from now on,
+    /// to be returned by `take_conditions`. Conditions in skipped groups
+    /// aren't evaluated, so aren't kept.
+    ///
+    pub fn record_conditions(&mut self) {
+        self.conditions.get_or_insert_with(Vec::new);
+    }
+
+    /// Return the conditions kept since `record_conditions`, in the order
+    /// they were evaluated.
+    ///
+    pub fn take_conditions(&mut self) -> Vec<Condition> {
+        self.conditions.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Give a token the line start and spacing carried from any macros
+    /// before it which expanded to nothing.
+    ///
+    fn carry(&mut self, token: MetaToken) -> MetaToken {
+        let (starts_line, space_before) = std::mem::take(&mut self.carried);
+
+        MetaToken {
+            starts_line: token.starts_line || starts_line,
+            space_before: token.space_before || space_before,
+            ..token
+        }
+    }
+
+    /// Get the next token from the lexer.
+    ///
+    fn lex(&mut self, source: &mut Source) -> Result<MetaToken, CcError> {
+        match self.lookahead.take() {
+            Some(token) => Ok(token),
+            None => {
+                let token = lexer::next_token_with(source, &mut self.emit, &self.options);
+                self.emit.clear();
+                token
+            },
+        }
+    }
+
+    /// Get the rest of the current line.
+    ///
+    fn line(&mut self, source: &mut Source) -> Result<Vec<MetaToken>, CcError> {
+        let mut line = Vec::new();
+
+        loop {
+            let token = match self.lex(source) {
+                Err(_) if self.skipping() => continue,
+                token => token?,
+            };
+
+            if token.starts_line || token.token == PpToken::Eof {
+                self.lookahead = Some(token);
+                return Ok(line);
+            }
+
+            line.push(token);
+        }
+    }
+
+    /// Process a directive, given the `#` which starts it.
+    ///
+    fn directive(&mut self, source: &mut Source, hash: MetaToken, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        let line = self.line(source)?;
+
+        let name = match line.first() {
+            Some(MetaToken { token: PpToken::Identifier(name), .. }) => name.as_str(),
+            Some(_) if self.skipping() => return Ok(()),
+            Some(token) => {
+                return Err(CcError::err_with_loc(
+                    format!("invalid preprocessing directive #{}", token.token.spelling()),
+                    token.loc
+                ));
+            },
+            None => return Ok(()),
+        };
+
+        if CONDITIONAL_DIRECTIVES.contains(&name) {
+            return self.conditional(name, &line[1..], hash.loc, warnings);
+        }
+
+        if self.skipping() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(parent: source.span(), "directive", name).entered();
+
+        match name {
+            "define" => self.macros.define_directive(&line[1..], hash.loc, warnings),
+            "undef" => self.macros.undef_directive(&line[1..], hash.loc, warnings),
+            "error" => Err(CcError::err_with_loc(format!("#error {}", spell(&line[1..])), hash.loc)),
+            "warning" => {
+                warnings.push(CcError::err_with_loc(format!("#warning {}", spell(&line[1..])), hash.loc));
+                Ok(())
+            },
+            "pragma" => {
+                self.pending.push_back(hash);
+                self.pending.extend(line);
+                Ok(())
+            },
+            name if UNSUPPORTED_DIRECTIVES.contains(&name) => {
+                Err(CcError::err_with_loc(format!("#{} is not supported yet", name), hash.loc))
+            },
+            name => Err(CcError::err_with_loc(format!("invalid preprocessing directive #{}", name), line[0].loc)),
+        }
+    }
+
+    /// Process one of the conditional directives, given the tokens after
+    /// the directive name.
+    ///
+    fn conditional(&mut self, name: &str, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<(), CcError> {
+        if matches!(name, "if" | "ifdef" | "ifndef") {
+            if self.skipping() {
+                self.push_conditional(name, loc, Branch::Done);
+                return Ok(());
+            }
+
+            //
+            // A condition which can't be evaluated is taken as false, so the
+            // group still has to be pushed to match its #else and #endif.
+            //
+            let taken = self.test(name, tokens, loc, warnings);
+            self.push_conditional(name, loc, if matches!(taken, Ok(true)) { Branch::Taken } else { Branch::Waiting });
+            return taken.map(|_| ());
+        }
+
+        let Some(top) = self.conditionals.last_mut() else {
+            return Err(CcError::err_with_loc(format!("#{} without #if", name), loc));
+        };
+
+        if top.seen_else && name != "endif" {
+            return Err(CcError::err_with_loc(format!("#{} after #else", name), loc));
+        }
+
+        if matches!(name, "else" | "endif") && !tokens.is_empty() && !top.outer_skipped {
+            warnings.push(CcError::err_with_loc(format!("extra tokens at end of #{} directive", name), tokens[0].loc));
+        }
+
+        if matches!(name, "elifdef" | "elifndef") && !top.outer_skipped && !self.standard.has_elifdef() {
+            let since = if self.standard.is_cxx() { "C++23" } else { "C23" };
+            warnings.push(CcError::err_with_loc(format!("#{} before {} is an extension", name, since), loc));
+        }
+
+        match name {
+            "elif" | "elifdef" | "elifndef" => match top.branch {
+                Branch::Taken => top.branch = Branch::Done,
+                Branch::Done => {},
+                Branch::Waiting => {
+                    let taken = self.test(name, tokens, loc, warnings)?;
+                    if taken {
+                        self.conditionals.last_mut().unwrap().branch = Branch::Taken;
+                    }
+                },
+            },
+            "else" => {
+                top.seen_else = true;
+                top.branch = if top.branch == Branch::Waiting { Branch::Taken } else { Branch::Done };
+            },
+            _ => {
+                self.conditionals.pop();
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Start an #if group.
+    ///
+    fn push_conditional(&mut self, directive: &str, loc: Point, branch: Branch) {
+        let outer_skipped = self.skipping();
+
+        self.conditionals.push(Conditional {
+            directive: directive.to_string(),
+            loc,
+            branch,
+            seen_else: false,
+            outer_skipped,
+        });
+    }
+
+    /// Test the condition of a directive which starts or continues an #if
+    /// group, given the tokens after the directive name.
+    ///
+    fn test(&mut self, directive: &str, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<bool, CcError> {
+        match directive {
+            "if" | "elif" => self.condition(directive, tokens, loc, warnings),
+            _ => {
+                let name = tested_name(directive, tokens, loc, warnings)?;
+                Ok(self.macros.is_defined(name) != directive.ends_with("ndef"))
+            },
+        }
+    }
+
+    /// Evaluate the condition of an #if or #elif, given the tokens after
+    /// the directive name, keeping it if conditions are being recorded.
+    ///
+    fn condition(&mut self, directive: &str, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<bool, CcError> {
+        let (expanded, value) = self.evaluate(tokens, loc, warnings)?;
+
+        if let Some(conditions) = &mut self.conditions {
+            conditions.push(Condition { directive: directive.to_string(), loc, expanded, value });
+        }
+
+        Ok(value.is_true())
+    }
+
+    /// Evaluate a controlling expression as #if would with the macros
+    /// defined so far: `defined` is replaced, then macros are expanded.
+    /// Returns the expanded tokens and the value. `loc` is the location of
+    /// the directive, for errors at the end of the expression.
+    ///
+    pub fn evaluate(&self, tokens: &[MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<(Vec<MetaToken>, Value), CcError> {
+        warnings.extend(tokens.iter().flat_map(lexer::unknown_escapes));
+
+        let mut expanded = Vec::new();
+        for token in expr::replace_defined(tokens, &self.macros)? {
+            self.macros.expand(token, &self.options, &mut expanded)?;
+        }
+
+        let booleans = self.options.language == Language::Cxx || self.standard >= Standard::C23;
+        let value = expr::evaluate(&expanded, booleans, loc, warnings)?;
+        Ok((expanded, value))
+    }
+}
+
+/// Get the macro name tested by #ifdef, #ifndef, #elifdef or #elifndef,
+/// warning about anything after it.
+///
+fn tested_name<'a>(directive: &str, tokens: &'a [MetaToken], loc: Point, warnings: &mut Vec<CcError>) -> Result<&'a str, CcError> {
+    let name = match tokens.first() {
+        Some(MetaToken { token: PpToken::Identifier(name), .. }) => name,
+        Some(token) => return Err(CcError::err_with_loc("macro names must be identifiers".to_string(), token.loc)),
+        None => return Err(CcError::err_with_loc(format!("no macro name given in #{} directive", directive), loc)),
+    };
+
+    if let Some(extra) = tokens.get(1) {
+        warnings.push(CcError::err_with_loc(format!("extra tokens at end of #{} directive", directive), extra.loc));
+    }
+
+    Ok(name)
+}
+
+/// Spell a sequence of tokens on one line, with a space wherever there was
+/// whitespace between them.
+///
+pub fn spell(tokens: &[MetaToken]) -> String {
+    let mut text = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        if i != 0 && token.space_before {
+            text.push(' ');
+        }
+        text.push_str(&token.token.spelling());
+    }
+
+    text
+}
+
+/// Whether two tokens written next to each other would lex as something
+/// else, and so need a space between them.
+///
+pub fn avoid_paste(prev: &PpToken, next: &PpToken) -> bool {
+    let prev_spelling = prev.spelling();
+    let next_spelling = next.spelling();
+
+    let (Some(last), Some(first)) = (prev_spelling.chars().last(), next_spelling.chars().next()) else {
+        return false;
+    };
+
+    let is_word = |ch: char| ch.is_ascii_alphanumeric() || ch == '_';
+
+    match prev {
+        PpToken::Identifier(_) => is_word(first) || first == '\'' || first == '"',
+        PpToken::Number(_) => {
+            is_word(first)
+                || first == '.'
+                || ((first == '+' || first == '-') && matches!(last, 'e' | 'E' | 'p' | 'P'))
+        },
+        PpToken::Dot => first.is_ascii_digit() || starts_operator_with(&prev_spelling, first),
+        PpToken::Divide => first == '/' || first == '*' || starts_operator_with(&prev_spelling, first),
+        PpToken::CharLiteral(..) | PpToken::StringLiteral(..) | PpToken::RawStringLiteral(..)
+            | PpToken::Other(_) | PpToken::Eof => false,
+        _ => starts_operator_with(&prev_spelling, first),
+    }
+}
+
+/// Whether an operator followed by a character is the start of a longer
+/// operator.
+///
+fn starts_operator_with(op: &str, ch: char) -> bool {
+    lexer::starts_operator(&format!("{}{}", op, ch))
+}
+
+/// Writes preprocessed tokens as text, keeping each token on the line it
+/// came from so that line numbers in the output match the source.
+///
+#[derive(Debug, Default)]
+pub struct TextWriter {
+    /// The line of the source the output is on.
+    line: u32,
+
+    /// The last token written.
+    prev: Option<PpToken>,
+
+    /// Where a line of the source was continued with a backslash-newline,
+    /// write one too rather than joining the lines, so that every physical
+    /// line of the source is a line of the output.
+    pub keep_splices: bool,
+}
+
+impl TextWriter {
+    pub fn new() -> Self {
+        TextWriter::default()
+    }
+
+    /// Append a token to `out`, after whatever newlines and spacing it
+    /// needs.
+    ///
+    pub fn write(&mut self, token: &MetaToken, out: &mut String) {
+        match &self.prev {
+            None => {
+                self.line = 1;
+                self.newline(token, out);
+            },
+            Some(_) if token.starts_line => self.newline(token, out),
+            Some(prev) => {
+                let space = token.space_before || avoid_paste(prev, &token.token);
+
+                if self.keep_splices && self.line < token.loc.line {
+                    //
+                    // The indent separates the tokens after the splice, unless
+                    // there isn't one.
+                    //
+                    if space && token.loc.col == 1 {
+                        out.push(' ');
+                    }
+
+                    while self.line < token.loc.line {
+                        out.push_str("\\\n");
+                        self.line += 1;
+                    }
+
+                    indent(token, out);
+                } else if space {
+                    out.push(' ');
+                }
+            },
+        }
+
+        let spelling = token.token.spelling();
+        self.line += spelling.matches('\n').count() as u32;
+        out.push_str(&spelling);
+
+        self.prev = Some(token.token.clone());
+    }
+
+    /// Finish the output with a newline, if anything was written.
+    ///
+    pub fn finish(&mut self, out: &mut String) {
+        if self.prev.is_some() {
+            out.push('\n');
+        }
+    }
+
+    /// Start a new line for a token, moving down to the token's line and
+    /// indenting it to its column.
+    ///
+    fn newline(&mut self, token: &MetaToken, out: &mut String) {
+        if self.prev.is_some() {
+            out.push('\n');
+            self.line += 1;
+        }
+
+        while self.line < token.loc.line {
+            out.push('\n');
+            self.line += 1;
+        }
+
+        indent(token, out);
+    }
+}
+
+/// Indent the output to a token's column.
+///
+fn indent(token: &MetaToken, out: &mut String) {
+    for _ in 1..token.loc.col {
+        out.push(' ');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn preprocess(text: &str) -> Result<(String, Vec<String>), CcError> {
+        preprocess_with(text, TextWriter::new())
+    }
+
+    fn preprocess_with(text: &str, writer: TextWriter) -> Result<(String, Vec<String>), CcError> {
+        preprocess_as(text, Preprocessor::new(LexOptions::default(), Standard::default()), writer)
+    }
+
+    fn preprocess_as(text: &str, mut pp: Preprocessor, mut writer: TextWriter) -> Result<(String, Vec<String>), CcError> {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), text.chars().collect());
+
+        let mut warnings = Vec::new();
+        let mut out = String::new();
+
+        loop {
+            match pp.next_token(&mut source, &mut warnings)? {
+                MetaToken { token: PpToken::Eof, .. } => break,
+                token => writer.write(&token, &mut out),
+            }
+        }
+
+        writer.finish(&mut out);
+        Ok((out, warnings.into_iter().map(|w| w.what).collect()))
+    }
+
+    #[test]
+    fn can_be_sent() {
+        fn assert_send<T: Send>() {}
+
+        assert_send::<Preprocessor>();
+    }
+
+    #[test]
+    fn expands_defined_macros() -> Result<(), CcError> {
+        let (out, warnings) = preprocess("#define N 10\nint a[N];\n#undef N\nint b[N];\n")?;
+
+        assert_eq!(out, "\nint a[10];\n\nint b[N];\n");
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_lines_and_spacing() -> Result<(), CcError> {
+        let (out, _) = preprocess("#define PLUS +\n#define E\n\n  a = +PLUS b /* c */E- -x;\n#pragma once\n")?;
+
+        assert_eq!(out, "\n\n\n  a = + + b - -x;\n#pragma once\n");
+
+        let (out, _) = preprocess("#define E
+a
+E b
+")?;
+        assert_eq!(out, "\na\n  b\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_splices() -> Result<(), CcError> {
+        let text = "int a = 1 +\\\n    2, b\\\n;\n#define X \\\n 3\nX\n";
+
+        let (out, _) = preprocess(text)?;
+        assert_eq!(out, "int a = 1 + 2, b;\n\n\n\n\n3\n");
+
+        let mut writer = TextWriter::new();
+        writer.keep_splices = true;
+
+        let (out, _) = preprocess_with(text, writer)?;
+        assert_eq!(out, "int a = 1 +\\\n    2, b\\\n;\n\n\n3\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagnoses_directives() -> Result<(), CcError> {
+        assert_eq!(preprocess("#\n#warning careful  now\n")?.1, vec!["#warning careful now"]);
+        assert_eq!(preprocess("#error stop").unwrap_err().what, "#error stop");
+        assert_eq!(preprocess("#frobnicate").unwrap_err().what, "invalid preprocessing directive #frobnicate");
+        assert_eq!(preprocess("#include <a.h>").unwrap_err().what, "#include is not supported yet");
+
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_conditionals() -> Result<(), CcError> {
+        let (out, warnings) = preprocess("\
+#define A 2
+#if A > 1
+a
+#elif 1 / 0
+b
+#else
+c
+#endif
+#if 0
+# error don't
+#ifdef X junk
+#else junk
+#endif
+'unterminated
+#elif defined A && !defined(B)
+d
+#else
+e
+#endif
+")?;
+
+        assert_eq!(out, "\n\na\n\n\n\n\n\n\n\n\n\n\n\n\nd\n");
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_expressions() -> Result<(), CcError> {
+        let mut pp = Preprocessor::new(LexOptions::default(), Standard::default());
+        let mut warnings = Vec::new();
+        pp.macros.define_option("A=B * 2", &mut warnings)?;
+        pp.macros.define_option("B=3", &mut warnings)?;
+
+        let loc = Point { file: 0, line: 1, col: 1 };
+        let (expanded, value) = pp.evaluate(&lexer::lex("A + defined(C)")?, loc, &mut warnings)?;
+
+        assert_eq!(expanded.iter().map(|token| token.token.spelling()).collect::<Vec<_>>(), ["3", "*", "2", "+", "0"]);
+        assert_eq!(value, Value::Signed(6));
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_evaluated_conditions() -> Result<(), CcError> {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), "#define A 2\n#if A > 2\n#if 1\n#endif\n#elif A\n#endif\n".chars().collect());
+
+        let mut pp = Preprocessor::new(LexOptions::default(), Standard::default());
+        pp.record_conditions();
+
+        let mut warnings = Vec::new();
+        while pp.next_token(&mut source, &mut warnings)?.token != PpToken::Eof {}
+
+        let conditions = pp.take_conditions();
+        let summary: Vec<_> = conditions.iter()
+            .map(|condition| (condition.directive.as_str(), condition.loc.line, spell(&condition.expanded), condition.value))
+            .collect();
+        assert_eq!(summary, [("if", 2, "2 > 2".to_string(), Value::Signed(0)), ("elif", 5, "2".to_string(), Value::Signed(2))]);
+        assert!(pp.take_conditions().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn diagnoses_conditionals() -> Result<(), CcError> {
+        assert_eq!(preprocess("#if 1\n").unwrap_err().what, "unterminated #if");
+        assert_eq!(preprocess("#if 0\n#else\n").unwrap_err().what, "unterminated #else");
+        assert_eq!(preprocess("#else\n").unwrap_err().what, "#else without #if");
+        assert_eq!(preprocess("#endif\n").unwrap_err().what, "#endif without #if");
+        assert_eq!(preprocess("#if 1\n#else\n#elif 1\n#endif\n").unwrap_err().what, "#elif after #else");
+        assert_eq!(preprocess("#if\n#endif\n").unwrap_err().what, "#if with no expression");
+
+        let (_, warnings) = preprocess("#if 1\n#else x\n#endif y\n")?;
+        assert_eq!(warnings, vec!["extra tokens at end of #else directive", "extra tokens at end of #endif directive"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tests_macro_names() -> Result<(), CcError> {
+        let (out, warnings) = preprocess("\
+#define A
+#ifdef A
+a
+#endif
+#ifndef A
+b
+#elifndef B
+c
+#endif
+#ifdef B
+d
+#elifdef A
+e
+#elifdef A
+f
+#endif
+")?;
+
+        assert_eq!(out, "\n\na\n\n\n\n\nc\n\n\n\n\ne\n");
+        assert_eq!(warnings, vec!["#elifndef before C23 is an extension", "#elifdef before C23 is an extension", "#elifdef before C23 is an extension"]);
+
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), "#ifdef A\n#elifdef B\n#endif\n".chars().collect());
+
+        let mut pp = Preprocessor::new(LexOptions::default(), Standard::C23);
+        let mut warnings = Vec::new();
+        assert_eq!(pp.next_token(&mut source, &mut warnings)?.token, PpToken::Eof);
+        assert!(warnings.is_empty());
+
+        let cxx = |standard| Preprocessor::new(LexOptions { language: Language::Cxx, ..Default::default() }, standard);
+        let text = "#ifdef A\n#elifdef B\n#endif\n";
+        assert_eq!(preprocess_as(text, cxx(Standard::Cxx20), TextWriter::new())?.1, vec!["#elifdef before C++23 is an extension"]);
+        assert!(preprocess_as(text, cxx(Standard::Cxx23), TextWriter::new())?.1.is_empty());
+
+        assert_eq!(preprocess("#ifdef\n#endif\n").unwrap_err().what, "no macro name given in #ifdef directive");
+        assert_eq!(preprocess("#ifndef 1\n#endif\n").unwrap_err().what, "macro names must be identifiers");
+        assert_eq!(preprocess("#ifdef A B\n#endif\n")?.1, vec!["extra tokens at end of #ifdef directive"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocesses_cxx() -> Result<(), CcError> {
+        let cxx = || Preprocessor::new(LexOptions { language: Language::Cxx, ..Default::default() }, Standard::Cxx20);
+
+        let (out, _) = preprocess_as("#if __cplusplus == 202002L and not 0\na and b\n#endif\n", cxx(), TextWriter::new())?;
+        assert_eq!(out, "\na and b\n");
+
+        let (out, _) = preprocess_as("#if 1 and 0\nx\n#elif 1 bitor 0\ny\n#endif\n", cxx(), TextWriter::new())?;
+        assert_eq!(out, "\n\n\ny\n");
+
+        let (out, _) = preprocess("#ifdef __cplusplus\nx\n#endif\n")?;
+        assert_eq!(out, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn stops_when_cancelled() -> Result<(), CcError> {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), "a b\n#define X\nc\n".chars().collect());
+
+        let cancel = CancelToken::new();
+        let mut pp = Preprocessor::new(LexOptions::default(), Standard::default());
+        pp.set_cancel(cancel.clone());
+
+        let mut warnings = Vec::new();
+        assert_eq!(pp.next_token(&mut source, &mut warnings)?.token, PpToken::Identifier("a".to_string()));
+
+        cancel.cancel();
+        assert_eq!(pp.next_token(&mut source, &mut warnings)?.token, PpToken::Identifier("b".to_string()));
+
+        for _ in 0..2 {
+            let e = pp.next_token(&mut source, &mut warnings).unwrap_err();
+            assert!(e.cancelled);
+            assert_eq!(e.to_string(), "2:1: preprocessing cancelled");
+        }
+
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("abc"), "#define X 1\nX X\n".chars().collect());
+
+        let cancel = CancelToken::new();
+        let mut pp = Preprocessor::new(LexOptions::default(), Standard::default());
+        pp.set_cancel(cancel.clone());
+
+        assert_eq!(pp.next_token(&mut source, &mut warnings)?.token, PpToken::Number("1".to_string()));
+
+        cancel.cancel();
+        for _ in 0..2 {
+            let e = pp.next_token(&mut source, &mut warnings).unwrap_err();
+            assert_eq!(e.to_string(), "2:3: preprocessing cancelled while expanding macro 'X'");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn avoids_pasting_tokens() {
+        let id = |s: &str| PpToken::Identifier(s.to_string());
+        let num = |s: &str| PpToken::Number(s.to_string());
+
+        assert!(avoid_paste(&id("a"), &id("b")));
+        assert!(avoid_paste(&PpToken::Add, &PpToken::Add));
+        assert!(avoid_paste(&PpToken::Less, &PpToken::LessEqual));
+        assert!(avoid_paste(&PpToken::Divide, &PpToken::Star));
+        assert!(avoid_paste(&num("1e"), &PpToken::Add));
+        assert!(avoid_paste(&PpToken::Dot, &num("5")));
+        assert!(!avoid_paste(&id("a"), &PpToken::Add));
+        assert!(!avoid_paste(&PpToken::Add, &PpToken::Subtract));
+        assert!(!avoid_paste(&num("1"), &PpToken::Add));
+        assert!(!avoid_paste(&PpToken::RightParen, &PpToken::Semicolon));
+    }
+}
@@ -3,11 +3,18 @@
 // characters with source location attached.
 //
 use crate::ccerror::CcError;
-use std::path::PathBuf;
+use crate::charset::{self, Charset};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use unicode_width::UnicodeWidthChar;
 
 /// A location in the source code, for errors.
 /// 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Point {
     /// The index of the source file the token came from.
     pub file: u32,
@@ -15,10 +22,40 @@ pub struct Point {
     /// The 1-based line number in the source.
     pub line: u32,
 
-    /// The 1-based column in the source.
+    /// The 1-based display column in the source. Columns are counted in
+    /// terminal cells, not characters; see `column_width`.
     pub col: u32,
 }
 
+/// The number of columns `ch` occupies when displayed.
+/// 
+/// Combining marks and other zero-width characters share the column of the
+/// character they modify, and East Asian wide characters take two columns, so
+/// that a column in a diagnostic lines up with what the user sees in an editor.
+/// Control characters such as tab count as a single column.
+/// 
+pub fn column_width(ch: char) -> u32 {
+    ch.width().unwrap_or(1) as u32
+}
+
+/// Whether two file names refer to the same file, as far as can be told
+/// from the names alone.
+/// 
+/// Windows and macOS file systems ignore case by default, so names which
+/// differ only in case are the same file there; Windows also accepts either
+/// slash as a separator.
+/// 
+pub fn same_path(a: &Path, b: &Path) -> bool {
+    if cfg!(windows) {
+        let fold = |path: &Path| path.to_string_lossy().replace('/', "\\").to_lowercase();
+        fold(a) == fold(b)
+    } else if cfg!(target_os = "macos") {
+        a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+    } else {
+        a == b
+    }
+}
+
 /// The source code from one file.
 /// 
 pub struct SourceFile {
@@ -30,6 +67,85 @@ pub struct SourceFile {
 
     /// The contents of the source file.
     pub text: Vec<char>,
+
+    /// Did the file start with a byte order mark? The mark itself is not
+    /// part of `text`, but is remembered so output can preserve it.
+    pub bom: bool,
+
+    /// The canonical path of the file, if it was read from disk, for telling
+    /// whether two names refer to the same file. A buffer such as the
+    /// command line has none, even if a file of the same name exists.
+    pub canonical: Option<PathBuf>,
+
+    /// A hash of `text`.
+    pub hash: u64,
+}
+
+/// The deepest that files may be nested.
+pub const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// The Unicode byte order mark.
+pub const BOM: char = '\u{feff}';
+
+impl SourceFile {
+    /// Construct from a name and the file contents. A leading byte order
+    /// mark is removed from the text.
+    /// 
+    pub fn new(name: &Path, mut text: Vec<char>) -> Self {
+        let bom = text.first() == Some(&BOM);
+        if bom {
+            text.remove(0);
+        }
+
+        SourceFile {
+            name: name.to_path_buf(),
+            strname: name.to_string_lossy().to_string(),
+            canonical: None,
+            hash: hash_text(&text),
+            text,
+            bom,
+        }
+    }
+
+    /// The number of lines in the file. As when reading it, CR, LF, CR/LF
+    /// and LF/CR each end one line. A last line without a newline still
+    /// counts.
+    /// 
+    pub fn line_count(&self) -> usize {
+        let text = &self.text;
+        let mut lines = 0;
+        let mut partial = false;
+        let mut i = 0;
+
+        while i < text.len() {
+            match (text[i], text.get(i + 1)) {
+                ('\r', Some('\n')) | ('\n', Some('\r')) => {
+                    lines += 1;
+                    partial = false;
+                    i += 2;
+                },
+                ('\r' | '\n', _) => {
+                    lines += 1;
+                    partial = false;
+                    i += 1;
+                },
+                _ => {
+                    partial = true;
+                    i += 1;
+                },
+            }
+        }
+
+        lines + partial as usize
+    }
+}
+
+/// Hash the text of a file.
+/// 
+fn hash_text(text: &[char]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
 }
 
 /// A pointer for iterating through a source file.
@@ -60,11 +176,14 @@ pub struct SourceChar {
     pub switched: bool,
 }
 
-/// The state for reading characters across all source files.
+/// The state for reading characters across all source files. A Source can
+/// be moved to another thread, and the files it has read can be shared
+/// with Sources on other threads.
 /// 
 pub struct Source {
-    /// All files, indexed by a file integer.
-    pub files: Vec<SourceFile>,
+    /// All files, indexed by a file integer. Files are never changed once
+    /// read, so they may be shared.
+    pub files: Vec<Arc<SourceFile>>,
 
     /// Nested stack of file pointers. The first will be the main
     /// source file.
@@ -73,11 +192,22 @@ pub struct Source {
     /// The current file changed, but a character has not been read
     /// from it yet.
     pub switched: bool, 
+
+    /// The encoding of files read from disk.
+    pub charset: Charset,
+
+    /// The total size of files read from disk, in bytes.
+    pub bytes_read: usize,
+
+    /// A span for each file in `iters`, open while the file is being read.
+    #[cfg(feature = "tracing")]
+    spans: Vec<tracing::Span>,
 }
 
 /// An iterator to take source characters while a predicate is true. Unlike
 /// the built-in take_while, the character past the iterated range is not
-/// consumed.
+/// consumed. Line splices are skipped, so a backslash-newline never ends
+/// the range.
 /// 
 pub struct TakeWhile<'a, F> 
     where F: Fn(char) -> bool
@@ -95,7 +225,7 @@ impl<F> Iterator for TakeWhile<'_, F>
     type Item = SourceChar;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.source.peek() {
+        match self.source.peek_unspliced() {
             Some(sch) => {
                 if (self.pred)(sch.ch) {
                     self.source.next()
@@ -115,105 +245,212 @@ impl<F> Iterator for TakeWhile<'_, F>
     }
 }
 
+/// Like TakeWhile, but returns at most `maxlen` characters.
+/// 
+pub struct TakeWhileN<'a, F> 
+    where F: Fn(char) -> bool
+{
+    /// The condition for iterating.
+    pred: F, 
+
+    /// The maximum number of characters to return.
+    maxlen: u32,
+
+    /// The character count returned so far.
+    so_far: u32,
+
+    /// The source code.
+    source: &'a mut Source,
+}
+
+impl<F> Iterator for TakeWhileN<'_, F> 
+    where F: Fn(char) -> bool
+{
+    type Item = SourceChar;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.so_far >= self.maxlen {
+            return None;
+        }
+
+        let sch = TakeWhile { pred: &self.pred, source: self.source }.next()?;
+        self.so_far += 1;
+        Some(sch)
+    }
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::new()
+    }
+}
+
 impl Source {
     pub fn new() -> Source {
         Source {
             files: Vec::new(),
             iters: Vec::new(),
             switched: false,
+            charset: Charset::Auto,
+            bytes_read: 0,
+            #[cfg(feature = "tracing")]
+            spans: Vec::new(),
         }
     }
 
     pub fn push_file(&mut self, name: &PathBuf) -> Result<(), CcError> {
+        if self.iters.len() >= MAX_INCLUDE_DEPTH {
+            let what = match self.include_cycle(name) {
+                Some(cycle) => format!("#include nested too deeply, through the cycle {}", cycle.join(" -> ")),
+                None => format!("#include nested more than {} deep", MAX_INCLUDE_DEPTH),
+            };
+            return Err(CcError::new(what));
+        }
+
         //
         // Did we already read this file?
         //
-        match self.files.iter().enumerate().find(|(_, sf)| sf.name == *name) {
-            Some((file, _)) => {
-                let ptr = SourcePointer {
+        if let Some((file, _)) = self.files.iter().enumerate().find(|(_, sf)| same_path(&sf.name, name)) {
+            let ptr = SourcePointer {
+                file: file as u32,
+                next: 0,
+                next_loc: Point {
                     file: file as u32,
-                    next: 0,
-                    next_loc: Point {
-                        file: file as u32,
-                        line: 1, 
-                        col: 1
-                    }
-                };
+                    line: 1, 
+                    col: 1
+                }
+            };
 
-                self.iters.push(ptr);
-                self.switched = true;
-                return Ok(())
-            },
-            None => {},
-        };
+            self.push_pointer(ptr);
+            return Ok(())
+        }
 
         //
         // No, read a new file.
         //
-        let text = std::fs::read_to_string(name)?;
-        let text = text.chars().collect();
-        let file = self.files.len() as u32;
-
-        self.files.push(SourceFile{ 
-            name: name.clone(),
-            strname: name.to_string_lossy().to_string(), 
-            text });
-
-        let ptr = SourcePointer {
-            file: file as u32,
-            next: 0,
-            next_loc: Point {
-                file: file as u32,
-                line: 1, 
-                col: 1
-            }
-        };
+        let bytes = std::fs::read(name)?;
+        self.push_bytes(name, &bytes)
+    } 
 
-        self.iters.push(ptr);
-        self.switched = true;
+    /// Decode the contents of the file `name`, already read from disk, in
+    /// the source's charset, and start reading it. It's an error if the
+    /// bytes aren't valid in the charset.
+    /// 
+    pub fn push_bytes(&mut self, name: &PathBuf, bytes: &[u8]) -> Result<(), CcError> {
+        let text = charset::decode(bytes, self.charset)?;
+        self.bytes_read += bytes.len();
 
-        self.pop_nested();
+        let mut sf = SourceFile::new(name, text);
+        sf.canonical = std::fs::canonicalize(name).ok();
+        self.push_shared(Arc::new(sf));
 
         Ok(())
-    } 
+    }
+
+    pub fn push_data(&mut self, name: &Path, text: Vec<char>) {
+        self.push_shared(Arc::new(SourceFile::new(name, text)));
+    }
 
-    pub fn push_data(&mut self, name: &PathBuf, text: Vec<char>) {
+    /// Start reading a file which may also be in use by other Sources, such
+    /// as a header read once and shared between threads.
+    /// 
+    pub fn push_shared(&mut self, sf: Arc<SourceFile>) {
         let file = self.files.len() as u32;
 
-        self.files.push(SourceFile{ 
-            name: name.clone(),
-            strname: name.to_string_lossy().to_string(), 
-            text });
+        self.files.push(sf);
 
         let ptr = SourcePointer {
-            file: file as u32,
+            file,
             next: 0,
             next_loc: Point {
-                file: file as u32,
+                file,
                 line: 1, 
                 col: 1
             }
         };
 
+        self.push_pointer(ptr);
+        self.pop_nested();
+    }
+
+    /// Start reading a file from a pointer into it.
+    /// 
+    fn push_pointer(&mut self, ptr: SourcePointer) {
+        #[cfg(feature = "tracing")]
+        self.spans.push(tracing::debug_span!("file", name = %self.files[ptr.file as usize].strname));
+
         self.iters.push(ptr);
         self.switched = true;
-
-        self.pop_nested();
     }
 
     fn pop_nested(&mut self) {
-        loop {
-            match self.iters.last() {
-                Some(sp) => {
-                    if sp.next < self.files[sp.file as usize].text.len() {
-                        break;
-                    }
-                    self.switched = true;
-                    self.iters.pop();
-                },
-                None => break,
+        while let Some(sp) = self.iters.last() {
+            if sp.next < self.files[sp.file as usize].text.len() {
+                break;
             }
+            self.switched = true;
+            self.iters.pop();
+        }
+
+        //
+        // Dropping a file's span closes it.
+        //
+        #[cfg(feature = "tracing")]
+        self.spans.truncate(self.iters.len());
+    }
+
+    /// The span of the file being read, for the spans of the work done on
+    /// it to be nested in.
+    /// 
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> tracing::Span {
+        self.spans.last().cloned().unwrap_or_else(tracing::Span::none)
+    }
+
+    /// Find a file already read which is the same file as `name`, for
+    /// #pragma once. Besides the same name, this is a file with the same
+    /// canonical path, reached through a symlink, `..` or another include
+    /// directory, or failing that, a file with the same contents.
+    /// 
+    pub fn find_same_file(&self, name: &Path) -> Option<u32> {
+        if let Some(file) = self.files.iter().position(|sf| same_path(&sf.name, name)) {
+            return Some(file as u32);
+        }
+
+        let canonical = std::fs::canonicalize(name).ok()?;
+        if let Some(file) = self.files.iter().position(|sf| sf.canonical.as_ref() == Some(&canonical)) {
+            return Some(file as u32);
         }
+
+        //
+        // The same contents under a different path, such as a copy of a
+        // header installed in two places.
+        //
+        let bytes = std::fs::read(name).ok()?;
+        let file = SourceFile::new(name, charset::decode(&bytes, self.charset).ok()?);
+
+        self.files.iter()
+            .position(|sf| sf.hash == file.hash && sf.text == file.text)
+            .map(|file| file as u32)
+    }
+
+    /// If the file `name` is already open, return the names of the files from
+    /// its outermost open instance through to the current file, then `name`
+    /// again: the cycle that pushing `name` would create.
+    /// 
+    /// A header protected by an include guard may legitimately be pushed
+    /// again, as the guard stops the recursion, so it's up to the caller to
+    /// decide if a cycle is an error.
+    /// 
+    pub fn include_cycle(&self, name: &Path) -> Option<Vec<String>> {
+        let start = self.iters.iter().position(|sp| same_path(&self.files[sp.file as usize].name, name))?;
+
+        let mut cycle: Vec<String> = self.iters[start..].iter()
+            .map(|sp| self.files[sp.file as usize].strname.clone())
+            .collect();
+        cycle.push(name.to_string_lossy().to_string());
+
+        Some(cycle)
     }
 
     /// Get a printable name for a file, by file index.
@@ -236,7 +473,7 @@ impl Source {
             let file = &self.files[sp.file as usize];
             assert!(sp.next < file.text.len());
 
-            let ch = file.text[sp.next as usize];
+            let ch = file.text[sp.next];
             let ch = if ch == '\r' { '\n' } else { ch };
             let pt = sp.next_loc;
 
@@ -291,7 +528,7 @@ impl Source {
                 let file = &self.files[sp.file as usize];
                 assert!(sp.next < file.text.len());
         
-                let ch = file.text[sp.next as usize];
+                let ch = file.text[sp.next];
                 let ch = if ch == '\r' { '\n' } else { ch };
                 let pt = sp.next_loc;
         
@@ -301,6 +538,55 @@ impl Source {
         }
     }
 
+    /// Skip any line splices at the current position, then peek the next
+    /// character.
+    /// 
+    fn peek_unspliced(&mut self) -> Option<SourceChar> {
+        loop {
+            match self.peek() {
+                Some(sch) if sch.ch == '\\' && self.peek_n(1).is_some_and(|next| next.ch == '\n') => {
+                    self.next();
+                    self.next();
+                },
+                sch => return sch,
+            }
+        }
+    }
+
+    /// Return an iterator which will return characters as long as pred(ch)
+    /// is true.
+    /// 
+    /// Call this through a `&mut Source`: on a `Source` value, method lookup
+    /// finds `Iterator::take_while` first.
+    /// 
+    pub fn take_while<F: Fn(char) -> bool>(&mut self, pred: F) -> TakeWhile<'_, F> {
+        TakeWhile {
+            pred,
+            source: self,
+        }
+    }
+
+    /// Return an iterator which will return characters as long as pred(ch)
+    /// is true, for at most n characters.
+    /// 
+    pub fn take_while_n<F: Fn(char) -> bool>(&mut self, pred: F, n: u32) -> TakeWhileN<'_, F> {
+        TakeWhileN {
+            pred,
+            maxlen: n,
+            so_far: 0,
+            source: self,
+        }
+    }
+
+    /// Skip whitespace, including newlines. Returns true if a newline was
+    /// skipped.
+    /// 
+    pub fn skip_whitespace(&mut self) -> bool {
+        self.take_while(|ch| ch.is_ascii_whitespace())
+            .filter(|sch| sch.ch == '\n')
+            .count() != 0
+    }
+
     fn extract_one_char(file: &SourceFile, iter: &SourcePointer) -> (SourcePointer, SourceChar) {
         let mut sp = *iter;
 
@@ -310,7 +596,7 @@ impl Source {
         // Handle CR, LF, CR/LF, LF/CR. The next layer depends on just
         // having \n to compute line splicing.         
         // 
-        let ch = file.text[sp.next as usize];
+        let ch = file.text[sp.next];
         let pt = sp.next_loc;
 
         let ch = match ch {
@@ -318,7 +604,7 @@ impl Source {
                 sp.next += 1;
 
                 if sp.next < file.text.len() {
-                    let next_ch = file.text[sp.next as usize];
+                    let next_ch = file.text[sp.next];
                     if (ch == '\r' && next_ch == '\n') || (ch == '\n' && next_ch == '\r') {
                         sp.next += 1;
                     }
@@ -331,7 +617,7 @@ impl Source {
             },
             ch => {
                 sp.next += 1;
-                sp.next_loc.col += 1;
+                sp.next_loc.col += column_width(ch);
                 ch
             },
         };
@@ -361,11 +647,9 @@ impl Iterator for Source {
             let (sp, ch) = Source::extract_one_char(file, sp);
 
             let ch = if switched {
-                println!("switched");
                 self.switched = false;
                 SourceChar{switched: true, ..ch}
             } else {
-                println!("not switched");
                 ch
             };
 
@@ -384,6 +668,130 @@ impl Iterator for Source {
 mod tests {
     use super::*;
 
+    #[test]
+    fn finds_same_file() -> Result<(), CcError> {
+        let root = std::env::temp_dir().join(format!("cpp-source-same-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("inc"))?;
+        std::fs::write(root.join("inc/a.h"), "int a;\n")?;
+        std::fs::write(root.join("copy.h"), "int a;\n")?;
+        std::fs::write(root.join("b.h"), "int b;\n")?;
+
+        let mut source = Source::new();
+        source.push_file(&root.join("inc/a.h"))?;
+
+        assert_eq!(source.find_same_file(&root.join("inc/a.h")), Some(0));
+        assert_eq!(source.find_same_file(&root.join("inc/../inc/a.h")), Some(0));
+        assert_eq!(source.find_same_file(&root.join("copy.h")), Some(0));
+        assert_eq!(source.find_same_file(&root.join("b.h")), None);
+        assert_eq!(source.find_same_file(&root.join("missing.h")), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("inc"), root.join("link"))?;
+            assert_eq!(source.find_same_file(&root.join("link/a.h")), Some(0));
+        }
+
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn canonicalizes_only_files_read_from_disk() -> Result<(), CcError> {
+        let mut source = Source::new();
+        source.push_file(&PathBuf::from("src/source.rs"))?;
+        source.push_data(Path::new("src/lib.rs"), vec!['a']);
+
+        assert_eq!(source.files[0].canonical, Some(std::fs::canonicalize("src/source.rs")?));
+        assert_eq!(source.files[1].canonical, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts_lines() {
+        let count = |text: &str| SourceFile::new(Path::new("a.c"), text.chars().collect()).line_count();
+
+        assert_eq!(count(""), 0);
+        assert_eq!(count("a"), 1);
+        assert_eq!(count("a\n"), 1);
+        assert_eq!(count("a\r\nb\n\rc\rd"), 4);
+        assert_eq!(count("\n\n"), 2);
+        assert_eq!(count("\r\r\n"), 2);
+    }
+
+    #[test]
+    fn push_bytes_decodes() -> Result<(), CcError> {
+        let mut source = Source::new();
+        source.push_bytes(&PathBuf::from("a.c"), "a\u{e9}".as_bytes())?;
+        assert_eq!(source.files[0].text, vec!['a', '\u{e9}']);
+        assert_eq!(source.bytes_read, 3);
+
+        let e = source.push_bytes(&PathBuf::from("b.c"), b"a\xff").unwrap_err();
+        assert_eq!(e.what, "invalid UTF-8 at byte offset 1");
+        assert_eq!(source.files.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_be_sent() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Send + Sync>() {}
+
+        assert_send::<Source>();
+        assert_sync::<SourceFile>();
+    }
+
+    #[test]
+    fn shares_files() {
+        let sf = Arc::new(SourceFile::new(&PathBuf::from("abc"), vec!['a', 'b']));
+
+        //
+        // Every thread is started before any is joined, so that they run at
+        // the same time.
+        //
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let sf = Arc::clone(&sf);
+                std::thread::spawn(move || {
+                    let mut source = Source::new();
+                    source.push_shared(sf);
+                    source.map(|sch| sch.ch).collect::<String>()
+                })
+            })
+            .collect();
+
+        let chars: Vec<String> = threads.into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+
+        assert_eq!(chars, vec!["ab", "ab"]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn closes_file_spans() {
+        let mut source = Source::new();
+        source.push_data(&PathBuf::from("a"), vec!['a']);
+        source.push_data(&PathBuf::from("b"), vec!['b']);
+        assert_eq!(source.spans.len(), 2);
+
+        source.next();
+        assert_eq!(source.spans.len(), 1);
+
+        source.next();
+        assert!(source.spans.is_empty());
+    }
+
+    #[test]
+    fn compares_paths() {
+        assert!(same_path(Path::new("a/b.h"), Path::new("a/b.h")));
+        assert!(!same_path(Path::new("a/b.h"), Path::new("a/c.h")));
+        assert_eq!(same_path(Path::new("a/b.h"), Path::new("A/B.h")), cfg!(any(windows, target_os = "macos")));
+        assert_eq!(same_path(Path::new("a/b.h"), Path::new("a\\b.h")), cfg!(windows));
+    }
+
     #[test]
     fn gets_characters() -> Result<(), CcError> {
         let mut source = Source::new();
@@ -394,7 +802,7 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 0, line: 1, col: 3 }, switched: false})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
         
         Ok(())
     }
@@ -409,7 +817,7 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 0, line: 2, col: 1 }, switched: false})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
         
         Ok(())
     }
@@ -424,7 +832,7 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 0, line: 2, col: 1 }, switched: false})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
         
         Ok(())
     }
@@ -439,7 +847,7 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 0, line: 2, col: 1 }, switched: false})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
         
         Ok(())
     }
@@ -454,33 +862,58 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 0, line: 2, col: 1 }, switched: false})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
         
         Ok(())
     }
 
     #[test]
-    fn files_nest() -> Result<(), CcError> {
+    fn combining_marks_take_no_column() -> Result<(), CcError> {
         let mut source = Source::new();
-        let text1 = vec!['a', '\n', 'b'];
-        let text2 = vec!['c', 'd', 'e'];
+        let text = vec!['e', '\u{0301}', 'x'];
 
-        source.push_data(&PathBuf::from("abc"), text1);
-        assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
-        assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
-        source.push_data(&PathBuf::from("def"), text2);
-        assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
-        assert!(matches!(source.next(), Some(SourceChar { ch: 'd', pt: Point{ file: 1, line: 1, col: 2 }, switched: false})));
-        assert!(matches!(source.next(), Some(SourceChar { ch: 'e', pt: Point{ file: 1, line: 1, col: 3 }, switched: false})));
-        assert!(matches!(source.next(), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 2, col: 1 }, switched: true})));
-        assert!(matches!(source.next(), None));
+        source.push_data(&PathBuf::new(), text);
 
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'e', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: '\u{0301}', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'x', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
+        assert!(source.next().is_none());
+        
         Ok(())
     }
 
     #[test]
-    fn peek_multiple() -> Result<(), CcError> {
+    fn wide_characters_take_two_columns() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = vec!['\u{4e2d}', '\t', 'x'];
+
+        source.push_data(&PathBuf::new(), text);
+
+        assert!(matches!(source.next(), Some(SourceChar { ch: '\u{4e2d}', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: '\t', pt: Point{ file: 0, line: 1, col: 3 }, switched: false})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'x', pt: Point{ file: 0, line: 1, col: 4 }, switched: false})));
+        assert!(source.next().is_none());
+        
+        Ok(())
+    }
+
+    #[test]
+    fn leading_bom_is_skipped() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = vec![BOM, 'a', BOM];
+
+        source.push_data(&PathBuf::new(), text);
+
+        assert!(source.files[0].bom);
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: BOM, pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
+        assert!(source.next().is_none());
+        
+        Ok(())
+    }
 
+    #[test]
+    fn files_nest() -> Result<(), CcError> {
         let mut source = Source::new();
         let text1 = vec!['a', '\n', 'b'];
         let text2 = vec!['c', 'd', 'e'];
@@ -489,186 +922,109 @@ mod tests {
         assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
         source.push_data(&PathBuf::from("def"), text2);
-
-        assert!(matches!(source.peek(), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
-        assert!(matches!(source.peek_n(0), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
-        assert!(matches!(source.peek_n(1), Some(SourceChar { ch: 'd', pt: Point{ file: 1, line: 1, col: 2 }, switched: false})));
-        assert!(matches!(source.peek_n(3), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 2, col: 1 }, switched: true})));
-
         assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'd', pt: Point{ file: 1, line: 1, col: 2 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'e', pt: Point{ file: 1, line: 1, col: 3 }, switched: false})));
         assert!(matches!(source.next(), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 2, col: 1 }, switched: true})));
-        assert!(matches!(source.next(), None));
+        assert!(source.next().is_none());
 
         Ok(())
     }
-}
 
+    #[test]
+    fn finds_include_cycle() -> Result<(), CcError> {
+        let mut source = Source::new();
 
-/***
- * 
+        source.push_data(&PathBuf::from("a.c"), vec!['a', '\n', 'b']);
+        source.next();
+        source.push_data(&PathBuf::from("b.h"), vec!['c', 'd']);
+        source.next();
+
+        assert_eq!(
+            source.include_cycle(&PathBuf::from("a.c")), 
+            Some(vec!["a.c".to_string(), "b.h".to_string(), "a.c".to_string()])
+        );
+        assert_eq!(
+            source.include_cycle(&PathBuf::from("b.h")), 
+            Some(vec!["b.h".to_string(), "b.h".to_string()])
+        );
+        assert_eq!(source.include_cycle(&PathBuf::from("c.h")), None);
 
-impl<F> Iterator for TakeWhile<'_, F> 
-    where F: Fn(char) -> bool
-{
-    type Item = SourceChar;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.source.peek() {
-            Some(sch) => {
-                if (self.pred)(sch.ch) {
-                    self.source.next()
-                } else {
-                    //
-                    // Character did not pass predicate
-                    //
-                    None
-                }
-            },
-            
-            //
-            // End of inner iterator
-            //
-            None => None,
-        }
+        Ok(())
     }
-}
 
-/// Like TakeWhile, but returns at most `maxlen` characters
-/// 
-pub struct TakeWhileN<'a, F> 
-    where F: Fn(char) -> bool
-{
-    /// The condition for iterating.
-    pred: F, 
+    #[test]
+    fn limits_include_depth() {
+        let mut source = Source::new();
 
-    /// The maximum number of characters to return
-    maxlen: u32,
+        for _ in 0..MAX_INCLUDE_DEPTH {
+            source.push_data(&PathBuf::from("a.h"), vec!['a']);
+        }
 
-    /// The character count returned so far
-    so_far: u32,
+        let err = source.push_file(&PathBuf::from("a.h")).unwrap_err();
+        assert!(err.what.contains("a.h -> a.h"));
+    }
 
-    /// The source code.
-    source: &'a mut SourceFile,
-}
+    #[test]
+    fn takes_while() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let source = &mut source;
+        source.push_data(&PathBuf::from("abc"), "ab\\\ncd+ef".chars().collect());
 
-impl<F> Iterator for TakeWhileN<'_, F> 
-    where F: Fn(char) -> bool
-{
-    type Item = SourceChar;
+        let taken: Vec<SourceChar> = source.take_while(|ch| ch.is_ascii_alphabetic()).collect();
+        let chars: String = taken.iter().map(|sch| sch.ch).collect();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.so_far >= self.maxlen {
-            None
-        } else {
-            match self.source.peek() {
-                Some(sch) => {
-                    if (self.pred)(sch.ch) {
-                        self.so_far += 1;
-                        self.source.next()
-                    } else {
-                        //
-                        // Character did not pass predicate
-                        //
-                        None
-                    }
-                },
-                
-                //
-                // End of inner iterator
-                //
-                None => None,
-            }
-        }
-    }
-}
+        assert_eq!(chars, "abcd");
+        assert_eq!(taken[2].pt, Point { file: 0, line: 2, col: 1 });
+        assert!(matches!(source.peek(), Some(SourceChar { ch: '+', .. })));
 
-impl SourceFile {
-    /// Construct from the source as an exploded vector of characters
-    /// 
-    pub fn new(source: Vec<char>, file: u32) -> Self {
-        SourceFile {
-            text: source.into(),
-            file,
-            next_loc: Point{ file, line: 1, col: 1 },
-        }
-    }
+        source.next();
 
-    /// Skip whitespace in the input stream.
-    /// 
-    pub fn skip_whitespace(&mut self) {
-        loop {
-            if let Some(sch) = self.peek() {
-                if sch.ch.is_whitespace() {
-                    self.next();
-                    continue;
-                }
-            }
+        let chars: String = source.take_while_n(|ch| ch.is_ascii_alphabetic(), 1).map(|sch| sch.ch).collect();
+        assert_eq!(chars, "e");
+        assert!(matches!(source.peek(), Some(SourceChar { ch: 'f', .. })));
 
-            break;
-        }
+        Ok(())
     }
-    
 
-    /// Peek the next character, if there is one.
-    /// 
-    pub fn peek(&self) -> Option<SourceChar> {
-        self.text.front().map(|ch| SourceChar{ ch: *ch, pt: self.next_loc })
-    }
+    #[test]
+    fn skips_whitespace() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let source = &mut source;
+        source.push_data(&PathBuf::from("abc"), " \t\\\n a\n\n b".chars().collect());
 
-    /// Return an iterator which will return characters as long as pred(ch) is true.
-    /// 
-    pub fn take_while<F: Fn(char) -> bool> (&mut self, pred: F) ->TakeWhile<'_, F> {
-        TakeWhile {
-            pred,
-            source: self
-        }
-    }
+        assert!(!source.skip_whitespace());
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point { file: 0, line: 2, col: 2 }, .. })));
+        assert!(source.skip_whitespace());
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'b', pt: Point { file: 0, line: 4, col: 2 }, .. })));
+        assert!(!source.skip_whitespace());
 
-    /// Return an iterator which will return characters as long as pred(ch) is true,
-    /// for at most n characters.
-    /// 
-    pub fn take_while_n<F: Fn(char) -> bool> (&mut self, pred: F, n: u32) ->TakeWhileN<'_, F> {
-        TakeWhileN {
-            pred,
-            maxlen: n,
-            so_far: 0,
-            source: self
-        }
+        Ok(())
     }
-}
 
-impl Iterator for SourceFile {
-    type Item = SourceChar;
+    #[test]
+    fn peek_multiple() -> Result<(), CcError> {
 
-    /// Iterate, but while iterating, keep track of the location in the source.
-    /// 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.text.pop_front().map(|ch| {
-            let pt = self.next_loc;
-            
-            if ch == '\n' {
-                self.next_loc.line += 1;
-                self.next_loc.col = 1;
-            } else {
-                self.next_loc.col += 1;
-            }
+        let mut source = Source::new();
+        let text1 = vec!['a', '\n', 'b'];
+        let text2 = vec!['c', 'd', 'e'];
 
-            SourceChar{ ch, pt }
-        })
-    }
-}
+        source.push_data(&PathBuf::from("abc"), text1);
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'a', pt: Point{ file: 0, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: '\n', pt: Point{ file: 0, line: 1, col: 2 }, switched: false})));
+        source.push_data(&PathBuf::from("def"), text2);
 
-/// Source, including all included source files.
-/// 
-pub struct Source {
-    /// All source files
-    pub files: Vec<SourceFile>,    
-    
+        assert!(matches!(source.peek(), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.peek_n(0), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.peek_n(1), Some(SourceChar { ch: 'd', pt: Point{ file: 1, line: 1, col: 2 }, switched: false})));
+        assert!(matches!(source.peek_n(3), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 2, col: 1 }, switched: true})));
 
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'c', pt: Point{ file: 1, line: 1, col: 1 }, switched: true})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'd', pt: Point{ file: 1, line: 1, col: 2 }, switched: false})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'e', pt: Point{ file: 1, line: 1, col: 3 }, switched: false})));
+        assert!(matches!(source.next(), Some(SourceChar { ch: 'b', pt: Point{ file: 0, line: 2, col: 1 }, switched: true})));
+        assert!(source.next().is_none());
 
+        Ok(())
+    }
 }
-
-
-    **/
\ No newline at end of file
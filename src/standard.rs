@@ -0,0 +1,211 @@
+//
+// Selection of the C or C++ language standard, and of GNU extensions.
+//
+use crate::ccerror::CcError;
+use crate::lexer::Language;
+
+use std::str::FromStr;
+
+/// A revision of the C or C++ standard. Later standards compare greater
+/// than earlier ones of the same language, and every C++ standard compares
+/// greater than every C standard.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Standard {
+    /// ANSI C / ISO C90.
+    C89,
+
+    /// ISO C99.
+    C99,
+
+    /// ISO C11.
+    C11,
+
+    /// ISO C17.
+    #[default]
+    C17,
+
+    /// ISO C23.
+    C23,
+
+    /// ISO C++98.
+    Cxx98,
+
+    /// ISO C++11.
+    Cxx11,
+
+    /// ISO C++14.
+    Cxx14,
+
+    /// ISO C++17.
+    Cxx17,
+
+    /// ISO C++20.
+    Cxx20,
+
+    /// ISO C++23.
+    Cxx23,
+}
+
+impl Standard {
+    /// The usual name of the standard, as given to `-std`.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Standard::C89 => "c89",
+            Standard::C99 => "c99",
+            Standard::C11 => "c11",
+            Standard::C17 => "c17",
+            Standard::C23 => "c23",
+            Standard::Cxx98 => "c++98",
+            Standard::Cxx11 => "c++11",
+            Standard::Cxx14 => "c++14",
+            Standard::Cxx17 => "c++17",
+            Standard::Cxx20 => "c++20",
+            Standard::Cxx23 => "c++23",
+        }
+    }
+
+    /// The standard used for `language` when none is given.
+    ///
+    pub fn default_for(language: Language) -> Standard {
+        match language {
+            Language::Cxx => Standard::Cxx17,
+            _ => Standard::default(),
+        }
+    }
+
+    /// Whether this is a revision of the C++ standard.
+    ///
+    pub fn is_cxx(&self) -> bool {
+        *self >= Standard::Cxx98
+    }
+
+    /// Whether #elifdef and #elifndef are part of the standard, as they are
+    /// from C23 and C++23, rather than an extension.
+    ///
+    pub fn has_elifdef(&self) -> bool {
+        matches!(self, Standard::C23 | Standard::Cxx23)
+    }
+
+    /// The value of `__cplusplus` for a C++ standard.
+    ///
+    pub fn cplusplus(&self) -> Option<&'static str> {
+        match self {
+            Standard::Cxx98 => Some("199711L"),
+            Standard::Cxx11 => Some("201103L"),
+            Standard::Cxx14 => Some("201402L"),
+            Standard::Cxx17 => Some("201703L"),
+            Standard::Cxx20 => Some("202002L"),
+            Standard::Cxx23 => Some("202302L"),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Standard {
+    type Err = CcError;
+
+    /// Parse a standard name as given to `-std`.
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c89" | "c90" | "ansi" | "iso9899:1990" | "iso9899:199409" => Ok(Standard::C89),
+            "c99" | "c9x" | "iso9899:1999" => Ok(Standard::C99),
+            "c11" | "c1x" | "iso9899:2011" => Ok(Standard::C11),
+            "c17" | "c18" | "iso9899:2017" | "iso9899:2018" => Ok(Standard::C17),
+            "c23" | "c2x" | "iso9899:2024" => Ok(Standard::C23),
+            "c++98" | "c++03" => Ok(Standard::Cxx98),
+            "c++11" | "c++0x" => Ok(Standard::Cxx11),
+            "c++14" | "c++1y" => Ok(Standard::Cxx14),
+            "c++17" | "c++1z" => Ok(Standard::Cxx17),
+            "c++20" | "c++2a" => Ok(Standard::Cxx20),
+            "c++23" | "c++2b" => Ok(Standard::Cxx23),
+            _ => Err(CcError::new(format!("unknown standard '{}'", s))),
+        }
+    }
+}
+
+/// What `-std` selects: a standard, and whether GNU extensions are on, as
+/// with `gnu11`.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash, Default)]
+pub struct Dialect {
+    pub standard: Standard,
+    pub gnu: bool,
+}
+
+impl Dialect {
+    /// The usual name of the dialect, as given to `-std`.
+    ///
+    pub fn name(&self) -> String {
+        match self.standard.name().strip_prefix('c') {
+            Some(version) if self.gnu => format!("gnu{}", version),
+            _ => self.standard.name().to_string(),
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = CcError;
+
+    /// Parse a dialect name as given to `-std`. A GNU dialect is named
+    /// like the standard it extends, with `gnu` in place of `c`.
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (standard, gnu) = match s.strip_prefix("gnu") {
+            Some(version) => (format!("c{}", version).parse(), true),
+            None => (s.parse(), false),
+        };
+
+        match standard {
+            Ok(standard) => Ok(Dialect { standard, gnu }),
+            Err(_) => Err(CcError::new(format!("unknown standard '{}'", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_standard_names() {
+        assert_eq!("c90".parse::<Standard>(), Ok(Standard::C89));
+        assert_eq!("c2x".parse::<Standard>(), Ok(Standard::C23));
+        assert_eq!("c++17".parse::<Standard>(), Ok(Standard::Cxx17));
+        assert_eq!("c++2a".parse::<Standard>(), Ok(Standard::Cxx20));
+        assert!("c++16".parse::<Standard>().is_err());
+        assert!(Standard::C99 < Standard::C11);
+        assert!(Standard::Cxx11 < Standard::Cxx14);
+    }
+
+    #[test]
+    fn knows_cxx_standards() {
+        assert_eq!(Standard::default_for(Language::Cxx), Standard::Cxx17);
+        assert_eq!(Standard::default_for(Language::C), Standard::C17);
+        assert!(Standard::Cxx98.is_cxx() && !Standard::C23.is_cxx());
+        assert_eq!(Standard::Cxx20.cplusplus(), Some("202002L"));
+        assert_eq!(Standard::C11.cplusplus(), None);
+        assert!(Standard::C23.has_elifdef() && Standard::Cxx23.has_elifdef());
+        assert!(!Standard::C17.has_elifdef() && !Standard::Cxx20.has_elifdef());
+    }
+
+    #[test]
+    fn parses_gnu_dialects() {
+        let gnu = |standard| Ok(Dialect { standard, gnu: true });
+
+        assert_eq!("gnu89".parse::<Dialect>(), gnu(Standard::C89));
+        assert_eq!("gnu99".parse::<Dialect>(), gnu(Standard::C99));
+        assert_eq!("gnu1x".parse::<Dialect>(), gnu(Standard::C11));
+        assert_eq!("gnu17".parse::<Dialect>(), gnu(Standard::C17));
+        assert_eq!("gnu2x".parse::<Dialect>(), gnu(Standard::C23));
+        assert_eq!("c11".parse::<Dialect>(), Ok(Dialect { standard: Standard::C11, gnu: false }));
+        assert!("gnuansi".parse::<Dialect>().is_err());
+        assert!("gnu".parse::<Dialect>().is_err());
+
+        assert_eq!("gnu18".parse::<Dialect>().map(|dialect| dialect.name()), Ok("gnu17".to_string()));
+        assert_eq!("iso9899:1999".parse::<Dialect>().map(|dialect| dialect.name()), Ok("c99".to_string()));
+        assert_eq!("gnu++1z".parse::<Dialect>().map(|dialect| dialect.name()), Ok("gnu++17".to_string()));
+    }
+}
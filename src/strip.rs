@@ -0,0 +1,318 @@
+//
+// Finding and removing comments in source text, without any other
+// preprocessing, for tools which want to see the code but not the comments
+// (or just the comments). When comments are removed, everything else is kept
+// exactly as it was, including line splices.
+//
+use crate::ccerror::CcError;
+use crate::lexer::{Language, LexOptions};
+use crate::source::{column_width, Point};
+
+/// The kind of a comment.
+///
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+pub enum CommentKind {
+    /// `/* ... */`
+    Block,
+
+    /// `// ...`
+    Line,
+}
+
+/// A comment found in source text.
+///
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct Comment {
+    pub kind: CommentKind,
+
+    /// The index in the text of the comment's first character.
+    pub start: usize,
+
+    /// The index just past the comment's last character. A line comment
+    /// doesn't include the newline which ends it.
+    pub end: usize,
+}
+
+/// Find the comments in the text of file number `file`, in order. Comment
+/// markers inside character constants and string literals (and raw strings,
+/// where `options` allows them) are not comments.
+///
+pub fn find_comments(text: &[char], file: u32, options: &LexOptions) -> Result<Vec<Comment>, CcError> {
+    let mut comments = Vec::new();
+    let mut i = 0;
+
+    while i < text.len() {
+        let ch = text[i];
+
+        match ch {
+            '/' => {
+                let next = skip_splices(text, i + 1);
+
+                match text.get(next) {
+                    Some('*') => {
+                        let end = block_comment_end(text, next + 1)
+                            .ok_or_else(|| CcError::err_with_loc("unterminated comment".to_string(), point_at(text, file, i)))?;
+
+                        comments.push(Comment { kind: CommentKind::Block, start: i, end });
+                        i = end;
+                    },
+                    Some('/') => {
+                        let end = line_comment_end(text, next + 1);
+
+                        comments.push(Comment { kind: CommentKind::Line, start: i, end });
+                        i = end;
+                    },
+                    _ => i += 1,
+                }
+            },
+            '"' if options.raw_strings() && is_raw_prefix(text, i) => {
+                i = raw_string_end(text, i + 1)
+                    .ok_or_else(|| CcError::err_with_loc("unterminated raw string".to_string(), point_at(text, file, i)))?;
+            },
+            '"' | '\'' => {
+                let end = literal_end(text, i + 1, ch);
+
+                //
+                // Assemblers use single quotes for other things, so in
+                // assembly an unmatched quote is just a character.
+                //
+                if options.language == Language::AssemblerWithCpp && ch == '\'' && text.get(end - 1) != Some(&'\'') {
+                    i += 1;
+                } else {
+                    i = end;
+                }
+            },
+            _ => i += 1,
+        }
+    }
+
+    Ok(comments)
+}
+
+/// Remove the comments from the text of file number `file`.
+///
+/// A block comment is replaced by a space, so the tokens either side stay
+/// apart, followed by any newlines it contained, so the lines of the output
+/// match those of the input. A line comment is removed up to the newline
+/// which ends it. Newlines are copied as they were, whether they are LF,
+/// CR, CR/LF or LF/CR.
+///
+pub fn strip_comments(text: &[char], file: u32, options: &LexOptions) -> Result<String, CcError> {
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+
+    for comment in find_comments(text, file, options)? {
+        out.extend(&text[copied..comment.start]);
+
+        if comment.kind == CommentKind::Block {
+            out.push(' ');
+
+            let mut i = comment.start;
+            while i < comment.end {
+                let len = newline_len(text, i);
+                out.extend(&text[i..i + len]);
+                i += len.max(1);
+            }
+        }
+
+        copied = comment.end;
+    }
+
+    out.extend(&text[copied..]);
+    Ok(out)
+}
+
+/// The number of characters in the newline starting at `i`, or 0 if there
+/// isn't one. As in `Source`, CR, LF, CR/LF and LF/CR are all one newline.
+///
+fn newline_len(text: &[char], i: usize) -> usize {
+    match (text.get(i), text.get(i + 1)) {
+        (Some('\r'), Some('\n')) | (Some('\n'), Some('\r')) => 2,
+        (Some('\r' | '\n'), _) => 1,
+        _ => 0,
+    }
+}
+
+/// Skip any line splices starting at `i`, returning the index of the next
+/// character which isn't part of one.
+///
+fn skip_splices(text: &[char], mut i: usize) -> usize {
+    while text.get(i) == Some(&'\\') {
+        let len = newline_len(text, i + 1);
+        if len == 0 {
+            break;
+        }
+        i += 1 + len;
+    }
+    i
+}
+
+/// Find the index just past the `*/` ending a block comment whose body
+/// starts at `i`.
+///
+fn block_comment_end(text: &[char], mut i: usize) -> Option<usize> {
+    while i < text.len() {
+        if text[i] == '*' {
+            let next = skip_splices(text, i + 1);
+            if text.get(next) == Some(&'/') {
+                return Some(next + 1);
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Find the newline ending a line comment whose body starts at `i`. A
+/// spliced newline continues the comment.
+///
+fn line_comment_end(text: &[char], mut i: usize) -> usize {
+    while i < text.len() {
+        i = skip_splices(text, i);
+        if newline_len(text, i) != 0 {
+            break;
+        }
+        i += 1;
+    }
+
+    i.min(text.len())
+}
+
+/// Find the index just past the quote closing a character constant or
+/// string literal whose body starts at `i`. An unterminated literal ends at
+/// the end of its line.
+///
+fn literal_end(text: &[char], mut i: usize, quote: char) -> usize {
+    while i < text.len() {
+        match text[i] {
+            //
+            // A backslash escapes the next character, or splices the line.
+            //
+            '\\' => i += 1 + newline_len(text, i + 1).max(1),
+            '\r' | '\n' => return i,
+            ch if ch == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+
+    text.len()
+}
+
+/// Whether the quote at `i` is preceded by a raw string prefix, such as the
+/// `R` or `u8R`, which isn't itself the end of a longer identifier.
+///
+fn is_raw_prefix(text: &[char], i: usize) -> bool {
+    let is_id = |ch: &char| ch.is_ascii_alphanumeric() || *ch == '_';
+
+    let start = text[..i].iter().rposition(|ch| !is_id(ch)).map_or(0, |pos| pos + 1);
+    let prefix: String = text[start..i].iter().collect();
+
+    matches!(prefix.as_str(), "R" | "u8R" | "LR" | "uR" | "UR")
+}
+
+/// Find the index just past the end of a raw string whose delimiter starts
+/// at `i`.
+///
+fn raw_string_end(text: &[char], i: usize) -> Option<usize> {
+    let open = i + text[i..].iter().position(|ch| *ch == '(')?;
+
+    let mut close = vec![')'];
+    close.extend(&text[i..open]);
+    close.push('"');
+
+    let body = &text[open + 1..];
+    let len = body.windows(close.len()).position(|window| window == close.as_slice())?;
+
+    Some(open + 1 + len + close.len())
+}
+
+/// The location of the character at `index`.
+///
+pub fn point_at(text: &[char], file: u32, index: usize) -> Point {
+    let mut pt = Point { file, line: 1, col: 1 };
+    let mut i = 0;
+
+    while i < index {
+        match newline_len(text, i) {
+            0 => {
+                pt.col += column_width(text[i]);
+                i += 1;
+            },
+            len => {
+                pt.line += 1;
+                pt.col = 1;
+                i += len;
+            },
+        }
+    }
+
+    pt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(text: &str, language: Language) -> Result<String, CcError> {
+        strip_with(text, &LexOptions { language, ..Default::default() })
+    }
+
+    fn strip_with(text: &str, options: &LexOptions) -> Result<String, CcError> {
+        let text: Vec<char> = text.chars().collect();
+        strip_comments(&text, 0, options)
+    }
+
+    #[test]
+    fn strips_comments() -> Result<(), CcError> {
+        assert_eq!(strip("a/**/b // c\nd /* e\n f */ g\n", Language::C)?, "a b \nd  \n g\n");
+        assert_eq!(strip("x = a / b; // end", Language::C)?, "x = a / b; ");
+        assert_eq!(strip("a /\\\n* c *\\\n/ b", Language::C)?, "a  \n\n b");
+        assert_eq!(strip("// one \\\n two\nthree", Language::C)?, "\nthree");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_line_endings() -> Result<(), CcError> {
+        assert_eq!(strip("a // x \\\r\n y\r\nb", Language::C)?, "a \r\nb");
+        assert_eq!(strip("a // x\r\nb // y\rc", Language::C)?, "a \r\nb \rc");
+        assert_eq!(strip("a /* x\r\n y\n\r z\r */ b\r\n", Language::C)?, "a  \r\n\n\r\r b\r\n");
+        assert_eq!(strip("a /\\\r\n* x *\\\r/ b", Language::C)?, "a  \r\n\r b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_literals() -> Result<(), CcError> {
+        assert_eq!(strip("\"/* no */\" '\\'' \"\\\"//\" // yes", Language::C)?, "\"/* no */\" '\\'' \"\\\"//\" ");
+        assert_eq!(strip("R\"x(// )\" /* )x\" // c", Language::Cxx)?, "R\"x(// )\" /* )x\" ");
+        assert_eq!(strip("R\"x(\")x\" // c", Language::C)?, "R\"x(\")x\" // c");
+        assert_eq!(strip_with("R\"x(\")x\" // c", &LexOptions { gnu: true, ..Default::default() })?, "R\"x(\")x\" ");
+        assert_eq!(strip("mov 'a, r0 // c\n", Language::AssemblerWithCpp)?, "mov 'a, r0 \n");
+        assert_eq!(strip("\"a\\\r\n/* no */\" /* yes */", Language::C)?, "\"a\\\r\n/* no */\"  ");
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_comments() -> Result<(), CcError> {
+        let text: Vec<char> = "a /* b */ \"//\" // c\n".chars().collect();
+
+        assert_eq!(find_comments(&text, 0, &LexOptions::default())?, vec![
+            Comment { kind: CommentKind::Block, start: 2, end: 9 },
+            Comment { kind: CommentKind::Line, start: 15, end: 19 },
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unterminated_comment_is_an_error() {
+        let e = strip("a\n  /* b", Language::C).unwrap_err();
+        assert_eq!(e.loc, Some(Point { file: 0, line: 2, col: 3 }));
+
+        let e = strip("a\n\r  /* b", Language::C).unwrap_err();
+        assert_eq!(e.loc, Some(Point { file: 0, line: 2, col: 3 }));
+    }
+}
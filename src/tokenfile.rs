@@ -0,0 +1,435 @@
+//
+// A compact binary format for a token stream, so that another tool can
+// consume preprocessed tokens without lexing text again.
+//
+// The layout, with integers as unsigned LEB128 unless noted, is:
+//
+//      magic       4 bytes, "CPPT"
+//      version     u16, little endian
+//      nfiles      count of file names
+//      files       each a string
+//      ntokens     count of tokens
+//      tokens      each: kind (u8), payload, file, line, col, flags (u8)
+//
+// A string is its length in bytes followed by the UTF-8 bytes. The payload
+// depends on the kind: a string for identifiers and numbers, an encoding
+// byte and a string for literals (including raw strings), the code point
+// for other characters, the spelling for C++ alternative tokens such as
+// `and`, and nothing for operators.
+//
+// Version 2 added raw strings, and version 3 alternative tokens. Each
+// version only adds token kinds, so files written by earlier versions can
+// still be read.
+//
+use crate::ccerror::CcError;
+use crate::lexer::{Encoding, MetaToken, PpToken};
+use crate::source::{Point, Source};
+
+use std::io::{Read, Write};
+
+/// The first bytes of every token file.
+pub const MAGIC: &[u8; 4] = b"CPPT";
+
+/// The version of the format written by this crate.
+pub const VERSION: u16 = 3;
+
+const KIND_EOF: u8 = 0;
+const KIND_IDENTIFIER: u8 = 1;
+const KIND_NUMBER: u8 = 2;
+const KIND_CHAR: u8 = 3;
+const KIND_STRING: u8 = 4;
+const KIND_OTHER: u8 = 5;
+const KIND_RAW_STRING: u8 = 6;
+const KIND_ALTERNATIVE: u8 = 7;
+const KIND_FIRST_OPERATOR: u8 = 16;
+
+const FLAG_STARTS_LINE: u8 = 0x01;
+const FLAG_SPACE_BEFORE: u8 = 0x02;
+
+/// Operators and punctuators, in the order of their kind codes. New entries
+/// must only be added at the end, so existing files keep their meaning.
+const OPERATORS: &[PpToken] = &[
+    PpToken::Hash,
+    PpToken::Add,
+    PpToken::Subtract,
+    PpToken::Star,
+    PpToken::Divide,
+    PpToken::Mod,
+    PpToken::Increment,
+    PpToken::Decrement,
+    PpToken::Equal,
+    PpToken::NotEqual,
+    PpToken::Less,
+    PpToken::LessEqual,
+    PpToken::Greater,
+    PpToken::GreaterEqual,
+    PpToken::LogicalNot,
+    PpToken::LogicalAnd,
+    PpToken::LogicalOr,
+    PpToken::BitNot,
+    PpToken::Ampersand,
+    PpToken::BitOr,
+    PpToken::BitXor,
+    PpToken::ShiftLeft,
+    PpToken::ShiftRight,
+    PpToken::Assign,
+    PpToken::AddAssign,
+    PpToken::SubtractAssign,
+    PpToken::MultiplyAssign,
+    PpToken::DivideAssign,
+    PpToken::ModAssign,
+    PpToken::AndAssign,
+    PpToken::OrAssign,
+    PpToken::XorAssign,
+    PpToken::LeftShiftAssign,
+    PpToken::RightShiftAssign,
+    PpToken::LeftBracket,
+    PpToken::RightBracket,
+    PpToken::LeftParen,
+    PpToken::RightParen,
+    PpToken::LeftBrace,
+    PpToken::RightBrace,
+    PpToken::Dot,
+    PpToken::Arrow,
+    PpToken::Semicolon,
+    PpToken::Question,
+    PpToken::Colon,
+    PpToken::Comma,
+    PpToken::BlockComment,
+    PpToken::LineComment,
+    PpToken::Scope,
+    PpToken::ArrowStar,
+    PpToken::DotStar,
+    PpToken::Spaceship,
+    PpToken::HashHash,
+];
+
+/// A token stream together with the table of files its locations refer to.
+///
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenFile {
+    /// File names, indexed by `Point::file`.
+    pub files: Vec<String>,
+
+    /// The tokens.
+    pub tokens: Vec<MetaToken>,
+}
+
+impl TokenFile {
+    /// Construct from tokens read from `source`, taking the file table from
+    /// the source.
+    ///
+    pub fn from_source(source: &Source, tokens: Vec<MetaToken>) -> Self {
+        TokenFile {
+            files: source.files.iter().map(|file| file.strname.clone()).collect(),
+            tokens,
+        }
+    }
+
+    /// Write the token file in binary form.
+    ///
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), CcError> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+
+        write_uint(out, self.files.len() as u64)?;
+        for file in &self.files {
+            write_str(out, file)?;
+        }
+
+        write_uint(out, self.tokens.len() as u64)?;
+        for token in &self.tokens {
+            write_token(out, token)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a token file in binary form.
+    ///
+    pub fn read<R: Read>(input: &mut R) -> Result<Self, CcError> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CcError::from_static("not a token file"));
+        }
+
+        let mut version = [0u8; 2];
+        input.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version == 0 || version > VERSION {
+            return Err(CcError::new(format!("unsupported token file version {}", version)));
+        }
+
+        let nfiles = read_uint(input)?;
+        let mut files = Vec::new();
+        for _ in 0..nfiles {
+            files.push(read_str(input)?);
+        }
+
+        let ntokens = read_uint(input)?;
+        let mut tokens = Vec::new();
+        for _ in 0..ntokens {
+            tokens.push(read_token(input, files.len())?);
+        }
+
+        Ok(TokenFile { files, tokens })
+    }
+}
+
+fn write_token<W: Write>(out: &mut W, token: &MetaToken) -> Result<(), CcError> {
+    match &token.token {
+        PpToken::Eof => write_u8(out, KIND_EOF)?,
+        PpToken::Identifier(id) => {
+            write_u8(out, KIND_IDENTIFIER)?;
+            write_str(out, id)?;
+        },
+        PpToken::Number(num) => {
+            write_u8(out, KIND_NUMBER)?;
+            write_str(out, num)?;
+        },
+        PpToken::CharLiteral(encoding, text) => {
+            write_u8(out, KIND_CHAR)?;
+            write_u8(out, encoding_code(*encoding))?;
+            write_str(out, text)?;
+        },
+        PpToken::StringLiteral(encoding, text) => {
+            write_u8(out, KIND_STRING)?;
+            write_u8(out, encoding_code(*encoding))?;
+            write_str(out, text)?;
+        },
+        PpToken::RawStringLiteral(encoding, text) => {
+            write_u8(out, KIND_RAW_STRING)?;
+            write_u8(out, encoding_code(*encoding))?;
+            write_str(out, text)?;
+        },
+        PpToken::Other(ch) => {
+            write_u8(out, KIND_OTHER)?;
+            write_uint(out, *ch as u64)?;
+        },
+        PpToken::Alternative(spelling) => {
+            write_u8(out, KIND_ALTERNATIVE)?;
+            write_str(out, spelling)?;
+        },
+        op => {
+            let index = OPERATORS.iter().position(|known| known == op).unwrap();
+            write_u8(out, KIND_FIRST_OPERATOR + index as u8)?;
+        },
+    }
+
+    write_uint(out, token.loc.file as u64)?;
+    write_uint(out, token.loc.line as u64)?;
+    write_uint(out, token.loc.col as u64)?;
+    let mut flags = 0;
+    if token.starts_line {
+        flags |= FLAG_STARTS_LINE;
+    }
+    if token.space_before {
+        flags |= FLAG_SPACE_BEFORE;
+    }
+    write_u8(out, flags)?;
+
+    Ok(())
+}
+
+fn read_token<R: Read>(input: &mut R, nfiles: usize) -> Result<MetaToken, CcError> {
+    let kind = read_u8(input)?;
+
+    let token = match kind {
+        KIND_EOF => PpToken::Eof,
+        KIND_IDENTIFIER => PpToken::Identifier(read_str(input)?),
+        KIND_NUMBER => PpToken::Number(read_str(input)?),
+        KIND_CHAR => {
+            let encoding = read_encoding(input)?;
+            PpToken::CharLiteral(encoding, read_str(input)?)
+        },
+        KIND_STRING => {
+            let encoding = read_encoding(input)?;
+            PpToken::StringLiteral(encoding, read_str(input)?)
+        },
+        KIND_RAW_STRING => {
+            let encoding = read_encoding(input)?;
+            PpToken::RawStringLiteral(encoding, read_str(input)?)
+        },
+        KIND_OTHER => {
+            match char::from_u32(read_u32(input)?) {
+                Some(ch) => PpToken::Other(ch),
+                None => return Err(CcError::from_static("invalid character in token file")),
+            }
+        },
+        KIND_ALTERNATIVE => {
+            let spelling = read_str(input)?;
+            match PpToken::alternative(&spelling) {
+                Some(token) => token,
+                None => return Err(CcError::new(format!("unknown alternative token '{}' in token file", spelling))),
+            }
+        },
+        kind if kind >= KIND_FIRST_OPERATOR && ((kind - KIND_FIRST_OPERATOR) as usize) < OPERATORS.len() => {
+            OPERATORS[(kind - KIND_FIRST_OPERATOR) as usize].clone()
+        },
+        kind => return Err(CcError::new(format!("unknown token kind {} in token file", kind))),
+    };
+
+    let file = read_u32(input)?;
+    if file as usize >= nfiles {
+        return Err(CcError::new(format!("file index {} out of range in token file", file)));
+    }
+
+    let line = read_u32(input)?;
+    let col = read_u32(input)?;
+    let flags = read_u8(input)?;
+
+    Ok(MetaToken {
+        token,
+        loc: Point { file, line, col },
+        starts_line: flags & FLAG_STARTS_LINE != 0,
+        space_before: flags & FLAG_SPACE_BEFORE != 0,
+    })
+}
+
+fn encoding_code(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Plain => 0,
+        Encoding::Utf8 => 1,
+        Encoding::Wide => 2,
+        Encoding::Utf16 => 3,
+        Encoding::Utf32 => 4,
+    }
+}
+
+fn read_encoding<R: Read>(input: &mut R) -> Result<Encoding, CcError> {
+    match read_u8(input)? {
+        0 => Ok(Encoding::Plain),
+        1 => Ok(Encoding::Utf8),
+        2 => Ok(Encoding::Wide),
+        3 => Ok(Encoding::Utf16),
+        4 => Ok(Encoding::Utf32),
+        code => Err(CcError::new(format!("unknown encoding {} in token file", code))),
+    }
+}
+
+fn write_u8<W: Write>(out: &mut W, value: u8) -> Result<(), CcError> {
+    out.write_all(&[value])?;
+    Ok(())
+}
+
+fn read_u8<R: Read>(input: &mut R) -> Result<u8, CcError> {
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn write_uint<W: Write>(out: &mut W, mut value: u64) -> Result<(), CcError> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            return write_u8(out, byte);
+        }
+
+        write_u8(out, byte | 0x80)?;
+    }
+}
+
+fn read_uint<R: Read>(input: &mut R) -> Result<u64, CcError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_u8(input)?;
+
+        if shift >= 64 {
+            return Err(CcError::from_static("integer too large in token file"));
+        }
+
+        value |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32, CcError> {
+    match u32::try_from(read_uint(input)?) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(CcError::from_static("integer too large in token file")),
+    }
+}
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> Result<(), CcError> {
+    write_uint(out, s.len() as u64)?;
+    out.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_str<R: Read>(input: &mut R) -> Result<String, CcError> {
+    let len = read_uint(input)? as usize;
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() != len {
+        return Err(CcError::from_static("truncated token file"));
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(CcError::from_static("invalid UTF-8 in token file")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{next_token_with, Language, LexOptions};
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn round_trips_lexed_tokens() -> Result<(), CcError> {
+        let mut source = Source::new();
+        let text = "int x = L'a' + 0x10;\n  s = u8\"str\" $ <<= 300 and R\"(raw)\";".chars().collect();
+
+        source.push_data(&PathBuf::from("abc"), text);
+
+        let cxx = LexOptions { language: Language::Cxx, ..Default::default() };
+        let mut emit = Vec::new();
+        let mut tokens = Vec::new();
+
+        loop {
+            let token = next_token_with(&mut source, &mut emit, &cxx)?;
+            if token.token == PpToken::Eof {
+                break;
+            }
+            tokens.push(token);
+        }
+
+        let file = TokenFile::from_source(&source, tokens);
+
+        let mut bytes = Vec::new();
+        file.write(&mut bytes)?;
+
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(TokenFile::read(&mut bytes.as_slice())?, file);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_older_versions() -> Result<(), CcError> {
+        let file = TokenFile::read(&mut b"CPPT\x01\x00\x00\x00".as_slice())?;
+        assert!(file.files.is_empty() && file.tokens.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(TokenFile::read(&mut b"CPPX\x01\x00\x00\x00".as_slice()).is_err());
+        assert!(TokenFile::read(&mut b"CPPT\x04\x00\x00\x00".as_slice()).is_err());
+        assert!(TokenFile::read(&mut b"CPPT\x01\x00\x00\x01\x01".as_slice()).is_err());
+        assert!(TokenFile::read(&mut b"CPPT\x03\x00\x00\x01\x07\x01x".as_slice()).is_err());
+    }
+}